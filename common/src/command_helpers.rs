@@ -1,32 +1,247 @@
-use std::process::Command;
+use crate::constants::ZFS_TIMEOUT_ENV;
+use crate::duration;
+use crate::types::Opts;
+use std::env;
+use std::io::{self, Read};
+use std::process::{Command, Output, Stdio};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
 
-/// Returns a printable string of the given command
+// How often run_with_timeout() checks whether its command has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Returns a printable string of the given command, which can be pasted back into a shell. Any
+/// part which needs it is single-quoted.
 ///
 pub fn format_command(cmd: &Command) -> String {
-    format!(
-        "{} {}",
-        cmd.get_program().to_string_lossy(),
-        cmd.get_args()
-            .map(|arg| arg.to_string_lossy())
-            .collect::<Vec<_>>()
-            .join(" ")
-    )
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|word| shell_quote(&word.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Single quotes protect everything but single quotes, which have to be closed, escaped, and
+// reopened.
+fn shell_quote(word: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./:@%+=,".contains(c);
+
+    if !word.is_empty() && word.chars().all(is_safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
 }
 
-/// Takes a Command output and returns it as a Vec of strings. Empty lines
-/// are omitted.
+/// Runs a command which changes something. If opts.verbose or opts.noop is set the command is
+/// printed first, and if opts.noop is set it is not run. Returns the command's output if it ran,
+/// and None if it did not. A command which runs and fails is not an error: check the output.
+///
+pub fn run_action(cmd: &mut Command, opts: &Opts) -> std::io::Result<Option<Output>> {
+    if opts.verbose > 0 || opts.noop {
+        println!("{}", format_command(cmd));
+    }
+
+    if opts.noop {
+        Ok(None)
+    } else {
+        cmd.output().map(Some)
+    }
+}
+
+/// Takes a Command output and returns it as a Vec of strings. Any line which is not valid UTF-8
+/// has the bad bytes replaced, and a warning is printed. See `bytes_to_lines()`.
 ///
 pub fn output_as_lines(mut cmd: Command) -> anyhow::Result<Vec<String>> {
-    let raw_output = cmd.output()?;
-    let string_output = String::from_utf8(raw_output.stdout)?;
-    let lines: Vec<String> = string_output.lines().map(String::from).collect();
+    bytes_to_lines(cmd.output()?.stdout, false)
+}
+
+/// Like `output_as_lines()`, but any invalid UTF-8 is an error.
+///
+pub fn output_as_lines_strict(mut cmd: Command) -> anyhow::Result<Vec<String>> {
+    bytes_to_lines(cmd.output()?.stdout, true)
+}
+
+/// Splits command output into lines, in the same way as `str::lines()`. If strict is set, any
+/// invalid UTF-8 is an error. Otherwise it is replaced with U+FFFD, with a warning naming the line,
+/// so one odd dataset name doesn't hide all the others.
+///
+pub fn bytes_to_lines(bytes: Vec<u8>, strict: bool) -> anyhow::Result<Vec<String>> {
+    let bytes = match String::from_utf8(bytes) {
+        Ok(string) => return Ok(string.lines().map(String::from).collect()),
+        Err(e) if strict => return Err(e.into()),
+        Err(e) => e.into_bytes(),
+    };
+
+    let mut lines: Vec<String> = bytes
+        .split(|&b| b == b'\n')
+        .map(|line| {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let string = String::from_utf8_lossy(line).to_string();
+
+            if std::str::from_utf8(line).is_err() {
+                eprintln!("WARNING: replaced invalid UTF-8 in '{}'", string);
+            }
+
+            string
+        })
+        .collect();
+
+    if bytes.ends_with(b"\n") {
+        lines.pop();
+    }
 
     Ok(lines)
 }
 
+/// Like `output_as_lines()`, but the command is killed, and an error returned, if it has not
+/// finished within the timeout. With no timeout, it waits forever.
+///
+pub fn output_as_lines_with_timeout(
+    cmd: Command,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Vec<String>> {
+    bytes_to_lines(output_with_timeout(cmd, timeout)?.stdout, false)
+}
+
+/// Like `Command::output()`, but the command is killed, and a `TimedOut` error returned, if it
+/// has not finished within the timeout. With no timeout, it waits forever.
+///
+pub fn output_with_timeout(mut cmd: Command, timeout: Option<Duration>) -> io::Result<Output> {
+    match timeout {
+        Some(timeout) => run_with_timeout(&mut cmd, timeout),
+        None => cmd.output(),
+    }
+}
+
+/// Runs a command, collecting its output in the same way as `Command::output()`. If it has not
+/// finished within the timeout it is killed, and a `TimedOut` error is returned.
+///
+pub fn run_with_timeout(cmd: &mut Command, timeout: Duration) -> io::Result<Output> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // The pipes are drained as we go, so a chatty command can't fill them and stall.
+    let stdout = child.stdout.take().map(read_in_background);
+    let stderr = child.stderr.take().map(read_in_background);
+    let deadline = Instant::now() + timeout;
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "'{}' did not finish within {:?}",
+                    format_command(cmd),
+                    timeout
+                ),
+            ));
+        }
+
+        sleep(POLL_INTERVAL);
+    };
+
+    Ok(Output {
+        status,
+        stdout: collect(stdout)?,
+        stderr: collect(stderr)?,
+    })
+}
+
+fn read_in_background<R: Read + Send + 'static>(
+    mut pipe: R,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        pipe.read_to_end(&mut buf)?;
+        Ok(buf)
+    })
+}
+
+fn collect(reader: Option<thread::JoinHandle<io::Result<Vec<u8>>>>) -> io::Result<Vec<u8>> {
+    match reader {
+        Some(handle) => handle
+            .join()
+            .map_err(|_| io::Error::other("output reader panicked"))?,
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Returns the timeout for zfs commands, set with the `ZFS_TOOLS_TIMEOUT` environment variable,
+/// or None if there isn't one.
+///
+pub fn command_timeout() -> Option<Duration> {
+    command_timeout_from(env::var(ZFS_TIMEOUT_ENV).ok())
+}
+
+// A value we can't understand is reported and ignored.
+fn command_timeout_from(value: Option<String>) -> Option<Duration> {
+    let value = value?;
+
+    match duration::parse(&value) {
+        Ok(timeout) if !timeout.is_zero() => Some(timeout),
+        Ok(_) => None,
+        Err(e) => {
+            eprintln!("WARNING: ignoring {}: {}", ZFS_TIMEOUT_ENV, e);
+            None
+        }
+    }
+}
+
+/// Checks whether a command's stderr contains any of the given transient error patterns.
+///
+pub fn is_transient(stderr: &str, patterns: &[&str]) -> bool {
+    let stderr = stderr.to_lowercase();
+    patterns
+        .iter()
+        .any(|pattern| stderr.contains(&pattern.to_lowercase()))
+}
+
+/// Runs a command up to `attempts` times, while it fails with a transient error, as defined by
+/// `patterns`. The wait between attempts starts at `backoff` and doubles each time. Returns the
+/// output of the last attempt. Failing to spawn the command is returned immediately.
+///
+pub fn run_with_retry(
+    cmd: &mut Command,
+    attempts: u32,
+    backoff: Duration,
+    patterns: &[&str],
+) -> std::io::Result<Output> {
+    let mut wait = backoff;
+    let mut attempt = 1;
+
+    loop {
+        let output = cmd.output()?;
+
+        if output.status.success()
+            || attempt >= attempts
+            || !is_transient(&String::from_utf8_lossy(&output.stderr), patterns)
+        {
+            return Ok(output);
+        }
+
+        sleep(wait);
+        wait *= 2;
+        attempt += 1;
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::constants::TRANSIENT_ZFS_ERRORS;
+    use crate::spec_helper::fixture;
+    use std::fs;
+    use tempfile::tempdir;
 
     #[test]
     fn test_output_as_lines() {
@@ -43,4 +258,257 @@ mod test {
 
         assert_eq!(expected, output_as_lines(Command::new("/bin/ls")).unwrap());
     }
+
+    #[test]
+    fn test_run_with_timeout() {
+        let start = Instant::now();
+        let result = run_with_timeout(
+            &mut Command::new(fixture("bin/hang")),
+            Duration::from_millis(200),
+        );
+
+        assert_eq!(io::ErrorKind::TimedOut, result.unwrap_err().kind());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let output = run_with_timeout(
+            &mut Command::new(fixture("bin/fail")),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("dataset does not exist"));
+    }
+
+    #[test]
+    fn test_output_as_lines_with_timeout() {
+        assert_eq!(
+            vec!["rpool/test\t-\t-\t-\t-".to_string()],
+            output_as_lines_with_timeout(
+                Command::new(fixture("bin/succeed")),
+                Some(Duration::from_secs(10))
+            )
+            .unwrap()
+        );
+
+        assert!(output_as_lines_with_timeout(
+            Command::new(fixture("bin/hang")),
+            Some(Duration::from_millis(200))
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_output_with_timeout() {
+        let output = output_with_timeout(Command::new(fixture("bin/fail")), None).unwrap();
+        assert!(!output.status.success());
+
+        let output = output_with_timeout(
+            Command::new(fixture("bin/succeed")),
+            Some(Duration::from_secs(10)),
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(b"rpool/test\t-\t-\t-\t-\n".to_vec(), output.stdout);
+
+        assert_eq!(
+            io::ErrorKind::TimedOut,
+            output_with_timeout(
+                Command::new(fixture("bin/hang")),
+                Some(Duration::from_millis(200))
+            )
+            .unwrap_err()
+            .kind()
+        );
+    }
+
+    #[test]
+    fn test_command_timeout_from() {
+        assert_eq!(None, command_timeout_from(None));
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            command_timeout_from(Some("30s".to_string()))
+        );
+        assert_eq!(
+            Some(Duration::from_secs(300)),
+            command_timeout_from(Some("5m".to_string()))
+        );
+        assert_eq!(None, command_timeout_from(Some("0s".to_string())));
+        assert_eq!(None, command_timeout_from(Some("soon".to_string())));
+    }
+
+    #[test]
+    fn test_output_as_lines_with_invalid_utf8() {
+        assert_eq!(
+            vec![
+                "rpool/good".to_string(),
+                "rpool/bad\u{FFFD}name".to_string(),
+                "rpool/also-good".to_string(),
+            ],
+            output_as_lines(Command::new(fixture("bin/bad_utf8"))).unwrap()
+        );
+
+        assert!(output_as_lines_strict(Command::new(fixture("bin/bad_utf8"))).is_err());
+
+        assert_eq!(
+            vec!["rpool/test\t-\t-\t-\t-".to_string()],
+            output_as_lines_strict(Command::new(fixture("bin/succeed"))).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_bytes_to_lines() {
+        assert_eq!(
+            vec!["a".to_string(), "".to_string(), "b\u{FFFD}".to_string()],
+            bytes_to_lines(b"a\r\n\nb\xff".to_vec(), false).unwrap()
+        );
+
+        assert_eq!(
+            vec!["a".to_string(), "".to_string(), "b".to_string()],
+            bytes_to_lines(b"a\r\n\nb\n".to_vec(), true).unwrap()
+        );
+
+        assert_eq!(
+            vec!["\u{FFFD}".to_string()],
+            bytes_to_lines(b"\xfe\n".to_vec(), false).unwrap()
+        );
+
+        assert!(bytes_to_lines(Vec::new(), true).unwrap().is_empty());
+        assert!(bytes_to_lines(b"\xfe\n".to_vec(), true).is_err());
+    }
+
+    #[test]
+    fn test_format_command() {
+        let mut cmd = Command::new("/usr/sbin/zfs");
+        cmd.arg("snapshot").arg("rpool/test@2024-10-27_09:45");
+        assert_eq!(
+            "/usr/sbin/zfs snapshot rpool/test@2024-10-27_09:45",
+            format_command(&cmd)
+        );
+
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("svcadm disable -t db");
+        assert_eq!("/bin/sh -c 'svcadm disable -t db'", format_command(&cmd));
+
+        let mut cmd = Command::new("/usr/bin/echo");
+        cmd.arg("it's").arg("").arg("$HOME;");
+        assert_eq!(r"/usr/bin/echo 'it'\''s' '' '$HOME;'", format_command(&cmd));
+
+        let cmd = Command::new("/opt/my tools/bin/zfs");
+        assert_eq!("'/opt/my tools/bin/zfs'", format_command(&cmd));
+    }
+
+    #[test]
+    fn test_run_action_noop() {
+        let tmp = tempdir().unwrap();
+        let marker = tmp.path().join("marker");
+        let mut cmd = Command::new("/usr/bin/touch");
+        cmd.arg(&marker);
+
+        let opts = Opts {
+            verbose: 0,
+            noop: true,
+        };
+
+        assert!(run_action(&mut cmd, &opts).unwrap().is_none());
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_run_action() {
+        let tmp = tempdir().unwrap();
+        let marker = tmp.path().join("marker");
+        let mut cmd = Command::new("/usr/bin/touch");
+        cmd.arg(&marker);
+
+        let opts = Opts {
+            verbose: 1,
+            noop: false,
+        };
+
+        assert!(run_action(&mut cmd, &opts)
+            .unwrap()
+            .unwrap()
+            .status
+            .success());
+        assert!(marker.exists());
+
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("echo oops >&2; exit 3");
+        let output = run_action(&mut cmd, &opts).unwrap().unwrap();
+        assert_eq!(Some(3), output.status.code());
+        assert_eq!("oops\n", String::from_utf8_lossy(&output.stderr));
+
+        assert!(run_action(&mut Command::new("/no/such/command"), &opts).is_err());
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(
+            "cannot create snapshot 'rpool/test@monday': dataset is busy",
+            TRANSIENT_ZFS_ERRORS
+        ));
+
+        assert!(is_transient(
+            "cannot destroy 'rpool/test@monday': Dataset is busy\n",
+            TRANSIENT_ZFS_ERRORS
+        ));
+
+        assert!(is_transient(
+            "cannot open 'tank': pool I/O is currently suspended",
+            TRANSIENT_ZFS_ERRORS
+        ));
+
+        assert!(!is_transient(
+            "cannot open 'rpool/nothing': dataset does not exist",
+            TRANSIENT_ZFS_ERRORS
+        ));
+
+        assert!(!is_transient("", TRANSIENT_ZFS_ERRORS));
+        assert!(!is_transient("dataset is busy", &[]));
+    }
+
+    // Each run of the script appends a line to a counter file, so we can see how many times it
+    // ran. It succeeds on the given run.
+    fn counting_command(counter: &std::path::Path, message: &str, succeed_on: u32) -> Command {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(format!(
+            "echo x >>{0}; [ $(wc -l <{0}) -ge {1} ] && exit 0; echo '{2}' >&2; exit 1",
+            counter.display(),
+            succeed_on,
+            message
+        ));
+        cmd
+    }
+
+    fn runs(counter: &std::path::Path) -> usize {
+        fs::read_to_string(counter).unwrap().lines().count()
+    }
+
+    #[test]
+    fn test_run_with_retry() {
+        let tmp = tempdir().unwrap();
+        let backoff = Duration::from_millis(1);
+
+        let counter = tmp.path().join("busy");
+        let mut cmd = counting_command(&counter, "dataset is busy", 99);
+        let output = run_with_retry(&mut cmd, 3, backoff, TRANSIENT_ZFS_ERRORS).unwrap();
+        assert!(!output.status.success());
+        assert_eq!(3, runs(&counter));
+
+        let counter = tmp.path().join("recovers");
+        let mut cmd = counting_command(&counter, "dataset is busy", 2);
+        let output = run_with_retry(&mut cmd, 3, backoff, TRANSIENT_ZFS_ERRORS).unwrap();
+        assert!(output.status.success());
+        assert_eq!(2, runs(&counter));
+
+        let counter = tmp.path().join("permanent");
+        let mut cmd = counting_command(&counter, "dataset does not exist", 99);
+        let output = run_with_retry(&mut cmd, 3, backoff, TRANSIENT_ZFS_ERRORS).unwrap();
+        assert!(!output.status.success());
+        assert_eq!(1, runs(&counter));
+
+        let mut cmd = Command::new("/no/such/zfs");
+        assert!(run_with_retry(&mut cmd, 3, backoff, TRANSIENT_ZFS_ERRORS).is_err());
+    }
 }
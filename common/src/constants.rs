@@ -1,2 +1,43 @@
 pub const ZFS: &str = "/usr/sbin/zfs";
+pub const ZPOOL: &str = "/usr/sbin/zpool";
 pub const DIFF: &str = "/usr/bin/diff";
+
+// Fragments of zfs error messages which mean it's worth trying again.
+pub const TRANSIENT_ZFS_ERRORS: &[&str] = &[
+    "dataset is busy",
+    "pool I/O is currently suspended",
+    "resource temporarily unavailable",
+];
+
+// The control directory which holds snapshot/. Snapshots exposed somewhere unusual, say through a
+// bind mount, can be found by setting this environment variable to a different name.
+pub const ZFS_CTLDIR: &str = ".zfs";
+pub const ZFS_CTLDIR_ENV: &str = "ZFS_TOOLS_CTLDIR";
+// zr can be pointed at a whole snapshot directory, rather than just a differently named control
+// directory.
+pub const ZFS_SNAPDIR_ENV: &str = "ZFS_SNAPDIR";
+
+// A zfs command which takes longer than this, say because its pool is suspended, is killed. It is
+// given as a duration, like 30s or 5m. By default there is no limit.
+pub const ZFS_TIMEOUT_ENV: &str = "ZFS_TOOLS_TIMEOUT";
+
+// ZFS gives its .zfs control directory a reserved inode number, which differs between platforms.
+#[cfg(target_os = "illumos")]
+pub const ZFS_CTLDIR_INO: u64 = 0x1;
+#[cfg(not(target_os = "illumos"))]
+pub const ZFS_CTLDIR_INO: u64 = 0xFFFF_FFFF_FFFF;
+
+#[cfg(target_os = "illumos")]
+pub const MNTTAB: &str = "/etc/mnttab";
+#[cfg(not(target_os = "illumos"))]
+pub const MNTTAB: &str = "/proc/mounts";
+
+// Files larger than this are copied in chunks rather than with fs::copy(), by default through a
+// buffer of COPY_BUFFER_SIZE bytes.
+pub const STREAM_THRESHOLD: u64 = 64 * 1024 * 1024;
+pub const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+// Verbosity levels, set by giving -v more than once.
+pub const INFO: u8 = 1;
+pub const DEBUG: u8 = 2;
+pub const TRACE: u8 = 3;
@@ -0,0 +1,100 @@
+//! Tidying up dataset names given by users.
+//!
+use anyhow::anyhow;
+
+/// Trims surrounding whitespace and trailing slashes from a dataset name, so `rpool/data/` is
+/// `rpool/data`. Whitespace inside the name is kept, because ZFS allows spaces. A name with a
+/// leading slash is more likely a path than a dataset, and one with an `@` is a snapshot, so both
+/// are errors.
+///
+pub fn normalize(name: &str) -> anyhow::Result<String> {
+    let trimmed = name.trim().trim_end_matches('/').trim_end();
+
+    if trimmed.is_empty() {
+        return Err(anyhow!("'{}' is not a dataset name", name));
+    }
+
+    if trimmed.starts_with('/') {
+        return Err(anyhow!(
+            "'{}' looks like a path, not a dataset name",
+            trimmed
+        ));
+    }
+
+    if trimmed.contains('@') {
+        return Err(anyhow!("'{}' is a snapshot, not a dataset", trimmed));
+    }
+
+    Ok(trimmed.to_string())
+}
+
+/// Normalizes every name in the list, failing on the first bad one.
+///
+pub fn normalize_all(names: &[String]) -> anyhow::Result<Vec<String>> {
+    names.iter().map(|name| normalize(name)).collect()
+}
+
+/// Normalizes each name in a comma-separated list of rules, like the argument to `--omit`, and
+/// puts the list back together.
+///
+pub fn normalize_rules(rules: &str) -> anyhow::Result<String> {
+    let names = rules
+        .split(',')
+        .map(normalize)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(names.join(","))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!("rpool/data", normalize("rpool/data").unwrap());
+        assert_eq!("rpool/data", normalize("rpool/data/").unwrap());
+        assert_eq!("rpool/data", normalize("rpool/data///").unwrap());
+        assert_eq!("rpool", normalize("rpool/").unwrap());
+        assert_eq!("rpool/data", normalize("  rpool/data/ \n").unwrap());
+        assert_eq!("rpool/data", normalize("rpool/data/ ").unwrap());
+        assert_eq!("rpool/my data", normalize(" rpool/my data ").unwrap());
+        assert_eq!("rpool/*", normalize("rpool/*").unwrap());
+    }
+
+    #[test]
+    fn test_normalize_errors() {
+        assert_eq!(
+            "'/rpool/data' looks like a path, not a dataset name",
+            normalize("/rpool/data/").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "'rpool/data@monday' is a snapshot, not a dataset",
+            normalize("rpool/data@monday").unwrap_err().to_string()
+        );
+        assert!(normalize("").is_err());
+        assert!(normalize("   ").is_err());
+        assert!(normalize("/").is_err());
+        assert!(normalize(" /rpool").is_err());
+    }
+
+    #[test]
+    fn test_normalize_all() {
+        assert_eq!(
+            vec!["rpool".to_string(), "tank/a b".to_string()],
+            normalize_all(&["rpool/".to_string(), "tank/a b ".to_string()]).unwrap()
+        );
+        assert!(normalize_all(&["rpool".to_string(), "/tank".to_string()]).is_err());
+        assert!(normalize_all(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_normalize_rules() {
+        assert_eq!("rpool/data", normalize_rules("rpool/data/").unwrap());
+        assert_eq!(
+            "rpool/data,tank/*,my data",
+            normalize_rules("rpool/data/, tank/*/,my data ").unwrap()
+        );
+        assert!(normalize_rules("rpool,/tank").is_err());
+        assert!(normalize_rules("rpool,,tank").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! Parses human-friendly durations like `30d` or `12h`, and cutoff times given either as a date
+//! or as an age. Anything in the suite which takes a length of time, or a point in time, from the
+//! user should use this, so they all accept the same things.
+//!
+use crate::timefmt;
+use anyhow::anyhow;
+use std::time::Duration;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+const WEEK: u64 = 7 * DAY;
+
+/// Turns a whole number followed by one of `s`, `m`, `h`, `d`, or `w` into a Duration. The
+/// suffix is required, because a bare number could mean anything.
+///
+pub fn parse(input: &str) -> anyhow::Result<Duration> {
+    let input = input.trim();
+
+    let Some(suffix) = input.chars().last() else {
+        return Err(anyhow!("duration is empty"));
+    };
+
+    let multiplier = match suffix {
+        's' => 1,
+        'm' => MINUTE,
+        'h' => HOUR,
+        'd' => DAY,
+        'w' => WEEK,
+        c if c.is_ascii_digit() => {
+            return Err(anyhow!(
+                "duration '{}' needs a unit: one of s, m, h, d, w",
+                input
+            ))
+        }
+        c => return Err(anyhow!("duration '{}' has unknown unit '{}'", input, c)),
+    };
+
+    let number = &input[..input.len() - 1];
+
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return Err(anyhow!(
+            "duration '{}' must start with a whole number",
+            input
+        ));
+    }
+
+    let seconds = number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| anyhow!("duration '{}' is too long", input))?;
+
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Turns a cutoff into a Unix timestamp. The cutoff may be an age, like `3d`, meaning that long
+/// ago, or a date, like `2024-10-27` or `2024-10-27 09:45`, in local time. Its signature suits
+/// clap's `value_parser`.
+///
+pub fn parse_cutoff(arg: &str) -> anyhow::Result<i64> {
+    cutoff_at(arg, OffsetDateTime::now_utc(), timefmt::local_offset())
+}
+
+/// Like `parse_cutoff()`, but ages are counted back from now, and dates are in the given offset.
+///
+pub fn cutoff_at(arg: &str, now: OffsetDateTime, offset: UtcOffset) -> anyhow::Result<i64> {
+    if let Ok(age) = parse(arg) {
+        let age = i64::try_from(age.as_secs()).unwrap_or(i64::MAX);
+        return Ok(now.unix_timestamp().saturating_sub(age));
+    }
+
+    PrimitiveDateTime::parse(
+        arg,
+        format_description!("[year]-[month]-[day] [hour]:[minute]"),
+    )
+    .or_else(|_| {
+        Date::parse(arg, format_description!("[year]-[month]-[day]")).map(|d| d.midnight())
+    })
+    .map(|datetime| datetime.assume_offset(offset).unix_timestamp())
+    .map_err(|_| {
+        anyhow!(
+            "'{}' is not a date (YYYY-MM-DD or 'YYYY-MM-DD HH:MM') or an age like 3d",
+            arg
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Duration::from_secs(45), parse("45s").unwrap());
+        assert_eq!(Duration::from_secs(5 * 60), parse("5m").unwrap());
+        assert_eq!(Duration::from_secs(12 * 3600), parse("12h").unwrap());
+        assert_eq!(Duration::from_secs(30 * 86400), parse("30d").unwrap());
+        assert_eq!(Duration::from_secs(2 * 604800), parse("2w").unwrap());
+        assert_eq!(Duration::from_secs(0), parse("0s").unwrap());
+        assert_eq!(Duration::from_secs(3600), parse(" 1h\n").unwrap());
+    }
+
+    #[test]
+    fn test_parse_large_values() {
+        assert_eq!(
+            Duration::from_secs(10_000 * 604800),
+            parse("10000w").unwrap()
+        );
+        assert_eq!(
+            Duration::from_secs(u64::MAX),
+            parse(&format!("{}s", u64::MAX)).unwrap()
+        );
+        assert!(parse(&format!("{}m", u64::MAX)).is_err());
+        assert!(parse("99999999999999999999999s").is_err());
+    }
+
+    #[test]
+    fn test_cutoff_at() {
+        let now = OffsetDateTime::from_unix_timestamp(1730563919).unwrap();
+        let utc = UtcOffset::UTC;
+
+        assert_eq!(1730563919 - 3 * 86400, cutoff_at("3d", now, utc).unwrap());
+        assert_eq!(1729987200, cutoff_at("2024-10-27", now, utc).unwrap());
+        assert_eq!(1730022300, cutoff_at("2024-10-27 09:45", now, utc).unwrap());
+
+        let bst = UtcOffset::from_hms(1, 0, 0).unwrap();
+        assert_eq!(
+            1729987200 - 3600,
+            cutoff_at("2024-10-27", now, bst).unwrap()
+        );
+
+        assert!(cutoff_at("yesterday", now, utc).is_err());
+        assert!(cutoff_at("3", now, utc).is_err());
+        assert!(cutoff_at("2024-13-01", now, utc).is_err());
+    }
+
+    #[test]
+    fn test_parse_garbage() {
+        assert!(parse("").is_err());
+        assert!(parse("   ").is_err());
+        assert!(parse("30").is_err());
+        assert!(parse("30y").is_err());
+        assert!(parse("30D").is_err());
+        assert!(parse("30ms").is_err());
+        assert!(parse("d").is_err());
+        assert!(parse("-1d").is_err());
+        assert!(parse("+1d").is_err());
+        assert!(parse("1.5h").is_err());
+        assert!(parse("1 h").is_err());
+        assert!(parse("1h30m").is_err());
+        assert!(parse("30日").is_err());
+    }
+}
@@ -1,53 +1,296 @@
+use crate::constants::{DEBUG, STREAM_THRESHOLD, TRACE};
 use crate::types::ZpZrOpts;
-use std::fs;
-use std::io;
-use std::path::Path;
+use crate::verbose_at;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{self as unix_fs, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+
+/// What a copy did. `skipped` lists the targets which were left alone because they exist and
+/// noclobber is set.
+///
+#[derive(Debug, Default, PartialEq)]
+pub struct CopyStats {
+    pub bytes: u64,
+    pub skipped: Vec<PathBuf>,
+}
+
+impl CopyStats {
+    fn copied(bytes: u64) -> Self {
+        CopyStats {
+            bytes,
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, other: CopyStats) {
+        self.bytes += other.bytes;
+        self.skipped.extend(other.skipped);
+    }
+}
 
 /// Recursively copies directory trees. Is able to merge with existing targets if opts.noclobber
 /// is set.
-pub fn copy_file(src: &Path, dest: &Path, opts: &ZpZrOpts) -> io::Result<u64> {
+pub fn copy_file(src: &Path, dest: &Path, opts: &ZpZrOpts) -> io::Result<CopyStats> {
+    copy_file_with(src, dest, opts, &|dir, _src_dir| fs::create_dir_all(dir))
+}
+
+/// Like `copy_file()`, but creates each missing target directory with `mkdir`, which is given the
+/// new directory and the one it is copied from. Nothing is created under noop.
+///
+pub fn copy_file_with(
+    src: &Path,
+    dest: &Path,
+    opts: &ZpZrOpts,
+    mkdir: &dyn Fn(&Path, &Path) -> io::Result<()>,
+) -> io::Result<CopyStats> {
     if src.is_file() {
         copy_file_action(src, dest, opts)
     } else {
-        if !dest.exists() {
-            fs::create_dir_all(dest)?;
+        if !dest.exists() && !opts.noop {
+            mkdir(dest, src)?;
         }
 
+        let mut stats = CopyStats::default();
+
         for f in fs::read_dir(src)? {
             let f = f?;
             let src_path = f.path();
             let dest_path = dest.join(f.file_name());
 
             if src.is_file() {
-                copy_file_action(&src_path, &dest_path, opts)?;
+                stats.add(copy_file_action(&src_path, &dest_path, opts)?);
             } else {
-                copy_file(&src_path, &dest_path, opts)?;
+                stats.add(copy_file_with(&src_path, &dest_path, opts, mkdir)?);
             }
         }
 
-        Ok(0)
+        Ok(stats)
     }
 }
 
-fn copy_file_action(src: &Path, dest: &Path, opts: &ZpZrOpts) -> io::Result<u64> {
+fn copy_file_action(src: &Path, dest: &Path, opts: &ZpZrOpts) -> io::Result<CopyStats> {
+    copy_file_action_to(src, dest, opts, &mut io::stdout())
+}
+
+// Under noop, the planned copy is always printed. Otherwise copies are only printed if we are
+// verbose. Noclobber skips are only printed if we are verbose, noop or not.
+fn copy_file_action_to(
+    src: &Path,
+    dest: &Path,
+    opts: &ZpZrOpts,
+    out: &mut impl Write,
+) -> io::Result<CopyStats> {
     if dest.exists() && opts.noclobber {
-        if opts.verbose {
-            println!("{} exists and noclobber is set", dest.display());
+        if opts.verbose > 0 {
+            writeln!(out, "{} exists and noclobber is set", dest.display())?;
         }
-        Ok(0)
+
+        Ok(CopyStats {
+            bytes: 0,
+            skipped: vec![dest.to_path_buf()],
+        })
     } else {
-        if opts.verbose || opts.noop {
-            println!("{} -> {}", src.display(), dest.display());
+        if opts.noop || opts.verbose > 0 {
+            writeln!(out, "{} -> {}", src.display(), dest.display())?;
+        }
+
+        if let Some(trash_dir) = &opts.trash_dir {
+            if dest.is_file() {
+                move_to_trash(dest, trash_dir, opts, out)?;
+            }
         }
 
         if opts.noop || (src.is_dir() && dest.exists()) {
-            Ok(0)
+            Ok(CopyStats::default())
         } else {
-            fs::copy(src, dest)
+            let bytes = copy_contents(src, dest, opts, STREAM_THRESHOLD)?;
+
+            if opts.preserve_owner {
+                copy_ownership(src, dest)?;
+            }
+
+            if opts.preserve_xattr {
+                let count = copy_xattrs(src, dest)?;
+                verbose_at!(opts, DEBUG, "copied {} extended attributes", count);
+            }
+
+            Ok(CopyStats::copied(bytes))
+        }
+    }
+}
+
+// The file keeps its full path under the trash directory, so /tank/home/file goes to
+// TRASH/tank/home/file. Anything already there is not overwritten.
+fn move_to_trash(
+    file: &Path,
+    trash_dir: &Path,
+    opts: &ZpZrOpts,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let trash_file = free_name(&trash_path(file, trash_dir)?);
+
+    if opts.noop || opts.verbose > 0 {
+        writeln!(out, "{} -> {}", file.display(), trash_file.display())?;
+    }
+
+    if opts.noop {
+        return Ok(());
+    }
+
+    if let Some(parent) = trash_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // The trash may well be on a different dataset.
+    match fs::rename(file, &trash_file) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(file, &trash_file)?;
+            fs::remove_file(file)
         }
+        result => result,
     }
 }
 
+fn trash_path(file: &Path, trash_dir: &Path) -> io::Result<PathBuf> {
+    let file = if file.is_absolute() {
+        file.to_path_buf()
+    } else {
+        env::current_dir()?.join(file)
+    };
+
+    let mut relative = PathBuf::new();
+
+    for component in file.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::ParentDir => {
+                relative.pop();
+            }
+            _ => (),
+        }
+    }
+
+    Ok(trash_dir.join(relative))
+}
+
+// If file is taken, tries file.1, file.2 and so on.
+fn free_name(file: &Path) -> PathBuf {
+    if !file.exists() {
+        return file.to_path_buf();
+    }
+
+    (1..)
+        .map(|n| {
+            let mut name = file.as_os_str().to_owned();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        })
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+// The owner and group of the file, as numeric IDs.
+fn ownership(file: &Path) -> io::Result<(u32, u32)> {
+    let metadata = fs::metadata(file)?;
+    Ok((metadata.uid(), metadata.gid()))
+}
+
+// Only root can give files away. Anyone else gets a warning, and keeps the copy, which is still
+// what they asked for in every other way.
+fn copy_ownership(src: &Path, dest: &Path) -> io::Result<()> {
+    let (uid, gid) = ownership(src)?;
+
+    match unix_fs::chown(dest, Some(uid), Some(gid)) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            eprintln!(
+                "WARNING: could not set owner of {} to {}:{}: {}",
+                dest.display(),
+                uid,
+                gid,
+                e
+            );
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+// Returns the number of attributes copied. Where the platform or filesystem has no extended
+// attributes there is nothing to copy, and attributes we aren't allowed to set, like those in the
+// trusted namespace, only get a warning, as ownership does.
+fn copy_xattrs(src: &Path, dest: &Path) -> io::Result<usize> {
+    let names = match xattr::list(src) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut count = 0;
+
+    for name in names {
+        let Some(value) = xattr::get(src, &name)? else {
+            continue;
+        };
+
+        match xattr::set(dest, &name, &value) {
+            Ok(()) => count += 1,
+            Err(e)
+                if e.kind() == io::ErrorKind::PermissionDenied
+                    || e.kind() == io::ErrorKind::Unsupported =>
+            {
+                eprintln!(
+                    "WARNING: could not set {} on {}: {}",
+                    name.to_string_lossy(),
+                    dest.display(),
+                    e
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(count)
+}
+
+// Small files go through fs::copy(). Anything bigger than threshold is streamed, so we control
+// the buffering and can report progress as we go.
+fn copy_contents(src: &Path, dest: &Path, opts: &ZpZrOpts, threshold: u64) -> io::Result<u64> {
+    let size = fs::metadata(src)?.len();
+
+    if size > threshold {
+        stream_copy(src, dest, size, opts)
+    } else {
+        fs::copy(src, dest)
+    }
+}
+
+fn stream_copy(src: &Path, dest: &Path, size: u64, opts: &ZpZrOpts) -> io::Result<u64> {
+    let mut reader = File::open(src)?;
+    let mut writer = File::create(dest)?;
+    let mut buf = vec![0; opts.buffer_size.max(1)];
+    let mut copied: u64 = 0;
+
+    loop {
+        let count = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        writer.write_all(&buf[..count])?;
+        copied += count as u64;
+        verbose_at!(opts, TRACE, "{}: {}/{} bytes", dest.display(), copied, size);
+    }
+
+    writer.flush()?;
+    // fs::copy() does this for us
+    fs::set_permissions(dest, reader.metadata()?.permissions())?;
+    Ok(copied)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -64,18 +307,51 @@ mod tests {
         fs::write(&dest, "please don't clobber me!").unwrap();
 
         let opts = ZpZrOpts {
-            verbose: false,
-            noop: false,
             noclobber: true,
+            ..Default::default()
         };
 
-        assert!(copy_file(&src, &dest, &opts).is_ok());
+        assert_eq!(
+            vec![dest.clone()],
+            copy_file(&src, &dest, &opts).unwrap().skipped
+        );
         assert_eq!(
             "please don't clobber me!",
             fs::read_to_string(&dest).unwrap()
         );
     }
 
+    #[test]
+    fn test_copy_directory_with_noclobber() {
+        let tmp = tempdir().unwrap();
+        let src_dir = tmp.path().join("src_dir");
+        let dest_dir = tmp.path().join("dest_dir");
+
+        fs::create_dir(&src_dir).unwrap();
+        fs::create_dir(&dest_dir).unwrap();
+        fs::write(src_dir.join("old"), "from snapshot").unwrap();
+        fs::write(src_dir.join("new"), "from snapshot").unwrap();
+        fs::write(dest_dir.join("old"), "live").unwrap();
+
+        let opts = ZpZrOpts {
+            noclobber: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            CopyStats {
+                bytes: 13,
+                skipped: vec![dest_dir.join("old")],
+            },
+            copy_file(&src_dir, &dest_dir, &opts).unwrap()
+        );
+        assert_eq!("live", fs::read_to_string(dest_dir.join("old")).unwrap());
+        assert_eq!(
+            "from snapshot",
+            fs::read_to_string(dest_dir.join("new")).unwrap()
+        );
+    }
+
     #[test]
     fn test_copy_file_without_noclobber() {
         let tmp = tempdir().unwrap();
@@ -85,11 +361,7 @@ mod tests {
         fs::write(&src, "it's clobbering time").unwrap();
         fs::write(&dest, "blah blah blah").unwrap();
 
-        let opts = ZpZrOpts {
-            verbose: false,
-            noop: false,
-            noclobber: false,
-        };
+        let opts = ZpZrOpts::default();
 
         assert!(copy_file(&src, &dest, &opts).is_ok());
         assert_eq!("it's clobbering time", fs::read_to_string(&dest).unwrap());
@@ -104,9 +376,8 @@ mod tests {
         fs::write(&src, "blah blah blah").unwrap();
 
         let opts = ZpZrOpts {
-            verbose: false,
             noop: true,
-            noclobber: false,
+            ..Default::default()
         };
 
         assert!(copy_file(&src, &dest, &opts).is_ok());
@@ -123,11 +394,7 @@ mod tests {
         let src = src_dir.join("file.txt");
         fs::write(&src, "blah blah blah").unwrap();
 
-        let opts = ZpZrOpts {
-            verbose: false,
-            noop: false,
-            noclobber: false,
-        };
+        let opts = ZpZrOpts::default();
 
         let dest = dest_dir.join("file.txt");
 
@@ -137,6 +404,86 @@ mod tests {
         assert_eq!(dest_content, "blah blah blah");
     }
 
+    #[test]
+    fn test_copy_directory_with_noop() {
+        let tmp = tempdir().unwrap();
+        let src_dir = tmp.path().join("src_dir");
+        let dest_dir = tmp.path().join("dest_dir");
+
+        fs::create_dir_all(src_dir.join("sub")).unwrap();
+        fs::write(src_dir.join("sub/file.txt"), "blah blah blah").unwrap();
+
+        let opts = ZpZrOpts {
+            noop: true,
+            ..Default::default()
+        };
+
+        assert!(copy_file(&src_dir, &dest_dir, &opts).is_ok());
+        assert!(!dest_dir.exists());
+    }
+
+    #[test]
+    fn test_copy_file_with_trash_dir() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("src.txt");
+        let dest = tmp.path().join("live/dest.txt");
+        let trash_dir = tmp.path().join("trash");
+
+        fs::create_dir(tmp.path().join("live")).unwrap();
+        fs::write(&src, "new content").unwrap();
+        fs::write(&dest, "old content").unwrap();
+
+        let opts = ZpZrOpts {
+            trash_dir: Some(trash_dir.clone()),
+            ..Default::default()
+        };
+
+        let trashed = trash_dir.join(dest.strip_prefix("/").unwrap());
+
+        assert!(copy_file(&src, &dest, &opts).is_ok());
+        assert_eq!("new content", fs::read_to_string(&dest).unwrap());
+        assert_eq!("old content", fs::read_to_string(&trashed).unwrap());
+
+        fs::write(&src, "newer content").unwrap();
+        assert!(copy_file(&src, &dest, &opts).is_ok());
+        assert_eq!("newer content", fs::read_to_string(&dest).unwrap());
+        assert_eq!("old content", fs::read_to_string(&trashed).unwrap());
+
+        let mut second = trashed.into_os_string();
+        second.push(".1");
+        assert_eq!("new content", fs::read_to_string(second).unwrap());
+    }
+
+    #[test]
+    fn test_trash_path() {
+        assert_eq!(
+            PathBuf::from("/trash/tank/home/file"),
+            trash_path(Path::new("/tank/home/file"), Path::new("/trash")).unwrap()
+        );
+        assert_eq!(
+            PathBuf::from("/trash/tank/file"),
+            trash_path(Path::new("/tank/home/../file"), Path::new("/trash")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_copy_contents_streamed() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("src.bin");
+        let dest = tmp.path().join("dest.bin");
+        let content: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+
+        fs::write(&src, &content).unwrap();
+
+        let opts = ZpZrOpts {
+            buffer_size: 1024,
+            ..Default::default()
+        };
+
+        assert_eq!(10_000, copy_contents(&src, &dest, &opts, 4096).unwrap());
+        assert_eq!(content, fs::read(&dest).unwrap());
+    }
+
     #[test]
     fn test_copy_file_action_verbose() {
         let tmp = tempdir().unwrap();
@@ -146,9 +493,8 @@ mod tests {
         fs::write(&src, "blah blah blah").unwrap();
 
         let opts = ZpZrOpts {
-            verbose: true,
-            noop: false,
-            noclobber: false,
+            verbose: 1,
+            ..Default::default()
         };
 
         assert!(copy_file_action(&src, &dest, &opts).is_ok());
@@ -156,4 +502,139 @@ mod tests {
         let dest_content = fs::read_to_string(&dest).unwrap();
         assert_eq!(dest_content, "blah blah blah");
     }
+
+    // Runs copy_file_action_to() on a fresh source and target, with the target already there if
+    // noclobber is set. Returns what was printed, and whether the target was written.
+    fn action_output(verbose: u8, noop: bool, noclobber: bool) -> (String, bool) {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("src");
+        let dest = tmp.path().join("dest");
+        fs::write(&src, "new").unwrap();
+
+        if noclobber {
+            fs::write(&dest, "old").unwrap();
+        }
+
+        let opts = ZpZrOpts {
+            verbose,
+            noop,
+            noclobber,
+            ..Default::default()
+        };
+
+        let mut out = Vec::new();
+        copy_file_action_to(&src, &dest, &opts, &mut out).unwrap();
+
+        let output = String::from_utf8(out)
+            .unwrap()
+            .replace(&tmp.path().display().to_string(), "DIR");
+        let written = fs::read_to_string(&dest).is_ok_and(|s| s == "new");
+
+        (output, written)
+    }
+
+    #[test]
+    fn test_ownership() {
+        let tmp = tempdir().unwrap();
+        let file = tmp.path().join("file");
+        fs::write(&file, "owned").unwrap();
+        let metadata = fs::metadata(&file).unwrap();
+
+        assert_eq!((metadata.uid(), metadata.gid()), ownership(&file).unwrap());
+        assert!(ownership(&tmp.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_copy_file_preserving_owner() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("src");
+        let dest = tmp.path().join("dest");
+        fs::write(&src, "owned").unwrap();
+
+        let opts = ZpZrOpts {
+            preserve_owner: true,
+            ..Default::default()
+        };
+
+        // Anyone may chown a file to themselves, so this works unprivileged.
+        copy_file(&src, &dest, &opts).unwrap();
+        assert_eq!("owned", fs::read_to_string(&dest).unwrap());
+        assert_eq!(ownership(&src).unwrap(), ownership(&dest).unwrap());
+    }
+
+    #[test]
+    fn test_copy_file_preserving_xattrs() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("src");
+        let dest = tmp.path().join("dest");
+        fs::write(&src, "attributed").unwrap();
+
+        // Not every platform or filesystem has user attributes.
+        if xattr::set(&src, "user.zfs-tools.test", b"monday").is_err() {
+            return;
+        }
+
+        let opts = ZpZrOpts {
+            preserve_xattr: true,
+            ..Default::default()
+        };
+
+        copy_file(&src, &dest, &opts).unwrap();
+        assert_eq!("attributed", fs::read_to_string(&dest).unwrap());
+        assert_eq!(
+            Some(b"monday".to_vec()),
+            xattr::get(&dest, "user.zfs-tools.test").unwrap()
+        );
+
+        let plain = tmp.path().join("plain");
+        copy_file(
+            &src,
+            &plain,
+            &ZpZrOpts {
+                preserve_xattr: false,
+                ..opts
+            },
+        )
+        .unwrap();
+        assert_eq!(None, xattr::get(&plain, "user.zfs-tools.test").unwrap());
+    }
+
+    #[test]
+    fn test_copy_xattrs_without_any() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("src");
+        let dest = tmp.path().join("dest");
+        fs::write(&src, "plain").unwrap();
+        fs::write(&dest, "plain").unwrap();
+
+        assert!(copy_xattrs(&src, &dest).is_ok());
+        assert!(copy_xattrs(&tmp.path().join("missing"), &dest).is_err());
+    }
+
+    #[test]
+    fn test_copy_file_action_output() {
+        assert_eq!(("".to_string(), true), action_output(0, false, false));
+        assert_eq!(
+            ("DIR/src -> DIR/dest\n".to_string(), true),
+            action_output(1, false, false)
+        );
+        assert_eq!(
+            ("DIR/src -> DIR/dest\n".to_string(), false),
+            action_output(0, true, false)
+        );
+        assert_eq!(
+            ("DIR/src -> DIR/dest\n".to_string(), false),
+            action_output(1, true, false)
+        );
+        assert_eq!(("".to_string(), false), action_output(0, false, true));
+        assert_eq!(("".to_string(), false), action_output(0, true, true));
+        assert_eq!(
+            ("DIR/dest exists and noclobber is set\n".to_string(), false),
+            action_output(1, false, true)
+        );
+        assert_eq!(
+            ("DIR/dest exists and noclobber is set\n".to_string(), false),
+            action_output(1, true, true)
+        );
+    }
 }
@@ -0,0 +1,72 @@
+//! Filesystem helpers which don't need ZFS.
+//!
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Returns the total size, in bytes, of the regular files at or below the given path. Symbolic
+/// links are not followed, so nothing outside the tree is counted, and nothing is counted twice
+/// through a link. A link itself counts for nothing.
+///
+pub fn tree_size(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+
+    if metadata.is_file() {
+        return Ok(metadata.len());
+    }
+
+    if !metadata.is_dir() {
+        return Ok(0);
+    }
+
+    let mut ret = 0;
+    let mut to_visit = vec![path.to_path_buf()];
+
+    while let Some(dir) = to_visit.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+
+            if file_type.is_dir() {
+                to_visit.push(entry.path());
+            } else if file_type.is_file() {
+                ret += entry.metadata()?.len();
+            }
+        }
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_tree_size() {
+        let tmp = tempdir().unwrap();
+        let tree = tmp.path().join("tree");
+        fs::create_dir_all(tree.join("a/b")).unwrap();
+        fs::create_dir(tree.join("empty")).unwrap();
+        fs::write(tree.join("ten"), "0123456789").unwrap();
+        fs::write(tree.join("a/five"), "01234").unwrap();
+        fs::write(tree.join("a/b/three"), "012").unwrap();
+        fs::write(tree.join("a/b/nothing"), "").unwrap();
+
+        let outside = tmp.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("big"), vec![0; 4096]).unwrap();
+        symlink(&outside, tree.join("a/link_to_dir")).unwrap();
+        symlink(outside.join("big"), tree.join("link_to_file")).unwrap();
+        symlink(&tree, tree.join("a/b/loop")).unwrap();
+
+        assert_eq!(18, tree_size(&tree).unwrap());
+        assert_eq!(8, tree_size(&tree.join("a")).unwrap());
+        assert_eq!(10, tree_size(&tree.join("ten")).unwrap());
+        assert_eq!(0, tree_size(&tree.join("empty")).unwrap());
+        assert_eq!(0, tree_size(&tree.join("link_to_file")).unwrap());
+        assert!(tree_size(&tree.join("missing")).is_err());
+    }
+}
@@ -1,8 +1,18 @@
 pub mod command_helpers;
 pub mod constants;
+pub mod dataset;
+pub mod duration;
 pub mod file_copier;
+pub mod fsutil;
+pub mod lock;
+pub mod macros;
+pub mod pathutil;
+pub mod pool;
 pub mod rules;
+pub mod snapshot;
 pub mod spec_helper;
+pub mod timefmt;
 pub mod types;
 pub mod zfs_file;
 pub mod zfs_info;
+pub mod zfs_runner;
@@ -0,0 +1,73 @@
+//! Advisory file locks, to stop two copies of a tool treading on each other.
+//!
+use anyhow::anyhow;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::Path;
+
+/// Holds an exclusive lock on a file until it is dropped.
+///
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+/// Takes an exclusive lock on the given file, creating it if need be. If another process holds
+/// the lock, this fails immediately rather than waiting. Our PID is written to the file to help
+/// anyone wondering who has it.
+///
+pub fn acquire(path: &Path) -> anyhow::Result<Lock> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(|e| anyhow!("cannot open lock file {}: {}", path.display(), e))?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(anyhow!("{} is locked by another process", path.display()))
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(anyhow!("cannot lock {}: {}", path.display(), e))
+        }
+    }
+
+    file.set_len(0)?;
+    writeln!(file, "{}", std::process::id())?;
+
+    Ok(Lock { _file: file })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_acquire() {
+        let tmp = tempdir().unwrap();
+        let lock_file = tmp.path().join("test.lock");
+
+        let lock = acquire(&lock_file).unwrap();
+        assert_eq!(
+            format!("{}\n", std::process::id()),
+            fs::read_to_string(&lock_file).unwrap()
+        );
+
+        assert!(acquire(&lock_file)
+            .unwrap_err()
+            .to_string()
+            .ends_with("is locked by another process"));
+
+        drop(lock);
+        assert!(acquire(&lock_file).is_ok());
+    }
+
+    #[test]
+    fn test_acquire_unwritable() {
+        assert!(acquire(Path::new("/no/such/dir/test.lock")).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! Macros for printing progress messages. Anything with a `verbose` field holding the number of
+//! times -v was given can be passed as the first argument.
+//!
+
+/// Prints, like println!, if the verbosity of the first argument is at least the given level.
+/// Levels are `INFO`, `DEBUG`, and `TRACE`, from `constants`. Arguments are not evaluated if
+/// nothing is printed.
+///
+#[macro_export]
+macro_rules! verbose_at {
+    ($opts:expr, $level:expr, $($arg:tt)*) => {
+        if $opts.verbose >= $level {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Prints, like println!, if any verbosity is set. The same as `verbose_at!` at `INFO` level.
+///
+#[macro_export]
+macro_rules! verbose {
+    ($opts:expr, $($arg:tt)*) => {
+        $crate::verbose_at!($opts, $crate::constants::INFO, $($arg)*)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::constants::{DEBUG, INFO, TRACE};
+    use crate::types::Opts;
+
+    #[test]
+    fn test_verbose_at() {
+        let opts = Opts {
+            verbose: 2,
+            noop: false,
+        };
+
+        let mut printed = 0;
+
+        verbose_at!(opts, INFO, "{}", {
+            printed += 1;
+            "info"
+        });
+
+        verbose_at!(opts, DEBUG, "{}", {
+            printed += 1;
+            "debug"
+        });
+
+        verbose_at!(opts, TRACE, "{}", {
+            printed += 1;
+            "trace"
+        });
+
+        assert_eq!(2, printed);
+    }
+
+    #[test]
+    fn test_verbose() {
+        let mut printed = 0;
+
+        let quiet = Opts {
+            verbose: 0,
+            noop: false,
+        };
+
+        verbose!(quiet, "{}", {
+            printed += 1;
+            "quiet"
+        });
+
+        assert_eq!(0, printed);
+
+        let loud = Opts {
+            verbose: 1,
+            noop: false,
+        };
+
+        verbose!(loud, "{}", {
+            printed += 1;
+            "loud"
+        });
+
+        assert_eq!(1, printed);
+    }
+}
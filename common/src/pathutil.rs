@@ -0,0 +1,171 @@
+//! Does what a shell would have done to a path argument, for when there was no shell to do it,
+//! like under xargs.
+//!
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Expands a leading `~` or `~user`, and any `$VAR` or `${VAR}`, in the given path. Anything
+/// which can't be expanded, like an unknown user or an unset variable, is left as it is, so the
+/// error comes later, naming the path the user gave.
+///
+pub fn expand(arg: &str) -> PathBuf {
+    PathBuf::from(expand_with(arg, home_of, |name| env::var(name).ok()))
+}
+
+/// Like `expand()`, but home directories and variables are looked up with the given functions.
+/// `home` is given None for the current user.
+///
+pub fn expand_with(
+    arg: &str,
+    home: impl Fn(Option<&str>) -> Option<String>,
+    var: impl Fn(&str) -> Option<String>,
+) -> String {
+    expand_vars(&expand_tilde(arg, home), var)
+}
+
+fn expand_tilde(arg: &str, home: impl Fn(Option<&str>) -> Option<String>) -> String {
+    let Some(rest) = arg.strip_prefix('~') else {
+        return arg.to_string();
+    };
+
+    let (user, tail) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, ""),
+    };
+
+    let user = if user.is_empty() { None } else { Some(user) };
+
+    match home(user) {
+        Some(dir) => format!("{}{}", dir, tail),
+        None => arg.to_string(),
+    }
+}
+
+fn expand_vars(arg: &str, var: impl Fn(&str) -> Option<String>) -> String {
+    let mut ret = String::new();
+    let mut rest = arg;
+
+    while let Some(index) = rest.find('$') {
+        ret.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+
+        let (name, reference_len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let len = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..len], len)
+            }
+        };
+
+        match var_value(name, &var) {
+            Some(value) => ret.push_str(&value),
+            None => ret.push_str(&rest[index..index + 1 + reference_len]),
+        }
+
+        rest = &after[reference_len..];
+    }
+
+    ret.push_str(rest);
+    ret
+}
+
+fn var_value(name: &str, var: &impl Fn(&str) -> Option<String>) -> Option<String> {
+    if name.is_empty() {
+        None
+    } else {
+        var(name)
+    }
+}
+
+// The current user's home is $HOME. Anyone else's comes from the password file.
+fn home_of(user: Option<&str>) -> Option<String> {
+    match user {
+        None => env::var("HOME").ok(),
+        Some(user) => home_in_passwd(&fs::read_to_string("/etc/passwd").ok()?, user),
+    }
+}
+
+fn home_in_passwd(passwd: &str, user: &str) -> Option<String> {
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+
+        if fields.len() >= 6 && fields[0] == user {
+            Some(fields[5].to_string())
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PASSWD: &str = "root:x:0:0:root:/root:/bin/bash
+nobody:x:65534:65534:nobody:/nonexistent:/usr/sbin/nologin
+rob:x:1000:1000:Rob:/home/rob:/bin/zsh
+";
+
+    fn expand_test(arg: &str) -> String {
+        expand_with(
+            arg,
+            |user| match user {
+                None => Some("/home/me".to_string()),
+                Some(user) => home_in_passwd(PASSWD, user),
+            },
+            |name| match name {
+                "HOME" => Some("/home/me".to_string()),
+                "DIR" => Some("work".to_string()),
+                _ => None,
+            },
+        )
+    }
+
+    #[test]
+    fn test_expand_tilde() {
+        assert_eq!("/home/me", expand_test("~"));
+        assert_eq!("/home/me/work/foo", expand_test("~/work/foo"));
+        assert_eq!("/nonexistent/foo", expand_test("~nobody/foo"));
+        assert_eq!("/home/rob", expand_test("~rob"));
+        assert_eq!("~stranger/foo", expand_test("~stranger/foo"));
+        assert_eq!("/tmp/~/foo", expand_test("/tmp/~/foo"));
+    }
+
+    #[test]
+    fn test_expand_vars() {
+        assert_eq!("/home/me/foo", expand_test("$HOME/foo"));
+        assert_eq!("/home/me/foo", expand_test("${HOME}/foo"));
+        assert_eq!("/home/me/work/foo", expand_test("$HOME/$DIR/foo"));
+        assert_eq!("/home/me/workshop", expand_test("$HOME/${DIR}shop"));
+        assert_eq!("/x/$UNSET/y", expand_test("/x/$UNSET/y"));
+        assert_eq!("/x/${UNSET}/y", expand_test("/x/${UNSET}/y"));
+        assert_eq!("/x/${HOME", expand_test("/x/${HOME"));
+        assert_eq!("/x/$/y", expand_test("/x/$/y"));
+        assert_eq!("$/home/me", expand_test("$$HOME"));
+        assert_eq!("cost$", expand_test("cost$"));
+        assert_eq!("/home/me/work", expand_test("~/$DIR"));
+    }
+
+    #[test]
+    fn test_expand() {
+        env::set_var("ZFS_TOOLS_TEST_EXPAND", "expanded");
+        assert_eq!(
+            PathBuf::from("/tmp/expanded/file"),
+            expand("/tmp/$ZFS_TOOLS_TEST_EXPAND/file")
+        );
+        assert_eq!(PathBuf::from("/plain/path"), expand("/plain/path"));
+    }
+
+    #[test]
+    fn test_home_in_passwd() {
+        assert_eq!(Some("/root".to_string()), home_in_passwd(PASSWD, "root"));
+        assert_eq!(None, home_in_passwd(PASSWD, "rob:x"));
+        assert_eq!(None, home_in_passwd(PASSWD, "missing"));
+    }
+}
@@ -0,0 +1,98 @@
+//! Pools, as opposed to the datasets in them.
+//!
+use crate::constants::ZPOOL;
+use crate::zfs_runner::{SystemRunner, ZfsRunner};
+use anyhow::anyhow;
+use std::process::Command;
+
+/// The health of a pool which has nothing wrong with it.
+///
+pub const HEALTHY: &str = "ONLINE";
+
+/// Returns the name of the pool which holds the given dataset or snapshot, which is the first
+/// segment of its name.
+///
+pub fn name_of(dataset: &str) -> &str {
+    dataset.split(['/', '@']).next().unwrap_or(dataset)
+}
+
+/// Returns a `zpool list` command which prints only the health of the given pool.
+///
+pub fn health_command(pool: &str) -> Command {
+    let mut cmd = Command::new(ZPOOL);
+    cmd.arg("list").arg("-Ho").arg("health").arg(pool);
+    cmd
+}
+
+/// Returns the health of the given pool, like `ONLINE` or `DEGRADED`.
+///
+pub fn health(pool: &str) -> anyhow::Result<String> {
+    health_with(&SystemRunner, pool)
+}
+
+/// Like `health()`, but runs zpool through the given runner.
+///
+pub fn health_with(runner: &dyn ZfsRunner, pool: &str) -> anyhow::Result<String> {
+    parse_health(&runner.lines(health_command(pool))?, pool)
+}
+
+fn parse_health(lines: &[String], pool: &str) -> anyhow::Result<String> {
+    let lines: Vec<&str> = lines
+        .iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    match lines.as_slice() {
+        [health] if !health.contains(char::is_whitespace) => Ok(health.to_string()),
+        _ => Err(anyhow!(
+            "could not understand health of pool {}: {:?}",
+            pool,
+            lines
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command_helpers::format_command;
+    use crate::zfs_runner::MockRunner;
+
+    #[test]
+    fn test_name_of() {
+        assert_eq!("rpool", name_of("rpool"));
+        assert_eq!("rpool", name_of("rpool/zones/serv-build/ROOT/zbe-3"));
+        assert_eq!("tank", name_of("tank/home@monday"));
+        assert_eq!("tank", name_of("tank@monday"));
+        assert_eq!("fast", name_of("fast/zone@03:00"));
+        assert_eq!("", name_of(""));
+    }
+
+    #[test]
+    fn test_health_command() {
+        assert_eq!(
+            format!("{} list -Ho health tank", ZPOOL),
+            format_command(&health_command("tank"))
+        );
+    }
+
+    #[test]
+    fn test_health_with() {
+        let runner = MockRunner::new(&["DEGRADED"]);
+        assert_eq!("DEGRADED", health_with(&runner, "tank").unwrap());
+        assert_eq!(
+            vec![format!("{} list -Ho health tank", ZPOOL)],
+            *runner.commands.borrow()
+        );
+
+        assert_eq!(
+            HEALTHY,
+            health_with(&MockRunner::new(&["ONLINE", ""]), "rpool").unwrap()
+        );
+        assert!(health_with(&MockRunner::new(&[]), "rpool").is_err());
+        assert!(health_with(&MockRunner::new(&["ONLINE", "ONLINE"]), "rpool").is_err());
+        assert!(health_with(&MockRunner::new(&["rpool ONLINE"]), "rpool").is_err());
+        assert!(health_with(&MockRunner::failing("no such pool 'gone'"), "gone").is_err());
+    }
+}
@@ -1,13 +1,105 @@
+use anyhow::anyhow;
+
+// A wildcard rule, with its shape worked out. A * anywhere but the ends is just a character.
+#[derive(Clone, Debug, PartialEq)]
+enum Rule {
+    Any,
+    Exact(String),
+    Prefix(String),
+    Suffix(String),
+    Contains(String),
+}
+
+impl Rule {
+    fn new(rule: &str) -> Self {
+        match rule {
+            "*" | "**" => Rule::Any,
+            r if r.len() > 1 && r.starts_with('*') && r.ends_with('*') => {
+                Rule::Contains(r[1..r.len() - 1].to_string())
+            }
+            r if r.starts_with('*') => Rule::Suffix(r[1..].to_string()),
+            r if r.ends_with('*') => Rule::Prefix(r[..r.len() - 1].to_string()),
+            r => Rule::Exact(r.to_string()),
+        }
+    }
+
+    fn matches(&self, item: &str) -> bool {
+        match self {
+            Rule::Any => true,
+            Rule::Exact(rule) => item == rule,
+            Rule::Prefix(rule) => item.starts_with(rule.as_str()),
+            Rule::Suffix(rule) => item.ends_with(rule.as_str()),
+            Rule::Contains(rule) => item.contains(rule.as_str()),
+        }
+    }
+}
+
+/// A list of wildcard rules, each worked out once, for matching against many items. Rules are as
+/// for `rule_matches()`.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompiledRules {
+    rules: Vec<Rule>,
+}
+
+impl CompiledRules {
+    pub fn new(rules: &[String]) -> Self {
+        CompiledRules {
+            rules: rules.iter().map(|rule| Rule::new(rule)).collect(),
+        }
+    }
+
+    /// True if any of the rules matches the item.
+    ///
+    pub fn matches(&self, item: &str) -> bool {
+        self.rules.iter().any(|rule| rule.matches(item))
+    }
+
+    /// The opposite of `matches()`, for use as a filter. The same as `omit_rules_match()`.
+    ///
+    pub fn keeps(&self, item: &str) -> bool {
+        !self.matches(item)
+    }
+}
+
+/// Checks if the given wildcard rule matches the given item. A rule may begin or end with a *,
+/// or both, to match on suffix, prefix, or substring.
+///
+pub fn rule_matches(item: &str, rule: &str) -> bool {
+    Rule::new(rule).matches(item)
+}
+
 /// Checks if any of the given wildcard rules matches any of the given items. Used as a filter,
-/// so it's a negative match.
+/// so it's a negative match. To check many items against the same rules, build a
+/// `CompiledRules` once instead.
 ///
 pub fn omit_rules_match(item: &str, rules: &[String]) -> bool {
-    !rules.iter().any(|rule| match rule.as_str() {
-        r if r.starts_with('*') && r.ends_with('*') => item.contains(&r[1..r.len() - 1]),
-        r if r.starts_with('*') => item.ends_with(&r[1..]),
-        r if r.ends_with('*') => item.starts_with(&r[..r.len() - 1]),
-        r => item == r,
-    })
+    CompiledRules::new(rules).keeps(item)
+}
+
+/// Checks that the given string can be used as the name part of a snapshot. ZFS only allows
+/// alphanumerics, underscore, hyphen, colon, and period.
+///
+pub fn valid_snapshot_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() {
+        return Err(anyhow!("snapshot name is empty"));
+    }
+
+    if name.len() > 255 {
+        return Err(anyhow!("snapshot name is longer than 255 characters"));
+    }
+
+    match name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || "_-:.".contains(*c)))
+    {
+        Some(c) => Err(anyhow!(
+            "snapshot name '{}' contains illegal character '{}'",
+            name,
+            c
+        )),
+        None => Ok(()),
+    }
 }
 
 #[cfg(test)]
@@ -57,6 +149,109 @@ mod tests {
         assert!(omit_rules_match("nothing_matches", &rules));
     }
 
+    #[test]
+    fn test_rule_matches() {
+        assert!(rule_matches("rpool/zones/web", "rpool/zones/*"));
+        assert!(!rule_matches("rpool/zone", "rpool/zones/*"));
+        assert!(rule_matches("anything", "*"));
+        assert!(rule_matches("", "*"));
+        assert!(rule_matches("whole", "whole"));
+    }
+
+    #[test]
+    fn test_valid_snapshot_name() {
+        assert!(valid_snapshot_name("monday").is_ok());
+        assert!(valid_snapshot_name("2024-10-27_09:45").is_ok());
+        assert!(valid_snapshot_name("pre.upgrade").is_ok());
+        assert!(valid_snapshot_name("").is_err());
+        assert!(valid_snapshot_name("rpool/test").is_err());
+        assert!(valid_snapshot_name("two words").is_err());
+        assert!(valid_snapshot_name("tab\there").is_err());
+        assert!(valid_snapshot_name("at@sign").is_err());
+        assert!(valid_snapshot_name(&"a".repeat(256)).is_err());
+    }
+
+    // The original rule_matches(), which CompiledRules must agree with.
+    fn reference_match(item: &str, rule: &str) -> bool {
+        match rule {
+            r if r.len() > 1 && r.starts_with('*') && r.ends_with('*') => {
+                item.contains(&r[1..r.len() - 1])
+            }
+            r if r.starts_with('*') => item.ends_with(&r[1..]),
+            r if r.ends_with('*') => item.starts_with(&r[..r.len() - 1]),
+            r => item == r,
+        }
+    }
+
+    #[test]
+    fn test_compiled_rules_match_reference() {
+        let rules: Vec<String> = [
+            "",
+            "*",
+            "**",
+            "***",
+            "whole",
+            "start*",
+            "*end",
+            "*contains*",
+            "mid*dle",
+            "*a*b*",
+            "rpool/zones/*",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let items = [
+            "",
+            "*",
+            "whole",
+            "wholesale",
+            "start_here",
+            "the_end",
+            "it_contains_this",
+            "mid*dle",
+            "middle",
+            "xa*by",
+            "rpool/zones/web",
+            "rpool/zone",
+        ];
+
+        for rule in &rules {
+            let compiled = CompiledRules::new(std::slice::from_ref(rule));
+
+            for item in items {
+                assert_eq!(
+                    reference_match(item, rule),
+                    compiled.matches(item),
+                    "rule '{}', item '{}'",
+                    rule,
+                    item
+                );
+            }
+        }
+
+        let compiled = CompiledRules::new(&rules[4..]);
+
+        for item in items {
+            assert_eq!(
+                !rules[4..].iter().any(|rule| reference_match(item, rule)),
+                compiled.keeps(item)
+            );
+            assert_eq!(compiled.keeps(item), omit_rules_match(item, &rules[4..]));
+        }
+    }
+
+    #[test]
+    fn test_compiled_rules() {
+        let rules = CompiledRules::new(&["start*".to_string(), "*end".to_string()]);
+        assert!(rules.matches("start_here"));
+        assert!(rules.matches("the_end"));
+        assert!(!rules.matches("middle"));
+        assert!(rules.keeps("middle"));
+        assert!(!CompiledRules::new(&[]).matches("anything"));
+    }
+
     #[test]
     fn test_empty_rules() {
         let rules: Vec<String> = vec![];
@@ -0,0 +1,202 @@
+//! A structured view of snapshot names, and functions for ordering them.
+//!
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use time::OffsetDateTime;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Snapshot {
+    pub dataset: String,
+    pub name: String,
+}
+
+impl Snapshot {
+    /// Splits a full snapshot name like `rpool/test@monday`. Returns None if it has no `@`.
+    ///
+    pub fn parse(full_name: &str) -> Option<Snapshot> {
+        full_name.split_once('@').map(|(dataset, name)| Snapshot {
+            dataset: dataset.to_string(),
+            name: name.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.dataset, self.name)
+    }
+}
+
+/// Returns the dataset part of a full snapshot name, so `rpool/test` from `rpool/test@monday`.
+/// Returns None if it has no `@`.
+///
+pub fn dataset_of_snapshot(full_name: &str) -> Option<String> {
+    Snapshot::parse(full_name).map(|snapshot| snapshot.dataset)
+}
+
+/// The error from `assert_is_snapshot()`. It holds the offending name.
+///
+#[derive(Debug, PartialEq)]
+pub struct NotASnapshot(pub String);
+
+impl fmt::Display for NotASnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "refusing to destroy {}: it is not a snapshot", self.0)
+    }
+}
+
+impl Error for NotASnapshot {}
+
+/// Anything which is about to be destroyed must pass through here first. A snapshot name has
+/// exactly one `@`, with something on both sides of it. Anything else could be a dataset, and
+/// destroying it would be a disaster.
+///
+pub fn assert_is_snapshot(name: &str) -> Result<(), NotASnapshot> {
+    match name.split_once('@') {
+        Some((dataset, snapname))
+            if !dataset.is_empty() && !snapname.is_empty() && !snapname.contains('@') =>
+        {
+            Ok(())
+        }
+        _ => Err(NotASnapshot(name.to_string())),
+    }
+}
+
+/// Sorts snapshots by dataset name, then newest-first within each dataset. Creation times are
+/// keyed by full snapshot name. Snapshots with no known creation time go to the end of their
+/// dataset's group, and otherwise keep their order.
+///
+pub fn sort_grouped(
+    mut snaps: Vec<Snapshot>,
+    creations: &HashMap<String, OffsetDateTime>,
+) -> Vec<Snapshot> {
+    snaps.sort_by(|a, b| {
+        a.dataset.cmp(&b.dataset).then_with(|| {
+            let a_time = creations.get(&a.to_string());
+            let b_time = creations.get(&b.to_string());
+
+            match (a_time, b_time) {
+                (Some(a_time), Some(b_time)) => Reverse(a_time).cmp(&Reverse(b_time)),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            }
+        })
+    });
+
+    snaps
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snaps(names: &[&str]) -> Vec<Snapshot> {
+        names.iter().filter_map(|n| Snapshot::parse(n)).collect()
+    }
+
+    fn at(timestamp: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Some(Snapshot {
+                dataset: "rpool/test".to_string(),
+                name: "monday".to_string(),
+            }),
+            Snapshot::parse("rpool/test@monday")
+        );
+
+        assert_eq!(None, Snapshot::parse("rpool/test"));
+        assert_eq!(
+            "rpool/test@12:00",
+            Snapshot::parse("rpool/test@12:00").unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn test_dataset_of_snapshot() {
+        assert_eq!(
+            Some("rpool/test".to_string()),
+            dataset_of_snapshot("rpool/test@monday")
+        );
+        assert_eq!(
+            Some("rpool".to_string()),
+            dataset_of_snapshot("rpool@12:00")
+        );
+        assert_eq!(None, dataset_of_snapshot("rpool/test"));
+    }
+
+    #[test]
+    fn test_assert_is_snapshot() {
+        assert!(assert_is_snapshot("rpool/test@monday").is_ok());
+        assert!(assert_is_snapshot("rpool@12:00").is_ok());
+
+        for name in ["pool/ds", "@foo", "pool@", "a@b@c", "@", ""] {
+            assert_eq!(
+                Err(NotASnapshot(name.to_string())),
+                assert_is_snapshot(name)
+            );
+        }
+
+        assert_eq!(
+            "refusing to destroy pool/ds: it is not a snapshot",
+            assert_is_snapshot("pool/ds").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_sort_grouped() {
+        let input = snaps(&[
+            "tank/data@a",
+            "rpool/test@old",
+            "tank/data@b",
+            "rpool@monday",
+            "rpool/test@new",
+            "tank/data@unknown",
+            "rpool/test@middle",
+            "tank/data@c",
+        ]);
+
+        let creations: HashMap<String, OffsetDateTime> = [
+            ("tank/data@a", 300),
+            ("rpool/test@old", 100),
+            ("tank/data@b", 100),
+            ("rpool@monday", 150),
+            ("rpool/test@new", 500),
+            ("rpool/test@middle", 200),
+            ("tank/data@c", 200),
+        ]
+        .iter()
+        .map(|(name, ts)| (name.to_string(), at(*ts)))
+        .collect();
+
+        assert_eq!(
+            snaps(&[
+                "rpool@monday",
+                "rpool/test@new",
+                "rpool/test@middle",
+                "rpool/test@old",
+                "tank/data@a",
+                "tank/data@c",
+                "tank/data@b",
+                "tank/data@unknown",
+            ]),
+            sort_grouped(input, &creations)
+        );
+    }
+
+    #[test]
+    fn test_sort_grouped_is_stable_without_creations() {
+        let input = snaps(&["tank@z", "rpool@b", "tank@a", "rpool@a"]);
+
+        assert_eq!(
+            snaps(&["rpool@b", "rpool@a", "tank@z", "tank@a"]),
+            sort_grouped(input, &HashMap::new())
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! Formats times for people to read, in local time. Anything in the suite which prints a time
+//! should use this, so they all agree on what local time is, and what happens when it can't be
+//! found.
+//!
+use time::{format_description, OffsetDateTime, UtcOffset};
+
+/// Dates and times as zr shows them, like `2024-10-27 09:45:00 +0100`.
+///
+pub const LONG_FORMAT: &str =
+    "[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]";
+
+/// Like RFC 2822, as used in mail headers: `Sun, 27 Oct 2024 09:45:00 +0100`.
+///
+pub const RFC2822_FORMAT: &str = "[weekday repr:short], [day] [month repr:short] [year] \
+                                  [hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]";
+
+/// The local offset from UTC. If that can't be found, which is often the case once a program has
+/// started threads, UTC is used.
+///
+pub fn local_offset() -> UtcOffset {
+    UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC)
+}
+
+/// Formats the Unix timestamp in local time, with a `time` format description. If the timestamp
+/// can't be formatted, you get "Invalid date".
+///
+pub fn format_local(timestamp: i64, fmt: &str) -> String {
+    format_in(timestamp, fmt, local_offset())
+}
+
+/// Like `format_local()`, but at the given offset.
+///
+pub fn format_in(timestamp: i64, fmt: &str, offset: UtcOffset) -> String {
+    let Ok(datetime) = OffsetDateTime::from_unix_timestamp(timestamp) else {
+        return String::from("Invalid date");
+    };
+
+    format_description::parse(fmt)
+        .ok()
+        .and_then(|format| datetime.to_offset(offset).format(&format).ok())
+        .unwrap_or_else(|| String::from("Invalid date"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_in() {
+        let utc = UtcOffset::UTC;
+        let bst = UtcOffset::from_hms(1, 0, 0).unwrap();
+        let est = UtcOffset::from_hms(-5, 0, 0).unwrap();
+
+        assert_eq!(
+            "2024-10-27 09:45:00 +0000",
+            format_in(1730022300, LONG_FORMAT, utc)
+        );
+        assert_eq!(
+            "2024-10-27 10:45:00 +0100",
+            format_in(1730022300, LONG_FORMAT, bst)
+        );
+        assert_eq!(
+            "Sun, 27 Oct 2024 04:45:00 -0500",
+            format_in(1730022300, RFC2822_FORMAT, est)
+        );
+        assert_eq!("Invalid date", format_in(i64::MAX, LONG_FORMAT, utc));
+        assert_eq!("Invalid date", format_in(0, "[nonsense]", utc));
+    }
+}
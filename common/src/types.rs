@@ -1,19 +1,71 @@
+use crate::constants::COPY_BUFFER_SIZE;
 use std::path::PathBuf;
 
 pub type ArgList = Vec<String>;
 pub type SnapshotList = Vec<String>;
 pub type SnapshotResult = anyhow::Result<SnapshotList>;
-pub type MountList = Vec<(PathBuf, String)>;
+pub type MountList = Vec<Mount>;
 pub type Filesystems = Vec<String>;
-pub type ZfsMounts = Vec<(PathBuf, String)>;
+
+/// A mounted ZFS filesystem: where it is mounted, and the name of the dataset.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mount {
+    pub mountpoint: PathBuf,
+    pub dataset: String,
+}
+
+impl Mount {
+    pub fn new(mountpoint: impl Into<PathBuf>, dataset: &str) -> Self {
+        Self {
+            mountpoint: mountpoint.into(),
+            dataset: dataset.to_string(),
+        }
+    }
+}
 
 pub struct Opts {
-    pub verbose: bool,
+    pub verbose: u8,
     pub noop: bool,
 }
 
+/// Space accounting for a dataset, in bytes, as `zfs list -o space` shows it. Anything which
+/// doesn't apply, like the children of a snapshot, is zero.
+#[derive(Debug, Default, PartialEq)]
+pub struct Usage {
+    pub used: u64,
+    pub referenced: u64,
+    pub available: u64,
+    pub usedbydataset: u64,
+    pub usedbysnapshots: u64,
+    pub usedbychildren: u64,
+}
+
 pub struct ZpZrOpts {
-    pub verbose: bool,
+    pub verbose: u8,
     pub noop: bool,
     pub noclobber: bool,
+    /// Files bigger than `STREAM_THRESHOLD` are copied through a buffer of this many bytes
+    pub buffer_size: usize,
+    /// If set, existing files are moved under here, rather than overwritten
+    pub trash_dir: Option<PathBuf>,
+    /// Give copied files the owner and group of the original. Only root can do this
+    pub preserve_owner: bool,
+    /// Give copied files the extended attributes of the original, where the platform has them
+    pub preserve_xattr: bool,
+}
+
+// A quiet, clobbering copy, through the usual buffer.
+impl Default for ZpZrOpts {
+    fn default() -> Self {
+        ZpZrOpts {
+            verbose: 0,
+            noop: false,
+            noclobber: false,
+            buffer_size: COPY_BUFFER_SIZE,
+            trash_dir: None,
+            preserve_owner: false,
+            preserve_xattr: false,
+        }
+    }
 }
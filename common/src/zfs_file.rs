@@ -1,38 +1,268 @@
-//! Functions, constants, types, and whatever else comes along, which are required by
-//! more than one of the tools in this crate.
+//! Functions which relate files and directories to the ZFS datasets and snapshots which hold
+//! them.
 //!
-use crate::types::{Filesystems, MountList, ZfsMounts};
+use crate::constants::{ZFS_CTLDIR, ZFS_CTLDIR_ENV, ZFS_CTLDIR_INO};
+use crate::types::{Filesystems, MountList};
 use crate::zfs_info::dataset_root;
-use std::collections::HashSet;
+use anyhow::anyhow;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-/// Given a path and a list of ZFS mounts, works out which, if any, filesystem owns the path.
+/// Given a path and a list of ZFS mounts, works out which, if any, filesystem owns the path. That
+/// is the one with the deepest mountpoint above it, so the mounts can be in any order.
 ///
 pub fn file_to_dataset(file: &Path, mounts: &MountList) -> Option<String> {
-    file.ancestors().find_map(|f| {
-        mounts.iter().find_map(|(mountpoint, name)| {
-            if f.starts_with(mountpoint) {
-                Some(name.clone())
-            } else {
-                None
-            }
-        })
-    })
+    mounts
+        .iter()
+        .filter(|mount| file.starts_with(&mount.mountpoint))
+        .max_by_key(|mount| mount.mountpoint.components().count())
+        .map(|mount| mount.dataset.clone())
 }
 
-pub fn files_to_datasets(file_list: &[String], zfs_mounts: ZfsMounts) -> Filesystems {
-    let filesystems: HashSet<String> = file_list
-        .iter()
-        .filter_map(|f| file_to_dataset(&PathBuf::from(f), &zfs_mounts))
+/// Given a list of files and a list of ZFS mounts, returns the unique datasets which hold them.
+///
+pub fn files_to_datasets(file_list: &[String], zfs_mounts: MountList) -> Filesystems {
+    let filesystems: HashSet<String> = files_to_datasets_detailed(file_list, zfs_mounts)
+        .into_iter()
+        .filter_map(|(_file, dataset)| dataset)
         .collect();
 
     filesystems.into_iter().collect()
 }
 
+/// Pairs each of the given files, in order, with the dataset which holds it, or None if no ZFS
+/// filesystem does.
+///
+pub fn files_to_datasets_detailed(
+    file_list: &[String],
+    zfs_mounts: MountList,
+) -> Vec<(String, Option<String>)> {
+    file_list
+        .iter()
+        .map(|f| (f.to_string(), file_to_dataset(Path::new(f), &zfs_mounts)))
+        .collect()
+}
+
+/// Returns the path of a file relative to the given filesystem root, or None if the file is not
+/// under that root.
+///
+pub fn path_relative_to_root(file: &Path, filesystem_root: &Path) -> Option<PathBuf> {
+    file.strip_prefix(filesystem_root).ok().map(PathBuf::from)
+}
+
+/// Splits an existing file's canonical path into the root of the dataset which holds it, and its
+/// path relative to that root. The relative part is empty if the file is the dataset root.
+///
+pub fn split_at_dataset_root(file: &Path) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let file = file.canonicalize()?;
+    let root = dataset_root(&file)?;
+
+    match path_relative_to_root(&file, &root) {
+        Some(relative) => Ok((root, relative)),
+        None => Err(anyhow!(
+            "{} is not under {}",
+            file.display(),
+            root.display()
+        )),
+    }
+}
+
+/// Returns the name of the directory which holds snapshot/. This is `.zfs` unless it has been
+/// overridden with the `ZFS_TOOLS_CTLDIR` environment variable.
+///
+pub fn control_dir_name() -> String {
+    control_dir_name_from(env::var(ZFS_CTLDIR_ENV).ok())
+}
+
+// Anything which isn't a plain directory name is ignored.
+fn control_dir_name_from(value: Option<String>) -> String {
+    match value {
+        Some(name) if !name.is_empty() && !name.contains('/') && name != "." && name != ".." => {
+            name
+        }
+        _ => ZFS_CTLDIR.to_string(),
+    }
+}
+
+/// Checks that the given path is a real ZFS .zfs control directory, and not just an ordinary
+/// directory with that name. See `is_control_dir()`.
+///
+pub fn is_zfs_control_dir(dir: &Path) -> bool {
+    is_control_dir(dir, &control_dir_name())
+}
+
+/// Checks the given path is a control directory with the given name. The real `.zfs` is
+/// recognised by its inode number. An alternative control directory is not a ZFS one, so there's
+/// nothing to check but its name, and that it is a directory.
+///
+pub fn is_control_dir(dir: &Path, ctldir: &str) -> bool {
+    if dir.file_name() != Some(OsStr::new(ctldir)) {
+        return false;
+    }
+
+    fs::symlink_metadata(dir)
+        .map(|m| m.is_dir() && (ctldir != ZFS_CTLDIR || m.ino() == ZFS_CTLDIR_INO))
+        .unwrap_or(false)
+}
+
+/// Returns the directories under the given dataset root's `.zfs/snapshot`, newest first, or None
+/// if there is no `.zfs/snapshot`. This only looks at the directory listing, so doesn't need to
+/// run zfs.
+///
+pub fn snapshot_dirs(dataset_root: &Path) -> Option<Vec<PathBuf>> {
+    snapshot_dirs_in(dataset_root, &control_dir_name())
+}
+
+/// Like `snapshot_dirs()`, but with the given control directory name rather than `.zfs`.
+///
+pub fn snapshot_dirs_in(dataset_root: &Path, ctldir: &str) -> Option<Vec<PathBuf>> {
+    snapshot_dirs_at(&dataset_root.join(ctldir).join("snapshot"))
+}
+
+/// Like `snapshot_dirs()`, but lists the given directory, which holds one directory per snapshot.
+///
+pub fn snapshot_dirs_at(snapshot_root: &Path) -> Option<Vec<PathBuf>> {
+    if !snapshot_root.exists() {
+        return None;
+    }
+
+    let dirs: Option<Vec<PathBuf>> = fs::read_dir(snapshot_root)
+        .ok()?
+        .map(|entry| entry.ok().map(|f| f.path()))
+        .collect();
+
+    dirs.map(newest_first)
+}
+
+/// True if the given snapshot directory, like `/build/.zfs/snapshot/monday`, can be opened. ZFS
+/// mounts a snapshot when its directory is opened, so calling this before looking for files in a
+/// snapshot stops a stale handle making them seem to be missing.
+///
+pub fn is_snapshot_mounted(snapshot_dir: &Path) -> bool {
+    snapshot_dir.is_dir() && fs::read_dir(snapshot_dir).is_ok()
+}
+
+/// Returns the names of the snapshots in the given dataset root's `.zfs/snapshot`, sorted. Unlike
+/// `snapshot_dirs()`, a missing or unreadable snapshot directory is an error.
+///
+pub fn snapshot_names(dataset_root: &Path) -> anyhow::Result<Vec<String>> {
+    snapshot_names_in(dataset_root, &control_dir_name())
+}
+
+/// Like `snapshot_names()`, but with the given control directory name rather than `.zfs`.
+///
+pub fn snapshot_names_in(dataset_root: &Path, ctldir: &str) -> anyhow::Result<Vec<String>> {
+    let snapshot_root = dataset_root.join(ctldir).join("snapshot");
+
+    let mut ret = fs::read_dir(&snapshot_root)
+        .map_err(|e| anyhow!("cannot read {}: {}", snapshot_root.display(), e))?
+        .map(|entry| entry.map(|f| f.file_name().to_string_lossy().to_string()))
+        .collect::<Result<Vec<String>, _>>()?;
+
+    ret.sort();
+    Ok(ret)
+}
+
+/// Checks the requested snapshot is one of those available. If it isn't, the error lists the ones
+/// which are, so the user can pick one.
+///
+pub fn check_snapshot_name(name: &str, available: &[String]) -> anyhow::Result<()> {
+    if available.iter().any(|snapname| snapname == name) {
+        Ok(())
+    } else if available.is_empty() {
+        Err(anyhow!(
+            "no snapshot called '{}': there are no snapshots",
+            name
+        ))
+    } else {
+        Err(anyhow!(
+            "no snapshot called '{}'. Choose from: {}",
+            name,
+            available.join(", ")
+        ))
+    }
+}
+
+/// Sorts directories by their own mtimes, newest first. Those whose mtime can't be read go last.
+/// Directories with the same mtime are sorted by name, in reverse.
+///
+pub fn newest_first(dirs: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut dirs: Vec<(PathBuf, Option<SystemTime>)> = dirs
+        .into_iter()
+        .map(|dir| {
+            let mtime = fs::metadata(&dir).and_then(|m| m.modified()).ok();
+            (dir, mtime)
+        })
+        .collect();
+
+    dirs.sort_by(|(a_dir, a_time), (b_dir, b_time)| {
+        b_time.cmp(a_time).then_with(|| b_dir.cmp(a_dir))
+    });
+
+    dirs.into_iter().map(|(dir, _)| dir).collect()
+}
+
+/// Says whether the given path looks like it is inside a ZFS snapshot: that is, it has a
+/// `.zfs` component followed by `snapshot`. This only looks at the path, so it can be fooled by
+/// ordinary directories with those names. Use `is_zfs_control_dir()` to be sure.
+///
+pub fn is_in_snapshot(path: &Path) -> bool {
+    is_in_snapshot_in(path, &control_dir_name())
+}
+
+/// Like `is_in_snapshot()`, but with the given control directory name rather than `.zfs`.
+///
+pub fn is_in_snapshot_in(path: &Path, ctldir: &str) -> bool {
+    snapshot_components(path, ctldir).is_some()
+}
+
+/// Returns the name of the snapshot the given path is in, e.g. `monday` for
+/// `/build/.zfs/snapshot/monday/file`. None if the path is not in a snapshot, or stops at
+/// `.zfs/snapshot`.
+///
+pub fn snapshot_name_of(path: &Path) -> Option<String> {
+    snapshot_name_of_in(path, &control_dir_name())
+}
+
+/// Like `snapshot_name_of()`, but with the given control directory name rather than `.zfs`.
+///
+pub fn snapshot_name_of_in(path: &Path, ctldir: &str) -> Option<String> {
+    snapshot_components(path, ctldir)?
+        .next()
+        .map(|name| name.to_string_lossy().to_string())
+}
+
+// Everything after the first ctldir/snapshot in the path.
+fn snapshot_components<'a>(
+    path: &'a Path,
+    ctldir: &str,
+) -> Option<impl Iterator<Item = &'a OsStr>> {
+    let mut components = path.components().map(|c| c.as_os_str());
+    components.find(|&c| c == OsStr::new(ctldir))?;
+
+    if components.next() == Some(OsStr::new("snapshot")) {
+        Some(components)
+    } else {
+        None
+    }
+}
+
 pub fn snapshot_dir_from_file(file: &Path) -> Option<PathBuf> {
+    snapshot_dir_from_file_in(file, &control_dir_name())
+}
+
+/// Like `snapshot_dir_from_file()`, but with the given control directory name rather than
+/// `.zfs`.
+///
+pub fn snapshot_dir_from_file_in(file: &Path, ctldir: &str) -> Option<PathBuf> {
     match dataset_root(file) {
         Ok(dir) => {
-            let snapdir = dir.join(".zfs").join("snapshot");
+            let snapdir = dir.join(ctldir).join("snapshot");
             if snapdir.exists() {
                 Some(snapdir)
             } else {
@@ -43,9 +273,341 @@ pub fn snapshot_dir_from_file(file: &Path) -> Option<PathBuf> {
     }
 }
 
+/// A difference between two snapshots of a dataset, found by `diff_snapshots()`. Paths are
+/// relative to the root of the dataset.
+///
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    Added(PathBuf),
+    Removed(PathBuf),
+    Modified(PathBuf),
+}
+
+/// Compares two snapshots of the dataset mounted at root, through its `.zfs/snapshot` directory,
+/// so it needs no privileges. Files are modified if their size or mtime has changed: contents
+/// aren't read. The changes are sorted by path.
+///
+pub fn diff_snapshots(
+    dataset_root: &Path,
+    snap_a: &str,
+    snap_b: &str,
+) -> anyhow::Result<Vec<ChangeKind>> {
+    diff_snapshots_in(dataset_root, snap_a, snap_b, &control_dir_name())
+}
+
+/// Like `diff_snapshots()`, but with the given control directory name rather than `.zfs`.
+///
+pub fn diff_snapshots_in(
+    dataset_root: &Path,
+    snap_a: &str,
+    snap_b: &str,
+    ctldir: &str,
+) -> anyhow::Result<Vec<ChangeKind>> {
+    let snapshot_root = dataset_root.join(ctldir).join("snapshot");
+    let before = file_details(&snapshot_root.join(snap_a))?;
+    let after = file_details(&snapshot_root.join(snap_b))?;
+
+    let mut ret: Vec<ChangeKind> = before
+        .iter()
+        .filter_map(|(path, details)| match after.get(path) {
+            None => Some(ChangeKind::Removed(path.clone())),
+            Some(other) if other != details => Some(ChangeKind::Modified(path.clone())),
+            Some(_) => None,
+        })
+        .chain(
+            after
+                .keys()
+                .filter(|path| !before.contains_key(*path))
+                .map(|path| ChangeKind::Added(path.clone())),
+        )
+        .collect();
+
+    ret.sort_by(|a, b| change_path(a).cmp(change_path(b)));
+    Ok(ret)
+}
+
+fn change_path(change: &ChangeKind) -> &Path {
+    match change {
+        ChangeKind::Added(path) | ChangeKind::Removed(path) | ChangeKind::Modified(path) => path,
+    }
+}
+
+// Maps every non-directory under the snapshot directory, relative to it, to its size and mtime.
+// Symbolic links are not followed.
+fn file_details(snapshot_dir: &Path) -> anyhow::Result<BTreeMap<PathBuf, (u64, i64, i64)>> {
+    if !snapshot_dir.is_dir() {
+        return Err(anyhow!("{} does not exist", snapshot_dir.display()));
+    }
+
+    let mut ret = BTreeMap::new();
+    let mut to_visit = vec![snapshot_dir.to_path_buf()];
+
+    while let Some(dir) = to_visit.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let metadata = fs::symlink_metadata(&path)?;
+
+            if metadata.is_dir() {
+                to_visit.push(path);
+            } else if let Ok(relative) = path.strip_prefix(snapshot_dir) {
+                ret.insert(
+                    relative.to_path_buf(),
+                    (metadata.size(), metadata.mtime(), metadata.mtime_nsec()),
+                );
+            }
+        }
+    }
+
+    Ok(ret)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::spec_helper::fixture;
+    use crate::types::Mount;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_snapshot_names() {
+        assert_eq!(
+            vec!["monday".to_string(), "tuesday".to_string()],
+            snapshot_names_in(&fixture("root"), ".zfs").unwrap()
+        );
+        assert!(snapshot_names_in(&fixture("root/child"), ".zfs").is_err());
+    }
+
+    #[test]
+    fn test_is_snapshot_mounted() {
+        let snapshot_root = fixture("root/.zfs/snapshot");
+
+        assert!(is_snapshot_mounted(&snapshot_root.join("monday")));
+        // Touching a snapshot which is already there is harmless.
+        assert!(is_snapshot_mounted(&snapshot_root.join("monday")));
+        assert!(!is_snapshot_mounted(&snapshot_root.join("sunday")));
+        assert!(!is_snapshot_mounted(&fixture("root/child/monday")));
+
+        let tmp = tempdir().unwrap();
+        fs::write(tmp.path().join("monday"), "not a snapshot").unwrap();
+        assert!(!is_snapshot_mounted(&tmp.path().join("monday")));
+    }
+
+    #[test]
+    fn test_check_snapshot_name() {
+        let available = vec!["monday".to_string(), "tuesday".to_string()];
+
+        assert!(check_snapshot_name("monday", &available).is_ok());
+        assert_eq!(
+            "no snapshot called 'friday'. Choose from: monday, tuesday",
+            check_snapshot_name("friday", &available)
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "no snapshot called 'friday': there are no snapshots",
+            check_snapshot_name("friday", &[]).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_diff_snapshots() {
+        let tmp = tempdir().unwrap();
+        let snapshot_root = tmp.path().join(".zfs").join("snapshot");
+        let monday = snapshot_root.join("monday");
+        let tuesday = snapshot_root.join("tuesday");
+        let then = SystemTime::now() - Duration::from_secs(3600);
+
+        for snap in [&monday, &tuesday] {
+            fs::create_dir_all(snap.join("dir")).unwrap();
+
+            for (file, content) in [("same", "unchanged"), ("dir/touched", "unchanged")] {
+                let path = snap.join(file);
+                fs::write(&path, content).unwrap();
+                fs::File::options()
+                    .write(true)
+                    .open(&path)
+                    .unwrap()
+                    .set_modified(then)
+                    .unwrap();
+            }
+        }
+
+        fs::write(monday.join("removed"), "gone").unwrap();
+        fs::write(monday.join("grown"), "short").unwrap();
+        fs::write(tuesday.join("grown"), "much longer").unwrap();
+        fs::write(tuesday.join("dir/added"), "new").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(tuesday.join("dir/touched"))
+            .unwrap()
+            .set_modified(SystemTime::now())
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                ChangeKind::Added(PathBuf::from("dir/added")),
+                ChangeKind::Modified(PathBuf::from("dir/touched")),
+                ChangeKind::Modified(PathBuf::from("grown")),
+                ChangeKind::Removed(PathBuf::from("removed")),
+            ],
+            diff_snapshots_in(tmp.path(), "monday", "tuesday", ".zfs").unwrap()
+        );
+
+        assert_eq!(
+            vec![
+                ChangeKind::Removed(PathBuf::from("dir/added")),
+                ChangeKind::Modified(PathBuf::from("dir/touched")),
+                ChangeKind::Modified(PathBuf::from("grown")),
+                ChangeKind::Added(PathBuf::from("removed")),
+            ],
+            diff_snapshots_in(tmp.path(), "tuesday", "monday", ".zfs").unwrap()
+        );
+
+        assert!(diff_snapshots_in(tmp.path(), "monday", "monday", ".zfs")
+            .unwrap()
+            .is_empty());
+        assert!(diff_snapshots_in(tmp.path(), "monday", "friday", ".zfs").is_err());
+    }
+
+    #[test]
+    fn test_snapshot_dirs() {
+        let tmp = tempdir().unwrap();
+        let snapshot_root = tmp.path().join(".zfs").join("snapshot");
+        let now = SystemTime::now();
+
+        for (name, age) in [("monday", 300), ("wednesday", 0), ("tuesday", 100)] {
+            let dir = snapshot_root.join(name);
+            fs::create_dir_all(&dir).unwrap();
+            fs::File::open(&dir)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(age))
+                .unwrap();
+        }
+
+        assert_eq!(
+            vec![
+                snapshot_root.join("wednesday"),
+                snapshot_root.join("tuesday"),
+                snapshot_root.join("monday"),
+            ],
+            snapshot_dirs(tmp.path()).unwrap()
+        );
+
+        assert_eq!(None, snapshot_dirs(&snapshot_root));
+    }
+
+    #[test]
+    fn test_snapshot_dirs_in() {
+        let tmp = tempdir().unwrap();
+        let snapshot_root = tmp.path().join(".snapshots").join("snapshot");
+        fs::create_dir_all(snapshot_root.join("monday")).unwrap();
+
+        assert_eq!(
+            vec![snapshot_root.join("monday")],
+            snapshot_dirs_in(tmp.path(), ".snapshots").unwrap()
+        );
+
+        assert_eq!(None, snapshot_dirs_in(tmp.path(), ".zfs"));
+    }
+
+    #[test]
+    fn test_control_dir_name_from() {
+        assert_eq!(".zfs", control_dir_name_from(None));
+        assert_eq!(".zfs", control_dir_name_from(Some(String::new())));
+        assert_eq!(".zfs", control_dir_name_from(Some("a/b".to_string())));
+        assert_eq!(".zfs", control_dir_name_from(Some("..".to_string())));
+        assert_eq!(
+            ".snapshots",
+            control_dir_name_from(Some(".snapshots".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_is_control_dir() {
+        let tmp = tempdir().unwrap();
+        let custom = tmp.path().join(".snapshots");
+        fs::create_dir(&custom).unwrap();
+        fs::write(tmp.path().join("file"), "data").unwrap();
+
+        assert!(is_control_dir(&custom, ".snapshots"));
+        assert!(!is_control_dir(&custom, ".zfs"));
+        assert!(!is_control_dir(&tmp.path().join("file"), "file"));
+        assert!(!is_control_dir(&tmp.path().join(".zfs"), ".zfs"));
+    }
+
+    #[test]
+    fn test_is_in_snapshot_in() {
+        assert!(is_in_snapshot_in(
+            Path::new("/test/.zfs/snapshot/monday/d"),
+            ".zfs"
+        ));
+        assert!(is_in_snapshot_in(Path::new("/test/.zfs/snapshot"), ".zfs"));
+        assert!(!is_in_snapshot_in(Path::new("/build/dir"), ".zfs"));
+        assert!(!is_in_snapshot_in(Path::new("/test/snapshot/dir"), ".zfs"));
+        assert!(!is_in_snapshot_in(Path::new("/test/.zfs"), ".zfs"));
+        assert!(!is_in_snapshot_in(Path::new("/test/.zfs/shares/x"), ".zfs"));
+        assert!(!is_in_snapshot_in(
+            Path::new("/test/.zfs/x/snapshot"),
+            ".zfs"
+        ));
+        assert!(is_in_snapshot_in(
+            Path::new("/test/.snapshots/snapshot/monday"),
+            ".snapshots"
+        ));
+        assert!(!is_in_snapshot_in(
+            Path::new("/test/.snapshots/snapshot/monday"),
+            ".zfs"
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_name_of_in() {
+        assert_eq!(
+            Some("monday".to_string()),
+            snapshot_name_of_in(Path::new("/test/.zfs/snapshot/monday/d/f"), ".zfs")
+        );
+        assert_eq!(
+            Some("03:00".to_string()),
+            snapshot_name_of_in(Path::new("/test/.zfs/snapshot/03:00"), ".zfs")
+        );
+        assert_eq!(
+            None,
+            snapshot_name_of_in(Path::new("/test/.zfs/snapshot"), ".zfs")
+        );
+        assert_eq!(None, snapshot_name_of_in(Path::new("/test/.zfs"), ".zfs"));
+        assert_eq!(
+            None,
+            snapshot_name_of_in(Path::new("/test/.zfs/shares/monday"), ".zfs")
+        );
+        assert_eq!(None, snapshot_name_of_in(Path::new("/build/dir"), ".zfs"));
+    }
+
+    #[test]
+    fn test_newest_first() {
+        let tmp = tempdir().unwrap();
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(1730563919);
+        let mut dirs = Vec::new();
+
+        for name in ["a", "c", "b"] {
+            let dir = tmp.path().join(name);
+            fs::create_dir(&dir).unwrap();
+            fs::File::open(&dir).unwrap().set_modified(then).unwrap();
+            dirs.push(dir);
+        }
+
+        dirs.push(tmp.path().join("missing"));
+
+        assert_eq!(
+            vec![
+                tmp.path().join("c"),
+                tmp.path().join("b"),
+                tmp.path().join("a"),
+                tmp.path().join("missing"),
+            ],
+            newest_first(dirs)
+        );
+    }
 
     // You'll have to trust that these tests pass on my illumos box. They're skipped in Github
     // Actions.
@@ -65,20 +627,76 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_path_relative_to_root() {
+        assert_eq!(
+            PathBuf::from("d/e/f"),
+            path_relative_to_root(&PathBuf::from("/a/b/c/d/e/f"), &PathBuf::from("/a/b/c"))
+                .unwrap()
+        );
+
+        assert_eq!(
+            PathBuf::from("x/data/y"),
+            path_relative_to_root(&PathBuf::from("/data/x/data/y"), &PathBuf::from("/data"))
+                .unwrap()
+        );
+
+        assert_eq!(
+            PathBuf::from(""),
+            path_relative_to_root(&PathBuf::from("/data"), &PathBuf::from("/data")).unwrap()
+        );
+
+        assert_eq!(
+            None,
+            path_relative_to_root(&PathBuf::from("/a/b/c/d/e/f"), &PathBuf::from("/g/h/i"))
+        );
+
+        assert_eq!(
+            None,
+            path_relative_to_root(&PathBuf::from("/database/x"), &PathBuf::from("/data"))
+        );
+    }
+
+    #[test]
+    fn test_split_at_dataset_root() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("data").join("x").join("data");
+        fs::create_dir_all(&dir).unwrap();
+
+        let (root, relative) = split_at_dataset_root(&dir).unwrap();
+        assert!(relative.ends_with("data/x/data"));
+        assert_eq!(dir.canonicalize().unwrap(), root.join(&relative));
+
+        assert!(split_at_dataset_root(&tmp.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_is_zfs_control_dir() {
+        let tmp = tempdir().unwrap();
+        let decoy = tmp.path().join(".zfs");
+        fs::create_dir(&decoy).unwrap();
+
+        assert!(!is_zfs_control_dir(&decoy));
+        assert!(!is_zfs_control_dir(tmp.path()));
+        assert!(!is_zfs_control_dir(
+            &tmp.path().join("missing").join(".zfs")
+        ));
+    }
+
+    #[cfg(target_os = "illumos")]
+    #[test]
+    fn test_is_zfs_control_dir_real() {
+        assert!(is_zfs_control_dir(&PathBuf::from("/.zfs")));
+    }
+
     #[test]
     fn test_file_to_dataset() {
-        let mounts: Vec<(PathBuf, String)> = vec![
-            (
-                PathBuf::from("/zones/serv-build"),
-                "rpool/zones/serv-build".to_string(),
-            ),
-            (
-                PathBuf::from("/build/configs"),
-                "fast/zone/build/config".to_string(),
-            ),
-            (PathBuf::from("/build"), "fast/zone/build/build".to_string()),
-            (PathBuf::from("/rpool"), "rpool".to_string()),
-            (PathBuf::from("/zones"), "rpool/zones".to_string()),
+        let mounts: MountList = vec![
+            Mount::new("/zones/serv-build", "rpool/zones/serv-build"),
+            Mount::new("/build/configs", "fast/zone/build/config"),
+            Mount::new("/build", "fast/zone/build/build"),
+            Mount::new("/rpool", "rpool"),
+            Mount::new("/zones", "rpool/zones"),
         ];
 
         assert_eq!(
@@ -97,6 +715,50 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_file_to_dataset_with_unsorted_mounts() {
+        let mounts: MountList = vec![
+            Mount::new("/", "rpool/ROOT/solaris"),
+            Mount::new("/build", "fast/zone/build/build"),
+            Mount::new("/build/configs/secret", "fast/zone/build/secret"),
+            Mount::new("/build/configs", "fast/zone/build/config"),
+            Mount::new("/build/conf", "fast/decoy"),
+        ];
+
+        assert_eq!(
+            Some("fast/zone/build/config".to_string()),
+            file_to_dataset(&PathBuf::from("/build/configs/file"), &mounts)
+        );
+
+        assert_eq!(
+            Some("fast/zone/build/secret".to_string()),
+            file_to_dataset(&PathBuf::from("/build/configs/secret/a/b"), &mounts)
+        );
+
+        assert_eq!(
+            Some("fast/zone/build/config".to_string()),
+            file_to_dataset(&PathBuf::from("/build/configs"), &mounts)
+        );
+
+        assert_eq!(
+            Some("fast/zone/build/build".to_string()),
+            file_to_dataset(&PathBuf::from("/build/config"), &mounts)
+        );
+
+        assert_eq!(
+            Some("rpool/ROOT/solaris".to_string()),
+            file_to_dataset(&PathBuf::from("/etc/passwd"), &mounts)
+        );
+
+        let mut reversed = mounts.clone();
+        reversed.reverse();
+
+        assert_eq!(
+            Some("fast/zone/build/secret".to_string()),
+            file_to_dataset(&PathBuf::from("/build/configs/secret/a/b"), &reversed)
+        );
+    }
+
     #[test]
     fn test_files_to_datasets() {
         let arg_list = &[
@@ -106,12 +768,9 @@ mod test {
         ];
 
         let mount_list = vec![
-            (PathBuf::from("/build"), "fast/zone/build/build".to_string()),
-            (
-                PathBuf::from("/build/configs"),
-                "fast/zone/build/config".to_string(),
-            ),
-            (PathBuf::from("/rpool"), "rpool".to_string()),
+            Mount::new("/build", "fast/zone/build/build"),
+            Mount::new("/build/configs", "fast/zone/build/config"),
+            Mount::new("/rpool", "rpool"),
         ];
 
         let mut expected = vec!["fast/zone/build/build".to_string(), "rpool".to_string()];
@@ -123,4 +782,38 @@ mod test {
 
         assert!(files_to_datasets(&["/where/is/this".to_string()], mount_list).is_empty());
     }
+
+    #[test]
+    fn test_files_to_datasets_detailed() {
+        let arg_list = &[
+            "/build/f1".to_string(),
+            "/where/is/this".to_string(),
+            "/build/configs/f2".to_string(),
+            "/build/f3".to_string(),
+        ];
+
+        let mount_list = vec![
+            Mount::new("/build/configs", "fast/zone/build/config"),
+            Mount::new("/build", "fast/zone/build/build"),
+        ];
+
+        assert_eq!(
+            vec![
+                (
+                    "/build/f1".to_string(),
+                    Some("fast/zone/build/build".to_string())
+                ),
+                ("/where/is/this".to_string(), None),
+                (
+                    "/build/configs/f2".to_string(),
+                    Some("fast/zone/build/config".to_string())
+                ),
+                (
+                    "/build/f3".to_string(),
+                    Some("fast/zone/build/build".to_string())
+                ),
+            ],
+            files_to_datasets_detailed(arg_list, mount_list)
+        );
+    }
 }
@@ -1,37 +1,377 @@
-use crate::command_helpers::output_as_lines;
-use crate::constants::ZFS;
-use crate::types::{Filesystems, MountList};
+use crate::command_helpers::{command_timeout, output_with_timeout};
+use crate::constants::{MNTTAB, ZFS};
+use crate::rules::rule_matches;
+use crate::snapshot::dataset_of_snapshot;
+use crate::types::{Filesystems, Mount, MountList, Usage};
+use crate::zfs_runner::{SystemRunner, ZfsRunner};
 use anyhow::anyhow;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
+/// Returns a `zfs list` command which will print the given fields, without headers, for objects
+/// of the given types. If no types are given, zfs's default is used. If a dataset is given, the
+/// listing starts there, optionally recursing.
+///
+pub fn list_command(
+    fields: &[&str],
+    types: &[&str],
+    dataset: Option<&str>,
+    recursive: bool,
+) -> Command {
+    let mut cmd = Command::new(ZFS);
+    cmd.arg("list").arg("-Ho").arg(fields.join(","));
+
+    if !types.is_empty() {
+        cmd.arg("-t").arg(types.join(","));
+    }
+
+    if recursive {
+        cmd.arg("-r");
+    }
+
+    if let Some(dataset) = dataset {
+        cmd.arg(dataset);
+    }
+
+    cmd
+}
+
+/// Runs `zfs list` with the given options (see `list_command()`), and returns a Vec for each line
+/// of output, each of which holds the requested fields, in order.
+///
+pub fn list(
+    fields: &[&str],
+    types: &[&str],
+    dataset: Option<&str>,
+    recursive: bool,
+) -> anyhow::Result<Vec<Vec<String>>> {
+    list_with(&SystemRunner, fields, types, dataset, recursive)
+}
+
+/// Like `list()`, but runs zfs through the given runner.
+///
+pub fn list_with(
+    runner: &dyn ZfsRunner,
+    fields: &[&str],
+    types: &[&str],
+    dataset: Option<&str>,
+    recursive: bool,
+) -> anyhow::Result<Vec<Vec<String>>> {
+    let lines = runner.lines(list_command(fields, types, dataset, recursive))?;
+    Ok(lines.iter().map(|line| split_list_line(line)).collect())
+}
+
+fn split_list_line(line: &str) -> Vec<String> {
+    line.split('\t').map(String::from).collect()
+}
+
+fn first_fields(rows: Vec<Vec<String>>) -> Vec<String> {
+    rows.into_iter()
+        .filter_map(|row| row.into_iter().next())
+        .collect()
+}
+
+/// Returns a `zfs get` command which will print the name of each given dataset, and its value of
+/// the given property, without headers.
+///
+pub fn property_command(property: &str, datasets: &[String]) -> Command {
+    let mut cmd = Command::new(ZFS);
+    cmd.arg("get").arg("-Ho").arg("name,value").arg(property);
+    cmd.args(datasets);
+    cmd
+}
+
+/// Fetches the value of the given property for all the given datasets with a single `zfs get`.
+/// Returns a map of dataset name to value. Datasets which do not exist are missing from the map.
+///
+pub fn get_property(
+    property: &str,
+    datasets: &[String],
+) -> anyhow::Result<HashMap<String, String>> {
+    if datasets.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let lines = zfs_lines(property_command(property, datasets))?;
+
+    Ok(lines
+        .iter()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect())
+}
+
+/// Returns a `zfs get` command which prints the name of each given dataset, and the number of
+/// bytes written to it since its most recent snapshot.
+///
+pub fn written_command(datasets: &[String]) -> Command {
+    let mut cmd = Command::new(ZFS);
+    cmd.arg("get").arg("-Hpo").arg("name,value").arg("written");
+    cmd.args(datasets);
+    cmd
+}
+
+/// Returns a map of dataset name to the number of bytes written to it since its most recent
+/// snapshot. A dataset with no snapshots counts everything it references. Datasets which do not
+/// exist, or which zfs can't give a number for, are missing from the map.
+///
+pub fn written(datasets: &[String]) -> anyhow::Result<HashMap<String, u64>> {
+    if datasets.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let lines = zfs_lines(written_command(datasets))?;
+
+    Ok(lines
+        .iter()
+        .filter_map(|line| {
+            let (name, value) = line.split_once('\t')?;
+            Some((name.to_string(), value.trim().parse().ok()?))
+        })
+        .collect())
+}
+
+/// Returns a `zfs list` command which succeeds only if the given dataset exists.
+///
+pub fn dataset_exists_command(name: &str) -> Command {
+    let mut cmd = Command::new(ZFS);
+    cmd.arg("list").arg("-H").arg(name);
+    cmd
+}
+
+/// Tells you whether or not the given dataset exists. An error means we could not ask zfs.
+///
+pub fn dataset_exists(name: &str) -> anyhow::Result<bool> {
+    command_succeeds(dataset_exists_command(name))
+}
+
+/// Returns those of the given dataset names which do not exist.
+///
+pub fn missing_datasets(names: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut ret = Vec::new();
+
+    for name in names {
+        if !dataset_exists(name)? {
+            ret.push(name.to_string());
+        }
+    }
+
+    Ok(ret)
+}
+
+// Like every zfs lookup, this honours ZFS_TOOLS_TIMEOUT.
+fn command_succeeds(cmd: Command) -> anyhow::Result<bool> {
+    let output = output_with_timeout(cmd, command_timeout())?;
+    Ok(output.status.success())
+}
+
+const USAGE_PROPERTIES: &[&str] = &[
+    "used",
+    "referenced",
+    "available",
+    "usedbydataset",
+    "usedbysnapshots",
+    "usedbychildren",
+];
+
+/// Returns a `zfs get` command which prints the space used by the given dataset, in bytes.
+///
+pub fn usage_command(dataset: &str) -> Command {
+    let mut cmd = Command::new(ZFS);
+    cmd.arg("get")
+        .arg("-Hpo")
+        .arg("property,value")
+        .arg(USAGE_PROPERTIES.join(","))
+        .arg(dataset);
+    cmd
+}
+
+/// Returns the space used by the given dataset.
+///
+pub fn usage_of(dataset: &str) -> anyhow::Result<Usage> {
+    let lines = zfs_lines(usage_command(dataset))?;
+    parse_usage(&lines).map_err(|e| anyhow!("{}: {}", dataset, e))
+}
+
+// Every property must be present. zfs gives '-' for those which don't apply.
+fn parse_usage(lines: &[String]) -> anyhow::Result<Usage> {
+    let values: HashMap<&str, &str> = lines.iter().filter_map(|l| l.split_once('\t')).collect();
+
+    let value_of = |property: &str| -> anyhow::Result<u64> {
+        match values.get(property) {
+            Some(&"-") => Ok(0),
+            Some(value) => value
+                .parse()
+                .map_err(|_| anyhow!("cannot parse {} value '{}'", property, value)),
+            None => Err(anyhow!("no value for {}", property)),
+        }
+    };
+
+    Ok(Usage {
+        used: value_of("used")?,
+        referenced: value_of("referenced")?,
+        available: value_of("available")?,
+        usedbydataset: value_of("usedbydataset")?,
+        usedbysnapshots: value_of("usedbysnapshots")?,
+        usedbychildren: value_of("usedbychildren")?,
+    })
+}
+
 /// Returns a Vec of all the snapshots zfs can see, each being a string.
 ///
 pub fn all_snapshots() -> anyhow::Result<Vec<String>> {
+    all_snapshots_with(&SystemRunner)
+}
+
+/// Like `all_snapshots()`, but runs zfs through the given runner.
+///
+pub fn all_snapshots_with(runner: &dyn ZfsRunner) -> anyhow::Result<Vec<String>> {
+    list_with(runner, &["name"], &["snapshot"], None, false).map(first_fields)
+}
+
+/// Returns a `zfs list` command which prints the snapshots of the given dataset, oldest first. If
+/// recursive is not set, `-d 1` stops zfs listing snapshots of child datasets.
+///
+pub fn snapshots_of_command(dataset: &str, recursive: bool) -> Command {
     let mut cmd = Command::new(ZFS);
     cmd.arg("list")
         .arg("-Ho")
         .arg("name")
         .arg("-t")
-        .arg("snapshot");
+        .arg("snapshot")
+        .arg("-s")
+        .arg("creation");
+
+    if recursive {
+        cmd.arg("-r");
+    } else {
+        cmd.arg("-d").arg("1");
+    }
 
-    output_as_lines(cmd)
+    cmd.arg(dataset);
+    cmd
 }
 
-/// Returns a Vec of all the ZFS filesystems on the host, each being a string.
+/// Returns the snapshots of the given dataset, and optionally its children, ordered by creation
+/// time, oldest first.
 ///
-pub fn all_filesystems() -> anyhow::Result<Vec<String>> {
+pub fn snapshots_of(dataset: &str, recursive: bool) -> anyhow::Result<Vec<String>> {
+    zfs_lines(snapshots_of_command(dataset, recursive))
+}
+
+/// Returns a `zfs list` command which prints the name and creation time, in seconds since the
+/// epoch, of each snapshot of the given dataset, but not of its children.
+///
+pub fn snapshot_creation_command(dataset: &str) -> Command {
     let mut cmd = Command::new(ZFS);
     cmd.arg("list")
-        .arg("-Ho")
-        .arg("name")
+        .arg("-Hpo")
+        .arg("name,creation")
         .arg("-t")
-        .arg("filesystem");
+        .arg("snapshot")
+        .arg("-d")
+        .arg("1")
+        .arg(dataset);
+    cmd
+}
+
+/// Returns a map of snapshot name, without the dataset part, to the time the snapshot was
+/// created, in seconds since the epoch.
+///
+pub fn snapshot_creation(dataset: &str) -> anyhow::Result<HashMap<String, i64>> {
+    let lines = zfs_lines(snapshot_creation_command(dataset))?;
 
-    output_as_lines(cmd)
+    Ok(parse_creation_lines(&lines)
+        .into_iter()
+        .filter_map(|(name, creation)| {
+            let (_dataset, snapname) = name.split_once('@')?;
+            Some((snapname.to_string(), creation))
+        })
+        .collect())
+}
+
+/// Returns a `zfs list` command which prints the name and creation time, in seconds since the
+/// epoch, of every snapshot.
+///
+pub fn all_snapshot_creation_command() -> Command {
+    let mut cmd = Command::new(ZFS);
+    cmd.arg("list")
+        .arg("-Hpo")
+        .arg("name,creation")
+        .arg("-t")
+        .arg("snapshot");
+    cmd
+}
+
+/// Returns a map of the full name of every snapshot to the time it was created, in seconds since
+/// the epoch.
+///
+pub fn all_snapshot_creation() -> anyhow::Result<HashMap<String, i64>> {
+    zfs_lines(all_snapshot_creation_command()).map(|lines| parse_creation_lines(&lines))
+}
+
+fn parse_creation_lines(lines: &[String]) -> HashMap<String, i64> {
+    lines
+        .iter()
+        .filter_map(|line| {
+            let (name, creation) = line.split_once('\t')?;
+            Some((name.to_string(), creation.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+// Every zfs listing goes through here, so they all honour ZFS_TOOLS_TIMEOUT. Existence checks go
+// through command_succeeds(), which does too.
+fn zfs_lines(cmd: Command) -> anyhow::Result<Vec<String>> {
+    SystemRunner.lines(cmd)
+}
+
+/// Returns a Vec of all the ZFS filesystems on the host, each being a string.
+///
+pub fn all_filesystems() -> anyhow::Result<Vec<String>> {
+    all_filesystems_with(&SystemRunner)
+}
+
+/// Like `all_filesystems()`, but runs zfs through the given runner.
+///
+pub fn all_filesystems_with(runner: &dyn ZfsRunner) -> anyhow::Result<Vec<String>> {
+    list_with(runner, &["name"], &["filesystem"], None, false).map(first_fields)
+}
+
+/// Returns a Vec of all the ZFS volumes (zvols) on the host, each being a string.
+///
+pub fn all_volumes() -> anyhow::Result<Vec<String>> {
+    all_volumes_with(&SystemRunner)
+}
+
+/// Like `all_volumes()`, but runs zfs through the given runner.
+///
+pub fn all_volumes_with(runner: &dyn ZfsRunner) -> anyhow::Result<Vec<String>> {
+    list_with(runner, &["name"], &["volume"], None, false).map(first_fields)
+}
+
+/// Returns every filesystem which has no snapshots at all, in `zfs list` order. Useful for finding
+/// what isn't protected.
+///
+pub fn datasets_without_snapshots() -> anyhow::Result<Filesystems> {
+    Ok(without_snapshots(all_filesystems()?, &all_snapshots()?))
+}
+
+/// The filesystems which don't appear as the dataset part of any of the given snapshot names.
+///
+pub fn without_snapshots(filesystems: Filesystems, snapshots: &[String]) -> Filesystems {
+    let snapshotted: HashSet<String> = snapshots
+        .iter()
+        .filter_map(|snapshot| dataset_of_snapshot(snapshot))
+        .collect();
+
+    filesystems
+        .into_iter()
+        .filter(|filesystem| !snapshotted.contains(filesystem))
+        .collect()
 }
 
 /// Returns a Vec of all mounted ZFS filesystems, described as Strings.
@@ -39,14 +379,14 @@ pub fn all_filesystems() -> anyhow::Result<Vec<String>> {
 pub fn all_zfs_mounts() -> anyhow::Result<Vec<String>> {
     let mut cmd = Command::new(ZFS);
     cmd.arg("list").arg("-Ho").arg("mountpoint,name");
-    output_as_lines(cmd)
+    zfs_lines(cmd)
 }
 
 /// Returns a vec of all the ZFS mounts which are not 'legacy', sorted by the
 /// length of the path
 ///
 pub fn mounted_filesystems(mounts: Vec<String>) -> anyhow::Result<MountList> {
-    let mut ret: Vec<(PathBuf, String)> = mounts
+    let mut ret: MountList = mounts
         .iter()
         .filter_map(|line| {
             let mut parts = line.split_whitespace();
@@ -54,14 +394,14 @@ pub fn mounted_filesystems(mounts: Vec<String>) -> anyhow::Result<MountList> {
                 (Some(mountpoint), Some(name))
                     if mountpoint != "none" && mountpoint != "legacy" =>
                 {
-                    Some((PathBuf::from(mountpoint), name.to_string()))
+                    Some(Mount::new(mountpoint, name))
                 }
                 _ => None,
             }
         })
         .collect();
 
-    ret.sort_by_key(|(path, _name)| std::cmp::Reverse(path.to_string_lossy().len()));
+    ret.sort_by_key(|mount| std::cmp::Reverse(mount.mountpoint.to_string_lossy().len()));
     Ok(ret)
 }
 
@@ -70,8 +410,112 @@ pub fn get_mounted_filesystems() -> anyhow::Result<MountList> {
     mounted_filesystems(all_mounts)
 }
 
+/// Returns the mountpoint of the given dataset, as found in a list from
+/// `get_mounted_filesystems()`. Datasets which are unmounted, or have `legacy` mountpoints, are
+/// not in that list, so give None.
+///
+pub fn mountpoint_of(dataset: &str, mounts: &MountList) -> Option<PathBuf> {
+    mounts
+        .iter()
+        .find(|mount| mount.dataset == dataset)
+        .map(|mount| mount.mountpoint.clone())
+}
+
+/// Returns the ZFS filesystems in a system mount table, sorted by the length of the path. Unlike
+/// `mounted_filesystems()` this knows where legacy mounts really are.
+///
+pub fn mnttab_filesystems(mnttab: Vec<String>) -> MountList {
+    let mut ret: MountList = mnttab
+        .iter()
+        .filter_map(|line| match mnttab_fields(line).as_slice() {
+            [name, mountpoint, fstype, ..] if fstype == "zfs" => Some(Mount::new(mountpoint, name)),
+            _ => None,
+        })
+        .collect();
+
+    ret.sort_by_key(|mount| std::cmp::Reverse(mount.mountpoint.to_string_lossy().len()));
+    ret
+}
+
+// illumos separates mnttab fields with tabs, so a mountpoint may contain spaces. Linux's
+// /proc/mounts separates them with spaces, and writes any in a mountpoint as \040.
+fn mnttab_fields(line: &str) -> Vec<String> {
+    if line.contains('\t') {
+        line.split('\t').map(String::from).collect()
+    } else {
+        line.split_whitespace().map(unescape_octal).collect()
+    }
+}
+
+// Turns escapes like \040 back into the bytes they stand for. Anything else is left alone.
+fn unescape_octal(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+
+        match escaped {
+            Some(byte) => {
+                ret.push(byte);
+                i += 4;
+            }
+            None => {
+                ret.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&ret).to_string()
+}
+
+pub fn get_mnttab_filesystems() -> anyhow::Result<MountList> {
+    let mnttab = fs::read_to_string(MNTTAB)?;
+    Ok(mnttab_filesystems(
+        mnttab.lines().map(String::from).collect(),
+    ))
+}
+
+/// Works out which dataset is mounted at the given root. The mount table is what is really
+/// mounted, so it is believed first. The mountpoints zfs reports are only used if the mount table
+/// doesn't know.
+///
+pub fn dataset_name_for_root(
+    root: &Path,
+    zfs_mounts: &MountList,
+    mnttab_mounts: &MountList,
+) -> Option<String> {
+    mnttab_mounts
+        .iter()
+        .chain(zfs_mounts.iter())
+        .find(|mount| mount.mountpoint == root)
+        .map(|mount| mount.dataset.clone())
+}
+
+/// Like `dataset_root()`, but also cross-checks the root against the given mount lists, returning
+/// the root and the name of the dataset mounted there.
+///
+pub fn dataset_root_checked(
+    file: &Path,
+    zfs_mounts: &MountList,
+    mnttab_mounts: &MountList,
+) -> anyhow::Result<(PathBuf, String)> {
+    let root = dataset_root(file)?;
+
+    match dataset_name_for_root(&root, zfs_mounts, mnttab_mounts) {
+        Some(name) => Ok((root, name)),
+        None => Err(anyhow!("no ZFS dataset is mounted at {}", root.display())),
+    }
+}
+
 pub fn is_mountpoint(file: &Path) -> anyhow::Result<bool> {
-    if file == PathBuf::from("/") {
+    if file == Path::new("/") {
         Ok(true)
     } else {
         let path_metadata = fs::metadata(file)?;
@@ -80,6 +524,9 @@ pub fn is_mountpoint(file: &Path) -> anyhow::Result<bool> {
     }
 }
 
+/// Walks up from the given file until it finds the root of the filesystem which holds it. This
+/// works purely on `Path`s, so file names which are not valid UTF-8 survive the trip.
+///
 pub fn dataset_root(file: &Path) -> anyhow::Result<PathBuf> {
     if is_mountpoint(file)? {
         Ok(file.to_path_buf())
@@ -91,11 +538,12 @@ pub fn dataset_root(file: &Path) -> anyhow::Result<PathBuf> {
 }
 
 /// Given a list of ZFS filesystems and knowledge of all ZFS filesystems, returns the subset
-/// of all filesystems under any of the given ones.
+/// of all filesystems under any of the given ones. Empty or blank names match nothing.
 ///
 pub fn dataset_list_recursive(from_user: Vec<String>, all_filesystems: Filesystems) -> Filesystems {
     let unique_datasets: HashSet<String> = from_user
         .into_iter()
+        .filter(|path| !path.trim().is_empty())
         .flat_map(|path| {
             let formatted_path = ensure_trailing_slash(&path);
             all_filesystems
@@ -108,6 +556,18 @@ pub fn dataset_list_recursive(from_user: Vec<String>, all_filesystems: Filesyste
     unique_datasets.into_iter().collect()
 }
 
+/// Given a list of wildcard patterns and knowledge of all ZFS filesystems, returns every filesystem
+/// matched by any of the patterns. Patterns follow the same rules as omit lists, but match
+/// positively.
+///
+pub fn expand_dataset_globs(patterns: &[String], all_filesystems: &Filesystems) -> Filesystems {
+    all_filesystems
+        .iter()
+        .filter(|fs| patterns.iter().any(|pattern| rule_matches(fs, pattern)))
+        .cloned()
+        .collect()
+}
+
 fn ensure_trailing_slash(path: &str) -> String {
     if path.ends_with('/') {
         path.to_string()
@@ -119,21 +579,17 @@ fn ensure_trailing_slash(path: &str) -> String {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::zfs_runner::MockRunner;
+    use tempfile::tempdir;
 
     #[test]
     fn test_zfs_mounts() {
-        let expected: Vec<(PathBuf, String)> = vec![
-            (
-                PathBuf::from("/zones/serv-build"),
-                "rpool/zones/serv-build".to_string(),
-            ),
-            (
-                PathBuf::from("/build/configs"),
-                "fast/zone/build/config".to_string(),
-            ),
-            (PathBuf::from("/build"), "fast/zone/build/build".to_string()),
-            (PathBuf::from("/rpool"), "rpool".to_string()),
-            (PathBuf::from("/zones"), "rpool/zones".to_string()),
+        let expected: MountList = vec![
+            Mount::new("/zones/serv-build", "rpool/zones/serv-build"),
+            Mount::new("/build/configs", "fast/zone/build/config"),
+            Mount::new("/build", "fast/zone/build/build"),
+            Mount::new("/rpool", "rpool"),
+            Mount::new("/zones", "rpool/zones"),
         ];
 
         assert_eq!(
@@ -149,6 +605,481 @@ mod test {
         );
     }
 
+    fn mountpoint_list() -> MountList {
+        mounted_filesystems(
+            fs::read_to_string("test/resources/mountpoint_list.txt")
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_mountpoint_of() {
+        let mounts = mountpoint_list();
+
+        assert_eq!(
+            Some(PathBuf::from("/build/configs")),
+            mountpoint_of("fast/zone/build/config", &mounts)
+        );
+        assert_eq!(
+            Some(PathBuf::from("/rpool")),
+            mountpoint_of("rpool", &mounts)
+        );
+        assert_eq!(None, mountpoint_of("rpool/zones/serv-build/ROOT", &mounts));
+        assert_eq!(None, mountpoint_of("fast/zone", &mounts));
+        assert_eq!(None, mountpoint_of("rpool/zone", &mounts));
+    }
+
+    #[test]
+    fn test_mnttab_filesystems() {
+        let mnttab = vec![
+            "rpool/ROOT/omnios\t/\tzfs\tdev=4410002\t1730563919".to_string(),
+            "rpool/zones/serv-build/ROOT/zbe-3\t/zones/serv-build/root\tzfs\trw\t1".to_string(),
+            "swap\t/tmp\ttmpfs\txattr\t1730563919".to_string(),
+            "proc /proc proc rw,nosuid 0 0".to_string(),
+            "".to_string(),
+        ];
+
+        assert_eq!(
+            vec![
+                Mount::new(
+                    "/zones/serv-build/root",
+                    "rpool/zones/serv-build/ROOT/zbe-3"
+                ),
+                Mount::new("/", "rpool/ROOT/omnios"),
+            ],
+            mnttab_filesystems(mnttab)
+        );
+    }
+
+    #[test]
+    fn test_mnttab_filesystems_with_spaces() {
+        let mnttab = vec![
+            "rpool/export/share\t/export/my share\tzfs\trw\t1730563919".to_string(),
+            "tank/media /mnt/my\\040media zfs rw,xattr 0 0".to_string(),
+            "tank/odd /mnt/back\\slash zfs rw 0 0".to_string(),
+        ];
+
+        assert_eq!(
+            vec![
+                Mount::new("/export/my share", "rpool/export/share"),
+                Mount::new("/mnt/back\\slash", "tank/odd"),
+                Mount::new("/mnt/my media", "tank/media"),
+            ],
+            mnttab_filesystems(mnttab)
+        );
+    }
+
+    #[test]
+    fn test_unescape_octal() {
+        assert_eq!("/mnt/my media", unescape_octal("/mnt/my\\040media"));
+        assert_eq!("/mnt/café", unescape_octal("/mnt/caf\\303\\251"));
+        assert_eq!("/mnt/plain", unescape_octal("/mnt/plain"));
+        assert_eq!("/mnt/end\\", unescape_octal("/mnt/end\\"));
+        assert_eq!("/mnt/\\9zz", unescape_octal("/mnt/\\9zz"));
+    }
+
+    #[test]
+    fn test_dataset_name_for_root() {
+        let zfs_mounts = mountpoint_list();
+        let mnttab_mounts = vec![
+            Mount::new(
+                "/zones/serv-build/root",
+                "rpool/zones/serv-build/ROOT/zbe-3",
+            ),
+            Mount::new("/rpool", "rpool"),
+        ];
+
+        assert_eq!(
+            Some("rpool/zones/serv-build/ROOT/zbe-3".to_string()),
+            dataset_name_for_root(
+                &PathBuf::from("/zones/serv-build/root"),
+                &zfs_mounts,
+                &mnttab_mounts
+            )
+        );
+
+        assert_eq!(
+            Some("fast/zone/build/build".to_string()),
+            dataset_name_for_root(&PathBuf::from("/build"), &zfs_mounts, &mnttab_mounts)
+        );
+
+        assert_eq!(
+            None,
+            dataset_name_for_root(&PathBuf::from("/build/x"), &zfs_mounts, &mnttab_mounts)
+        );
+
+        assert_eq!(
+            None,
+            dataset_name_for_root(
+                &PathBuf::from("/zones/serv-build/root"),
+                &zfs_mounts,
+                &Vec::new()
+            )
+        );
+    }
+
+    #[test]
+    fn test_dataset_root() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        assert_eq!(PathBuf::from("/"), dataset_root(Path::new("/")).unwrap());
+
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join(OsStr::from_bytes(b"caf\xe9"));
+        fs::create_dir(&dir).unwrap();
+        let file = dir.join("file");
+        fs::write(&file, "data").unwrap();
+
+        let root = dataset_root(&file).unwrap();
+        assert!(file.starts_with(&root));
+        assert!(is_mountpoint(&root).unwrap());
+        assert_eq!(root, dataset_root(&dir).unwrap());
+    }
+
+    #[test]
+    fn test_dataset_root_checked() {
+        let tmp = tempdir().unwrap();
+        let root = dataset_root(tmp.path()).unwrap();
+        let legacy_mount = vec![Mount::new(&root, "rpool/zones/serv-build/ROOT")];
+
+        assert_eq!(
+            (root, "rpool/zones/serv-build/ROOT".to_string()),
+            dataset_root_checked(tmp.path(), &mountpoint_list(), &legacy_mount).unwrap()
+        );
+
+        assert!(dataset_root_checked(tmp.path(), &mountpoint_list(), &Vec::new()).is_err());
+    }
+
+    fn args_of(cmd: &Command) -> Vec<String> {
+        cmd.get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_list_command() {
+        let cmd = list_command(&["name"], &["snapshot"], None, false);
+        assert_eq!(ZFS, cmd.get_program());
+        assert_eq!(vec!["list", "-Ho", "name", "-t", "snapshot"], args_of(&cmd));
+
+        assert_eq!(
+            vec![
+                "list",
+                "-Ho",
+                "name,used,usedbydataset",
+                "-t",
+                "filesystem,volume"
+            ],
+            args_of(&list_command(
+                &["name", "used", "usedbydataset"],
+                &["filesystem", "volume"],
+                None,
+                false
+            ))
+        );
+
+        assert_eq!(
+            vec!["list", "-Ho", "mountpoint,name"],
+            args_of(&list_command(&["mountpoint", "name"], &[], None, false))
+        );
+
+        assert_eq!(
+            vec!["list", "-Ho", "name", "-t", "snapshot", "-r", "rpool/test"],
+            args_of(&list_command(
+                &["name"],
+                &["snapshot"],
+                Some("rpool/test"),
+                true
+            ))
+        );
+
+        assert_eq!(
+            vec!["list", "-Ho", "name", "rpool"],
+            args_of(&list_command(&["name"], &[], Some("rpool"), false))
+        );
+    }
+
+    #[test]
+    fn test_dataset_exists_command() {
+        let cmd = dataset_exists_command("rpool/test");
+        assert_eq!(ZFS, cmd.get_program());
+        assert_eq!(vec!["list", "-H", "rpool/test"], args_of(&cmd));
+    }
+
+    #[test]
+    fn test_command_succeeds() {
+        assert!(command_succeeds(Command::new("test/resources/bin/succeed")).unwrap());
+        assert!(!command_succeeds(Command::new("test/resources/bin/fail")).unwrap());
+        assert!(command_succeeds(Command::new("test/resources/bin/no_such_command")).is_err());
+    }
+
+    #[test]
+    fn test_snapshots_of_command() {
+        let cmd = snapshots_of_command("rpool/test", false);
+        assert_eq!(ZFS, cmd.get_program());
+        assert_eq!(
+            vec![
+                "list",
+                "-Ho",
+                "name",
+                "-t",
+                "snapshot",
+                "-s",
+                "creation",
+                "-d",
+                "1",
+                "rpool/test"
+            ],
+            args_of(&cmd)
+        );
+
+        assert_eq!(
+            vec!["list", "-Ho", "name", "-t", "snapshot", "-s", "creation", "-r", "rpool"],
+            args_of(&snapshots_of_command("rpool", true))
+        );
+    }
+
+    #[test]
+    fn test_snapshot_creation_command() {
+        let cmd = snapshot_creation_command("rpool/test");
+        assert_eq!(ZFS, cmd.get_program());
+        assert_eq!(
+            vec![
+                "list",
+                "-Hpo",
+                "name,creation",
+                "-t",
+                "snapshot",
+                "-d",
+                "1",
+                "rpool/test"
+            ],
+            args_of(&cmd)
+        );
+    }
+
+    #[test]
+    fn test_all_snapshot_creation_command() {
+        assert_eq!(
+            vec!["list", "-Hpo", "name,creation", "-t", "snapshot"],
+            args_of(&all_snapshot_creation_command())
+        );
+    }
+
+    #[test]
+    fn test_parse_creation_lines() {
+        let lines = vec![
+            "rpool/test@monday\t1730073600".to_string(),
+            "rpool/test@tuesday\t1730160000".to_string(),
+            "rpool/test@broken\tsoon".to_string(),
+            "rpool/test".to_string(),
+        ];
+
+        let expected: HashMap<String, i64> = [
+            ("rpool/test@monday".to_string(), 1730073600),
+            ("rpool/test@tuesday".to_string(), 1730160000),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(expected, parse_creation_lines(&lines));
+    }
+
+    #[test]
+    fn test_usage_command() {
+        assert_eq!(
+            vec![
+                "get",
+                "-Hpo",
+                "property,value",
+                "used,referenced,available,usedbydataset,usedbysnapshots,usedbychildren",
+                "rpool/test"
+            ],
+            args_of(&usage_command("rpool/test"))
+        );
+    }
+
+    #[test]
+    fn test_parse_usage() {
+        let to_lines = |text: &str| -> Vec<String> { text.lines().map(String::from).collect() };
+
+        let filesystem = to_lines(
+            "used\t6582231040
+referenced\t6495035392
+available\t51883741184
+usedbydataset\t6495035392
+usedbysnapshots\t87195648
+usedbychildren\t0",
+        );
+
+        assert_eq!(
+            Usage {
+                used: 6582231040,
+                referenced: 6495035392,
+                available: 51883741184,
+                usedbydataset: 6495035392,
+                usedbysnapshots: 87195648,
+                usedbychildren: 0,
+            },
+            parse_usage(&filesystem).unwrap()
+        );
+
+        let snapshot = to_lines(
+            "used\t1257472
+referenced\t6495035392
+available\t-
+usedbydataset\t-
+usedbysnapshots\t-
+usedbychildren\t-",
+        );
+
+        assert_eq!(
+            Usage {
+                used: 1257472,
+                referenced: 6495035392,
+                ..Default::default()
+            },
+            parse_usage(&snapshot).unwrap()
+        );
+
+        assert!(parse_usage(&filesystem[1..]).is_err());
+        assert!(parse_usage(&to_lines("used\t6.13G")).is_err());
+    }
+
+    #[test]
+    fn test_property_command() {
+        let cmd = property_command(
+            "com.myorg:autosnap",
+            &["rpool".to_string(), "rpool/test".to_string()],
+        );
+
+        assert_eq!(ZFS, cmd.get_program());
+        assert_eq!(
+            vec![
+                "get",
+                "-Ho",
+                "name,value",
+                "com.myorg:autosnap",
+                "rpool",
+                "rpool/test"
+            ],
+            args_of(&cmd)
+        );
+    }
+
+    #[test]
+    fn test_written_command() {
+        let cmd = written_command(&["rpool".to_string(), "rpool/test".to_string()]);
+
+        assert_eq!(ZFS, cmd.get_program());
+        assert_eq!(
+            vec![
+                "get",
+                "-Hpo",
+                "name,value",
+                "written",
+                "rpool",
+                "rpool/test"
+            ],
+            args_of(&cmd)
+        );
+    }
+
+    #[test]
+    fn test_all_snapshots_with() {
+        let runner = MockRunner::new(&["rpool@monday", "rpool/test@12:00"]);
+
+        assert_eq!(
+            vec!["rpool@monday".to_string(), "rpool/test@12:00".to_string()],
+            all_snapshots_with(&runner).unwrap()
+        );
+
+        assert_eq!(
+            vec![format!("{} list -Ho name -t snapshot", ZFS)],
+            *runner.commands.borrow()
+        );
+
+        assert!(all_snapshots_with(&MockRunner::failing("no pools available")).is_err());
+    }
+
+    #[test]
+    fn test_without_snapshots() {
+        let filesystems: Filesystems = ["rpool", "rpool/ROOT", "rpool/test", "tank", "tank/home"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        let snapshots: Vec<String> = [
+            "rpool@monday",
+            "rpool/test@12:00",
+            "rpool/test@13:00",
+            "tank/home/user@monday",
+            "gone@monday",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        assert_eq!(
+            vec![
+                "rpool/ROOT".to_string(),
+                "tank".to_string(),
+                "tank/home".to_string()
+            ],
+            without_snapshots(filesystems.clone(), &snapshots)
+        );
+
+        assert_eq!(filesystems, without_snapshots(filesystems.clone(), &[]));
+        assert!(without_snapshots(Vec::new(), &snapshots).is_empty());
+    }
+
+    #[test]
+    fn test_all_filesystems_with() {
+        let runner = MockRunner::new(&["rpool", "rpool/ROOT", "tank/home"]);
+
+        assert_eq!(
+            vec![
+                "rpool".to_string(),
+                "rpool/ROOT".to_string(),
+                "tank/home".to_string()
+            ],
+            all_filesystems_with(&runner).unwrap()
+        );
+
+        assert_eq!(
+            vec![format!("{} list -Ho name -t filesystem", ZFS)],
+            *runner.commands.borrow()
+        );
+
+        let runner = MockRunner::new(&["rpool/swap"]);
+        assert_eq!(
+            vec!["rpool/swap".to_string()],
+            all_volumes_with(&runner).unwrap()
+        );
+        assert_eq!(
+            vec![format!("{} list -Ho name -t volume", ZFS)],
+            *runner.commands.borrow()
+        );
+    }
+
+    #[test]
+    fn test_split_list_line() {
+        assert_eq!(
+            vec!["rpool/test", "1.2G", "-"],
+            split_list_line("rpool/test\t1.2G\t-")
+        );
+
+        assert_eq!(
+            vec!["/path with spaces", "rpool/test"],
+            split_list_line("/path with spaces\trpool/test")
+        );
+    }
+
     #[test]
     fn test_dataset_list_recursive() {
         let arg_list = vec!["build".to_string(), "rpool/test".to_string()];
@@ -178,4 +1109,62 @@ mod test {
 
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn test_dataset_list_recursive_blank_args() {
+        let all_filesystems = vec![
+            "build".to_string(),
+            "build/test".to_string(),
+            "rpool".to_string(),
+        ];
+
+        assert!(dataset_list_recursive(vec!["".to_string()], all_filesystems.clone()).is_empty());
+        assert!(dataset_list_recursive(vec![" ".to_string()], all_filesystems.clone()).is_empty());
+        assert!(dataset_list_recursive(vec!["/".to_string()], all_filesystems.clone()).is_empty());
+
+        assert_eq!(
+            vec!["rpool".to_string()],
+            dataset_list_recursive(
+                vec!["".to_string(), "rpool".to_string(), " ".to_string()],
+                all_filesystems
+            )
+        );
+    }
+
+    #[test]
+    fn test_expand_dataset_globs() {
+        let all_filesystems = vec![
+            "rpool".to_string(),
+            "rpool/zones".to_string(),
+            "rpool/zones/web".to_string(),
+            "rpool/zones/db".to_string(),
+            "tank/db".to_string(),
+            "tank/logs".to_string(),
+        ];
+
+        assert_eq!(
+            all_filesystems,
+            expand_dataset_globs(&["*".to_string()], &all_filesystems)
+        );
+
+        assert_eq!(
+            vec!["rpool/zones/web".to_string(), "rpool/zones/db".to_string()],
+            expand_dataset_globs(&["rpool/zones/*".to_string()], &all_filesystems)
+        );
+
+        assert_eq!(
+            vec!["rpool/zones/db".to_string(), "tank/db".to_string()],
+            expand_dataset_globs(&["*/db".to_string()], &all_filesystems)
+        );
+
+        assert_eq!(
+            vec!["rpool".to_string(), "tank/logs".to_string()],
+            expand_dataset_globs(
+                &["rpool".to_string(), "*logs".to_string()],
+                &all_filesystems
+            )
+        );
+
+        assert!(expand_dataset_globs(&["nothing*".to_string()], &all_filesystems).is_empty());
+    }
 }
@@ -0,0 +1,82 @@
+//! The seam between `zfs_info` and the `zfs` binary. Functions which take a `ZfsRunner` can be
+//! given a `MockRunner` in tests, so they can be exercised on hosts without ZFS.
+//!
+use crate::command_helpers::{command_timeout, format_command, output_as_lines_with_timeout};
+use anyhow::anyhow;
+use std::cell::RefCell;
+use std::process::Command;
+
+pub trait ZfsRunner {
+    /// Runs the command, and returns its standard output as a Vec of lines.
+    ///
+    fn lines(&self, cmd: Command) -> anyhow::Result<Vec<String>>;
+}
+
+/// Really runs commands, honouring `ZFS_TOOLS_TIMEOUT`.
+///
+pub struct SystemRunner;
+
+impl ZfsRunner for SystemRunner {
+    fn lines(&self, cmd: Command) -> anyhow::Result<Vec<String>> {
+        output_as_lines_with_timeout(cmd, command_timeout())
+    }
+}
+
+/// Runs nothing. Every command gets the same canned output, or the same error, and is recorded,
+/// as it would be printed by `format_command()`, so tests can check what would have been run.
+///
+pub struct MockRunner {
+    output: Result<Vec<String>, String>,
+    pub commands: RefCell<Vec<String>>,
+}
+
+impl MockRunner {
+    pub fn new(lines: &[&str]) -> Self {
+        MockRunner {
+            output: Ok(lines.iter().map(|line| line.to_string()).collect()),
+            commands: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn failing(message: &str) -> Self {
+        MockRunner {
+            output: Err(message.to_string()),
+            commands: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl ZfsRunner for MockRunner {
+    fn lines(&self, cmd: Command) -> anyhow::Result<Vec<String>> {
+        self.commands.borrow_mut().push(format_command(&cmd));
+
+        match &self.output {
+            Ok(lines) => Ok(lines.clone()),
+            Err(message) => Err(anyhow!("{}", message)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mock_runner() {
+        let runner = MockRunner::new(&["rpool", "rpool/test"]);
+        let mut cmd = Command::new("/usr/sbin/zfs");
+        cmd.arg("list");
+
+        assert_eq!(
+            vec!["rpool".to_string(), "rpool/test".to_string()],
+            runner.lines(cmd).unwrap()
+        );
+        assert_eq!(vec!["/usr/sbin/zfs list"], *runner.commands.borrow());
+
+        let runner = MockRunner::failing("pool is suspended");
+        assert_eq!(
+            "pool is suspended",
+            runner.lines(Command::new("zfs")).unwrap_err().to_string()
+        );
+    }
+}
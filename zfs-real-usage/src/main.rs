@@ -1,19 +1,90 @@
-use byte_unit::Byte;
-use common::constants::ZFS;
+use byte_unit::{Byte, UnitType};
+use clap::{Parser, ValueEnum};
+use common::command_helpers::{command_timeout, output_with_timeout};
+use common::{duration, pool, zfs_info};
 use std::{
-    io,
-    process::{exit, Command, Output},
+    collections::BTreeMap,
+    io::{self, Write},
+    process::{exit, Output},
     string::FromUtf8Error,
+    thread,
+    time::Duration,
 };
 
-fn list_dataset_usage() -> io::Result<Output> {
-    Command::new(ZFS)
-        .arg("list")
-        .arg("-t")
-        .arg("all")
-        .arg("-Ho")
-        .arg("name,used,usedbydataset")
-        .output()
+// Moves the cursor to the top left, and clears the screen.
+const CLEAR_SCREEN: &str = "\x1B[2J\x1B[H";
+
+#[derive(Parser)]
+#[clap(version, about = "Shows the real disk usage of ZFS datasets", long_about = None)]
+struct Cli {
+    /// Only show objects of this type
+    #[clap(short = 't', long = "type", value_enum, default_value_t = ObjectType::All)]
+    object_type: ObjectType,
+    /// Group datasets by pool, with a subtotal for each
+    #[clap(short = 'p', long)]
+    by_pool: bool,
+    /// Mark with a ! datasets whose snapshots use more space than their live data
+    #[clap(short, long)]
+    snapshot_heavy: bool,
+    /// Only show datasets using at least this much space, e.g. 500M
+    #[clap(short, long, value_parser = parse_size)]
+    min_size: Option<u64>,
+    /// Keep running, refreshing the report at this interval, e.g. 10s. Stop with Ctrl-C
+    #[clap(short, long, value_name = "INTERVAL", value_parser = parse_interval)]
+    watch: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum ObjectType {
+    Filesystem,
+    Snapshot,
+    Volume,
+    All,
+}
+
+fn type_arg(object_type: ObjectType) -> &'static str {
+    match object_type {
+        ObjectType::Filesystem => "filesystem",
+        ObjectType::Snapshot => "snapshot",
+        ObjectType::Volume => "volume",
+        ObjectType::All => "all",
+    }
+}
+
+fn parse_size(size: &str) -> Result<u64, String> {
+    Byte::parse_str(size, true)
+        .map(|bytes| bytes.as_u64())
+        .map_err(|e| format!("invalid size '{}': {}", size, e))
+}
+
+// A zero interval would have us hammering zfs.
+fn parse_interval(interval: &str) -> Result<Duration, String> {
+    match duration::parse(interval) {
+        Ok(duration) if duration.is_zero() => {
+            Err(format!("interval '{}' must be longer than zero", interval))
+        }
+        Ok(duration) => Ok(duration),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn drop_smaller_than(dataset_list: Vec<Dataset>, min_size: u64) -> Vec<Dataset> {
+    dataset_list
+        .into_iter()
+        .filter(|dataset| dataset.byte_size >= min_size)
+        .collect()
+}
+
+// A suspended pool can hang the listing, so it honours ZFS_TOOLS_TIMEOUT.
+fn list_dataset_usage(object_type: ObjectType) -> io::Result<Output> {
+    let cmd = zfs_info::list_command(
+        &["name", "used", "usedbydataset", "usedbysnapshots"],
+        &[type_arg(object_type)],
+        None,
+        false,
+    );
+
+    output_with_timeout(cmd, command_timeout())
 }
 
 #[derive(Debug, PartialEq)]
@@ -21,16 +92,32 @@ struct Dataset {
     byte_size: u64,
     format_size: String,
     name: String,
+    snapshot_bytes: u64,
+}
+
+// Snapshots use more space than the live data in their dataset. Snapshots themselves never have
+// snapshot usage, so are never heavy.
+fn is_snapshot_heavy(dataset: &Dataset) -> bool {
+    dataset.snapshot_bytes > dataset.byte_size
 }
 
-fn parse_dataset_line(line: &str) -> Option<Dataset> {
+// Datasets which use no space are Ok(None). Lines which can't be parsed are errors.
+fn parse_dataset_line(line: &str) -> Result<Option<Dataset>, String> {
     let chunks: Vec<&str> = line.split_whitespace().collect();
 
-    if chunks.len() != 3 {
-        eprintln!("ERROR: failed to parse '{}'", line);
-        return None;
+    if chunks.len() != 4 {
+        return Err(format!("failed to parse '{}'", line));
     }
 
+    let snapshot_bytes = if chunks[3] == "-" {
+        0
+    } else {
+        match Byte::parse_str(chunks[3], true) {
+            Ok(bytes) => bytes.as_u64(),
+            Err(e) => return Err(format!("failed to parse '{}': {}", line, e)),
+        }
+    };
+
     let size = if chunks[2] == "-" {
         chunks[1]
     } else {
@@ -41,60 +128,301 @@ fn parse_dataset_line(line: &str) -> Option<Dataset> {
         Ok(byte_size) => {
             let byte_size = byte_size.as_u64();
             if byte_size == 0 {
-                None
+                Ok(None)
             } else {
-                Some(Dataset {
+                Ok(Some(Dataset {
                     byte_size,
                     format_size: size.to_string(),
                     name: chunks[0].to_string(),
-                })
+                    snapshot_bytes,
+                }))
             }
         }
-        Err(e) => {
-            eprintln!("ERROR: failed to parse '{}': {}", line, e);
-            None
-        }
+        Err(e) => Err(format!("failed to parse '{}': {}", line, e)),
     }
 }
 
-fn parse_list_output(output: Output) -> Result<Vec<Dataset>, FromUtf8Error> {
+// Returns the datasets, and the number of lines which could not be parsed.
+fn parse_list_output(output: Output) -> Result<(Vec<Dataset>, usize), FromUtf8Error> {
     let stdout_string = String::from_utf8(output.stdout)?;
+    Ok(parse_lines(&stdout_string))
+}
 
-    let mut non_zero_datasets: Vec<Dataset> = stdout_string
-        .lines()
-        .filter_map(parse_dataset_line)
-        .collect();
+// Bad lines are reported, and counted, but don't stop the rest being parsed.
+fn parse_lines(list: &str) -> (Vec<Dataset>, usize) {
+    let mut non_zero_datasets = Vec::new();
+    let mut failures = 0;
+
+    for line in list.lines() {
+        match parse_dataset_line(line) {
+            Ok(Some(dataset)) => non_zero_datasets.push(dataset),
+            Ok(None) => (),
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                failures += 1;
+            }
+        }
+    }
 
     non_zero_datasets.sort_by_key(|dataset| dataset.byte_size);
-    Ok(non_zero_datasets)
+    (non_zero_datasets, failures)
 }
 
-fn display_list(sorted_dataset_list: Vec<Dataset>) {
+fn display_list(sorted_dataset_list: &[Dataset], mark_heavy: bool) {
     for dataset in sorted_dataset_list {
-        println!("  {:>6}  {}", dataset.format_size, dataset.name);
+        let marker = if mark_heavy && is_snapshot_heavy(dataset) {
+            '!'
+        } else {
+            ' '
+        };
+
+        println!("{} {:>6}  {}", marker, dataset.format_size, dataset.name);
     }
 }
 
-fn main() {
-    match list_dataset_usage() {
+// Each group keeps the order of the input list.
+fn group_by_pool(dataset_list: Vec<Dataset>) -> BTreeMap<String, Vec<Dataset>> {
+    let mut ret: BTreeMap<String, Vec<Dataset>> = BTreeMap::new();
+
+    for dataset in dataset_list {
+        ret.entry(pool::name_of(&dataset.name).to_string())
+            .or_default()
+            .push(dataset);
+    }
+
+    ret
+}
+
+fn subtotal(dataset_list: &[Dataset]) -> u64 {
+    dataset_list.iter().map(|dataset| dataset.byte_size).sum()
+}
+
+// Formats a size in the same style as zfs, e.g. 6.05G.
+fn format_bytes(bytes: u64) -> String {
+    let adjusted = Byte::from_u64(bytes).get_appropriate_unit(UnitType::Decimal);
+    let unit = adjusted.get_unit().to_string();
+
+    match unit.chars().next() {
+        Some(prefix) if unit != "B" => format!("{:.2}{}", adjusted.get_value(), prefix),
+        _ => format!("{}B", bytes),
+    }
+}
+
+fn display_by_pool(sorted_dataset_list: Vec<Dataset>, mark_heavy: bool) {
+    let mut grand_total = 0;
+
+    for (pool, dataset_list) in group_by_pool(sorted_dataset_list) {
+        let pool_total = subtotal(&dataset_list);
+        grand_total += pool_total;
+        display_list(&dataset_list, mark_heavy);
+        println!("  {:>6}  {} (subtotal)\n", format_bytes(pool_total), pool);
+    }
+
+    println!("  {:>6}  (total)", format_bytes(grand_total));
+}
+
+// Prints the report once, and returns the exit code.
+fn report(cli: &Cli) -> i32 {
+    match list_dataset_usage(cli.object_type) {
         Ok(output) => match parse_list_output(output) {
-            Ok(parsed_list) => display_list(parsed_list),
+            Ok((parsed_list, failures)) => {
+                let parsed_list = match cli.min_size {
+                    Some(min_size) => drop_smaller_than(parsed_list, min_size),
+                    None => parsed_list,
+                };
+
+                if cli.by_pool {
+                    display_by_pool(parsed_list, cli.snapshot_heavy)
+                } else {
+                    display_list(&parsed_list, cli.snapshot_heavy)
+                }
+
+                if failures > 0 {
+                    eprintln!("ERROR: {} lines could not be parsed", failures);
+                    3
+                } else {
+                    0
+                }
+            }
             Err(e) => {
                 eprintln!("ERROR: failed to parse dataset list: {}", e);
-                exit(2);
+                2
             }
         },
         Err(e) => {
             eprintln!("ERROR: failed to list datasets: {}", e);
-            exit(1);
+            1
         }
     }
 }
 
+// Runs until interrupted. Nothing is held open between reports, so Ctrl-C's default action is a
+// clean exit. A bad line or two shouldn't stop the monitor, but not being able to run zfs should.
+fn watch(cli: &Cli, interval: Duration) -> ! {
+    loop {
+        print!("{}", CLEAR_SCREEN);
+        println!("Every {}s\n", interval.as_secs());
+
+        let code = report(cli);
+
+        if code == 1 {
+            exit(code);
+        }
+
+        let _ = io::stdout().flush();
+        thread::sleep(interval);
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    match cli.watch {
+        Some(interval) => watch(&cli, interval),
+        None => exit(report(&cli)),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn dataset(name: &str, byte_size: u64) -> Dataset {
+        Dataset {
+            byte_size,
+            format_size: format_bytes(byte_size),
+            name: name.to_string(),
+            snapshot_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn test_is_snapshot_heavy() {
+        let heavy = Dataset {
+            snapshot_bytes: 3_000_000_000,
+            ..dataset("rpool/logs", 1_000_000_000)
+        };
+
+        let light = Dataset {
+            snapshot_bytes: 200_000_000,
+            ..dataset("rpool/home", 1_000_000_000)
+        };
+
+        let even = Dataset {
+            snapshot_bytes: 1_000_000_000,
+            ..dataset("rpool/even", 1_000_000_000)
+        };
+
+        assert!(is_snapshot_heavy(&heavy));
+        assert!(!is_snapshot_heavy(&light));
+        assert!(!is_snapshot_heavy(&even));
+        assert!(!is_snapshot_heavy(&dataset("rpool@monday", 100)));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(Ok(500_000_000), parse_size("500M"));
+        assert_eq!(Ok(1_500_000_000), parse_size("1.5G"));
+        assert_eq!(Ok(512), parse_size("512"));
+        assert!(parse_size("lots").is_err());
+    }
+
+    #[test]
+    fn test_type_flag() {
+        let cli = Cli::parse_from(["zfs-real-usage", "-t", "snapshot"]);
+        assert_eq!(ObjectType::Snapshot, cli.object_type);
+
+        let cli = Cli::parse_from(["zfs-real-usage", "--type", "volume"]);
+        assert_eq!(ObjectType::Volume, cli.object_type);
+
+        assert_eq!(
+            ObjectType::All,
+            Cli::parse_from(["zfs-real-usage"]).object_type
+        );
+    }
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(Ok(Duration::from_secs(10)), parse_interval("10s"));
+        assert_eq!(Ok(Duration::from_secs(120)), parse_interval("2m"));
+        assert_eq!(
+            Err("interval '0s' must be longer than zero".to_string()),
+            parse_interval("0s")
+        );
+        assert!(parse_interval("10").is_err());
+        assert!(parse_interval("soon").is_err());
+        assert!(parse_interval("").is_err());
+    }
+
+    #[test]
+    fn test_drop_smaller_than() {
+        let dataset_list = vec![
+            dataset("rpool/tiny", 12_000),
+            dataset("rpool/zones", 499_999_999),
+            dataset("rpool/home", 500_000_000),
+            dataset("fast/build", 6_050_000_000),
+        ];
+
+        let min_size = parse_size("500M").unwrap();
+
+        assert_eq!(
+            vec!["rpool/home", "fast/build"],
+            drop_smaller_than(dataset_list, min_size)
+                .iter()
+                .map(|d| d.name.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_group_by_pool() {
+        let dataset_list = vec![
+            dataset("rpool/zones", 100),
+            dataset("fast/zone/build", 200),
+            dataset("rpool@monday", 300),
+            dataset("fast", 400),
+            dataset("rpool/ROOT", 500),
+        ];
+
+        let groups = group_by_pool(dataset_list);
+
+        assert_eq!(vec!["fast", "rpool"], groups.keys().collect::<Vec<_>>());
+
+        assert_eq!(
+            vec!["fast/zone/build", "fast"],
+            groups["fast"]
+                .iter()
+                .map(|d| d.name.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec!["rpool/zones", "rpool@monday", "rpool/ROOT"],
+            groups["rpool"]
+                .iter()
+                .map(|d| d.name.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(600, subtotal(&groups["fast"]));
+        assert_eq!(900, subtotal(&groups["rpool"]));
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!("512B", format_bytes(512));
+        assert_eq!("6.05G", format_bytes(6_050_000_000));
+        assert_eq!("1.50M", format_bytes(1_500_000));
+    }
+
+    #[test]
+    fn test_type_arg() {
+        assert_eq!("filesystem", type_arg(ObjectType::Filesystem));
+        assert_eq!("snapshot", type_arg(ObjectType::Snapshot));
+        assert_eq!("volume", type_arg(ObjectType::Volume));
+        assert_eq!("all", type_arg(ObjectType::All));
+    }
+
     #[test]
     fn test_parse_line() {
         assert_eq!(
@@ -102,14 +430,51 @@ mod test {
                 byte_size: 6050000000_u64,
                 format_size: "6.05G".to_string(),
                 name: "rpool/zones/serv-build/ROOT/zbe-3".to_string(),
+                snapshot_bytes: 80000000_u64,
+            },
+            parse_dataset_line("rpool/zones/serv-build/ROOT/zbe-3\t6.13G\t6.05G\t80M")
+                .unwrap()
+                .unwrap()
+        );
+
+        assert_eq!(
+            Dataset {
+                byte_size: 1200000_u64,
+                format_size: "1.20M".to_string(),
+                name: "fast/zone/build@02:00".to_string(),
+                snapshot_bytes: 0,
             },
-            parse_dataset_line("rpool/zones/serv-build/ROOT/zbe-3       6.13G   6.05G").unwrap()
+            parse_dataset_line("fast/zone/build@02:00\t1.20M\t-\t-")
+                .unwrap()
+                .unwrap()
         );
 
         assert_eq!(
-            None,
-            parse_dataset_line("fast/zone/build@03:00   0B      -")
+            Ok(None),
+            parse_dataset_line("fast/zone/build@03:00\t0B\t-\t-")
         );
-        assert_eq!(None, parse_dataset_line(""));
+        assert!(parse_dataset_line("rpool\t6.13G\t6.05G").is_err());
+        assert!(parse_dataset_line("rpool\t6.13G\t6.05G\tlots").is_err());
+        assert!(parse_dataset_line("").is_err());
+    }
+
+    #[test]
+    fn test_parse_lines() {
+        let list = "rpool\t6.13G\t6.05G\t80M
+rpool/broken\t6.13G
+rpool/empty\t0B\t0B\t-
+rpool/small\t1.20M\t1.10M\t-
+rpool/bad_size\t1.20M\tbig\t-
+";
+
+        let (datasets, failures) = parse_lines(list);
+
+        assert_eq!(2, failures);
+        assert_eq!(
+            vec!["rpool/small", "rpool"],
+            datasets.iter().map(|d| d.name.as_str()).collect::<Vec<_>>()
+        );
+
+        assert_eq!((Vec::new(), 0), parse_lines(""));
     }
 }
@@ -1,11 +1,14 @@
 use anyhow::anyhow;
-use clap::Parser;
-use common::command_helpers::format_command;
+use clap::{ArgAction, Parser};
+use common::command_helpers::run_action;
 use common::constants::ZFS;
-use common::rules::omit_rules_match;
-use common::types::{ArgList, Opts, SnapshotList, SnapshotResult};
-use common::{zfs_file, zfs_info};
+use common::rules::CompiledRules;
+use common::snapshot::Snapshot;
+use common::types::{ArgList, Filesystems, MountList, Opts, SnapshotList, SnapshotResult};
+use common::{dataset, duration, snapshot, zfs_file, zfs_info};
 use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, BufRead, Write};
 use std::process::{exit, Command};
 
 #[derive(Parser)]
@@ -14,18 +17,22 @@ struct Cli {
     /// Specifies that args are files: the snapshots containing these files will be destroyed
     #[clap(short, long)]
     files: bool,
-    /// purge ALL datasets with this name ANYWHERE in the hierarchy
+    /// purge ALL datasets with this name ANYWHERE in the hierarchy. The name may have several
+    /// segments, like web/logs
     #[clap(short = 'a', long = "all-datasets")]
     all: bool,
+    /// With -a, only match datasets somewhere below this one, e.g. a pool name
+    #[clap(short, long)]
+    under: Option<String>,
     /// Specifies that all args are snapshot names
     #[clap(short = 's', long = "snaps")]
     snaps: bool,
     /// Print what would happen, without doing it
     #[clap(short, long)]
     noop: bool,
-    /// Be verbose
-    #[clap(short, long)]
-    verbose: bool,
+    /// Be verbose. Give more than once for more detail
+    #[clap(short, long, action = ArgAction::Count)]
+    verbose: u8,
     /// Comma-separated list of filesystems from which snapshots should NOT be removed. Accepts * as a wildcard.
     #[clap(short = 'o', long)]
     omit_fs: Option<String>,
@@ -35,45 +42,127 @@ struct Cli {
     /// Recurse down dataset hierarchies
     #[clap(short, long)]
     recurse: bool,
+    /// Treat args as dataset patterns, expanding * as a wildcard, in the same way as --omit-fs
+    #[clap(short, long)]
+    glob: bool,
+    /// Remove every snapshot whose full name, like rpool/test@monday, matches this regular
+    /// expression
+    #[clap(long, value_parser = parse_pattern)]
+    pattern: Option<Regex>,
+    /// Read full snapshot names, one per line, from standard input
+    #[clap(long)]
+    stdin: bool,
+    /// Only remove snapshots created after this. Either a date, like 2024-10-27 or
+    /// "2024-10-27 09:45", or an age, like 3h
+    #[clap(long, value_parser = duration::parse_cutoff)]
+    newer_than: Option<i64>,
+    /// Only remove snapshots created before this. Takes the same values as --newer-than
+    #[clap(long, value_parser = duration::parse_cutoff)]
+    older_than: Option<i64>,
+    /// Defer destruction of snapshots which have clones until the clones are gone
+    #[clap(short, long)]
+    defer: bool,
+    /// Destroy the snapshots from START to END inclusive, as in 'zfs destroy dataset@START%END',
+    /// from each dataset. Both must exist, and START must not be newer than END
+    #[clap(long, value_name = "START%END")]
+    range: Option<String>,
+    /// Also print, by dataset, the snapshots which -o, -O, --newer-than or --older-than kept
+    #[clap(long)]
+    show_kept: bool,
     /// Dataset, snapshot, or directory name
     #[clap()]
     object: Vec<String>,
 }
 
 // If any removal fails, fail the whole lot.
-fn remove_snaps(list: SnapshotList, opts: Opts) -> anyhow::Result<()> {
+fn remove_snaps(list: SnapshotList, defer: bool, opts: Opts) -> anyhow::Result<()> {
     for snap in list {
         // Double check that we aren't going to remove a dataset
-        if !snap.contains("@") {
-            return Err(anyhow!("refusing to remove {}", snap));
-        }
+        snapshot::assert_is_snapshot(&snap)?;
 
         let mut cmd = Command::new(ZFS);
-        cmd.arg("destroy").arg(&snap);
+        cmd.arg("destroy");
 
-        if opts.verbose || opts.noop {
-            println!("{}", format_command(&cmd));
+        if defer {
+            cmd.arg("-d");
         }
 
-        if !opts.noop {
-            cmd.status()?;
+        cmd.arg(&snap);
+
+        if let Some(output) = run_action(&mut cmd, &opts)? {
+            if !output.status.success() {
+                return Err(destroy_error(
+                    &snap,
+                    &String::from_utf8_lossy(&output.stderr),
+                ));
+            }
         }
     }
 
     Ok(())
 }
 
+fn destroy_error(snap: &str, stderr: &str) -> anyhow::Error {
+    match dependent_clones(stderr) {
+        Some(clones) => anyhow!(
+            "{} has dependent clones: {}. Destroy or promote them first, or use 'zfs destroy -R' \
+             by hand. Alternatively, --defer will destroy the snapshot once the clones are gone",
+            snap,
+            clones.join(", ")
+        ),
+        None => anyhow!("{}: {}", snap, stderr.trim()),
+    }
+}
+
+// zfs lists the clones which stop a snapshot being destroyed, one per line, after its advice to
+// use -R.
+fn dependent_clones(stderr: &str) -> Option<Vec<String>> {
+    if !stderr.contains("snapshot has dependent clones") {
+        return None;
+    }
+
+    let clones = stderr
+        .lines()
+        .skip_while(|line| !line.starts_with("use '-R'"))
+        .skip(1)
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect();
+
+    Some(clones)
+}
+
 fn filter_list(snapshot_list: SnapshotList, omit_rules: &str, is_snapshot: bool) -> SnapshotList {
+    filter_list_warning_to(snapshot_list, omit_rules, is_snapshot, &mut io::stderr())
+}
+
+// Anything which isn't a snapshot is dropped, but we say so: it means something has gone wrong
+// upstream.
+fn filter_list_warning_to(
+    snapshot_list: SnapshotList,
+    omit_rules: &str,
+    is_snapshot: bool,
+    warnings: &mut impl Write,
+) -> SnapshotList {
     let rules: Vec<_> = omit_rules.split(',').map(|s| s.to_string()).collect();
+    let rules = CompiledRules::new(&rules);
 
     snapshot_list
         .into_iter()
         .filter(|f| {
-            if let Some((fs_name, snap_name)) = f.split_once("@") {
-                let item = if is_snapshot { snap_name } else { fs_name };
-                omit_rules_match(item, &rules)
+            let item = if is_snapshot {
+                Snapshot::parse(f).map(|snapshot| snapshot.name)
             } else {
-                false
+                snapshot::dataset_of_snapshot(f)
+            };
+
+            match item {
+                Some(item) => rules.keeps(&item),
+                None => {
+                    let _ = writeln!(warnings, "WARNING: ignoring {}: it is not a snapshot", f);
+                    false
+                }
             }
         })
         .collect()
@@ -107,24 +196,42 @@ fn snapshot_list_from_dataset_paths(dataset_list: &ArgList) -> SnapshotResult {
     Ok(ret)
 }
 
-// All snapshots whose dataset name (final part) is one of those given.
-fn snapshot_list_from_dataset_names(dataset_list: &ArgList) -> SnapshotResult {
+// All snapshots whose dataset name ends with one of those given. Names may be a single segment
+// like "logs", or a tail of several segments like "web/logs", and always match on whole segments.
+// If under is given, only datasets below it are considered. Otherwise a tail may also start at the
+// pool, so "logs" matches a pool called logs, and "tank/logs" matches tank/logs itself.
+fn snapshot_list_from_dataset_names(dataset_list: &ArgList, under: Option<&str>) -> SnapshotResult {
+    filter_by_dataset_tail(zfs_info::all_snapshots()?, dataset_list, under)
+}
+
+fn dataset_tail_pattern(tail: &str, under: Option<&str>) -> Result<Regex, regex::Error> {
+    let tail = regex::escape(tail.trim_matches('/'));
+
+    match under {
+        Some(under) => Regex::new(&format!(
+            r"^{}/(?:.*/)?{}@",
+            regex::escape(under.trim_matches('/')),
+            tail
+        )),
+        None => Regex::new(&format!(r"(?:^|/){}@", tail)),
+    }
+}
+
+fn filter_by_dataset_tail(
+    snapshot_list: SnapshotList,
+    dataset_list: &ArgList,
+    under: Option<&str>,
+) -> SnapshotResult {
     let patterns: Result<Vec<Regex>, _> = dataset_list
         .iter()
-        .map(|dataset| Regex::new(&format!(r"/{}@", regex::escape(dataset))))
+        .map(|dataset| dataset_tail_pattern(dataset, under))
         .collect();
 
     let patterns = patterns?;
 
-    let ret: SnapshotList = zfs_info::all_snapshots()?
-        .iter()
-        .filter_map(|line| {
-            if patterns.iter().any(|pattern| pattern.is_match(line)) {
-                Some(line.to_string())
-            } else {
-                None
-            }
-        })
+    let ret: SnapshotList = snapshot_list
+        .into_iter()
+        .filter(|line| patterns.iter().any(|pattern| pattern.is_match(line)))
         .collect();
 
     Ok(ret)
@@ -149,14 +256,150 @@ fn snapshot_list_from_snap_names(snaplist: &ArgList) -> SnapshotResult {
     Ok(ret)
 }
 
+// Keeps snapshots created strictly after newer_than, and strictly before older_than. Either bound
+// may be missing. Snapshots whose creation time we don't know are dropped: removing snapshots is
+// no place for guessing.
+fn filter_by_age(
+    snapshot_list: SnapshotList,
+    creation: &HashMap<String, i64>,
+    newer_than: Option<i64>,
+    older_than: Option<i64>,
+) -> SnapshotList {
+    snapshot_list
+        .into_iter()
+        .filter(|snap| match creation.get(snap) {
+            Some(created) => {
+                newer_than.is_none_or(|cutoff| *created > cutoff)
+                    && older_than.is_none_or(|cutoff| *created < cutoff)
+            }
+            None => false,
+        })
+        .collect()
+}
+
+// Runs the omit and age filters over the candidates. Returns the snapshots to remove, and those the
+// filters kept, both in their original order. The age filter only runs if we have creation times.
+fn apply_filters(
+    candidates: SnapshotList,
+    cli: &Cli,
+    creation: Option<&HashMap<String, i64>>,
+) -> (SnapshotList, SnapshotList) {
+    let mut remove = candidates.clone();
+
+    if let Some(omit_snaps) = &cli.omit_snaps {
+        remove = filter_by_snap_name(remove, omit_snaps);
+    }
+
+    if let Some(omit_fs) = &cli.omit_fs {
+        remove = filter_by_fs_name(remove, omit_fs);
+    }
+
+    if let Some(creation) = creation {
+        remove = filter_by_age(remove, creation, cli.newer_than, cli.older_than);
+    }
+
+    let removing: HashSet<&String> = remove.iter().collect();
+    let keep = candidates
+        .into_iter()
+        .filter(|snap| !removing.contains(snap))
+        .collect();
+
+    (remove, keep)
+}
+
+// Maps each dataset to the names of its snapshots in the list. Anything which isn't a snapshot has
+// already been warned about, so is left out.
+fn by_dataset(snapshot_list: &[String]) -> BTreeMap<String, Vec<String>> {
+    let mut ret: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for snap in snapshot_list
+        .iter()
+        .filter_map(|snap| Snapshot::parse(snap))
+    {
+        ret.entry(snap.dataset).or_default().push(snap.name);
+    }
+
+    ret
+}
+
+fn print_kept(kept: &[String]) {
+    for (dataset, snapnames) in by_dataset(kept) {
+        println!("Keeping {}: {}", dataset, snapnames.join(", "));
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| format!("invalid regular expression: {}", e))
+}
+
+// The pattern isn't anchored: use ^ and $ to match the whole name.
+fn filter_by_pattern(snapshot_list: SnapshotList, pattern: &Regex) -> SnapshotList {
+    snapshot_list
+        .into_iter()
+        .filter(|snap| pattern.is_match(snap))
+        .collect()
+}
+
+// Blank lines are skipped, so is surrounding whitespace.
+fn snapshot_list_from_reader<R: BufRead>(reader: R) -> SnapshotResult {
+    let mut ret = SnapshotList::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let snap = line.trim();
+
+        if !snap.is_empty() {
+            ret.push(snap.to_string());
+        }
+    }
+
+    Ok(ret)
+}
+
 fn snapshot_list(cli: &Cli) -> SnapshotResult {
-    let mut arg_list = cli.object.clone();
+    if cli.under.is_some() && !cli.all {
+        return Err(anyhow!("--under is only allowed with -a"));
+    }
+
+    if let Some(pattern) = &cli.pattern {
+        if !cli.object.is_empty() || cli.stdin {
+            return Err(anyhow!(
+                "--pattern is not allowed with arguments or --stdin"
+            ));
+        }
+
+        if cli.snaps || cli.all || cli.files || cli.recurse || cli.glob {
+            return Err(anyhow!(
+                "--pattern is not allowed with -s, -a, -f, -r, or -g"
+            ));
+        }
+
+        return Ok(filter_by_pattern(zfs_info::all_snapshots()?, pattern));
+    }
+
+    if cli.stdin {
+        if !cli.object.is_empty() {
+            return Err(anyhow!("--stdin is not allowed with arguments"));
+        }
+
+        if cli.snaps || cli.all || cli.files || cli.recurse || cli.glob {
+            return Err(anyhow!("--stdin is not allowed with -s, -a, -f, -r, or -g"));
+        }
+
+        return snapshot_list_from_reader(io::stdin().lock());
+    }
+
+    let arg_list = &cli.object;
+
+    if cli.glob && (cli.snaps || cli.all || cli.files) {
+        return Err(anyhow!("--glob is only allowed with dataset arguments"));
+    }
 
     if cli.snaps {
         if cli.recurse {
             return Err(anyhow!("-r is not allowed with snapshot arguments"));
         } else {
-            return snapshot_list_from_snap_names(&arg_list);
+            return snapshot_list_from_snap_names(arg_list);
         }
     }
 
@@ -164,31 +407,168 @@ fn snapshot_list(cli: &Cli) -> SnapshotResult {
         if cli.recurse {
             return Err(anyhow!("-r is not allowed with dataset name arguments"));
         } else {
-            return snapshot_list_from_dataset_names(&arg_list);
+            return snapshot_list_from_dataset_names(arg_list, cli.under.as_deref());
         }
     }
 
+    // Plain dataset arguments which don't exist would otherwise quietly match no snapshots.
+    if !cli.files && !cli.glob {
+        warn_missing_datasets(arg_list);
+    }
+
+    // Only ask zfs for what we need: both lists can be expensive.
+    let mounts = if cli.files {
+        zfs_info::get_mounted_filesystems()?
+    } else {
+        MountList::new()
+    };
+
+    let all_filesystems = if cli.glob || cli.recurse {
+        zfs_info::all_filesystems()?
+    } else {
+        Filesystems::new()
+    };
+
+    snapshot_list_from_dataset_paths(&dataset_list(cli, mounts, all_filesystems))
+}
+
+// Validates a START%END range against the snapshots of the dataset, which must be oldest first, as
+// snapshots_of() gives them. Returns what to hand to zfs destroy.
+fn range_target(dataset: &str, range: &str, snapshots: &[String]) -> anyhow::Result<String> {
+    let (start, end) = range
+        .split_once('%')
+        .filter(|(start, end)| !start.is_empty() && !end.is_empty() && !end.contains('%'))
+        .ok_or_else(|| anyhow!("'{}' is not a range. Use START%END", range))?;
+
+    let position = |snapname: &str| {
+        let name = format!("{}@{}", dataset, snapname);
+        snapshots
+            .iter()
+            .position(|snap| *snap == name)
+            .ok_or_else(|| anyhow!("{} does not exist", name))
+    };
+
+    if position(start)? > position(end)? {
+        return Err(anyhow!(
+            "{}@{} is newer than {}@{}",
+            dataset,
+            start,
+            dataset,
+            end
+        ));
+    }
+
+    Ok(format!("{}@{}%{}", dataset, start, end))
+}
+
+// A range is given straight to zfs, so the filters which work on lists of snapshots can't be used.
+fn range_list(cli: &Cli, range: &str) -> SnapshotResult {
+    if cli.snaps || cli.all || cli.stdin || cli.pattern.is_some() {
+        return Err(anyhow!("--range is only allowed with dataset arguments"));
+    }
+
+    if cli.omit_snaps.is_some()
+        || cli.omit_fs.is_some()
+        || cli.newer_than.is_some()
+        || cli.older_than.is_some()
+    {
+        return Err(anyhow!(
+            "--range is not allowed with -o, -O, --newer-than or --older-than"
+        ));
+    }
+
+    let mounts = if cli.files {
+        zfs_info::get_mounted_filesystems()?
+    } else {
+        MountList::new()
+    };
+
+    let all_filesystems = if cli.glob || cli.recurse {
+        zfs_info::all_filesystems()?
+    } else {
+        Filesystems::new()
+    };
+
+    dataset_list(cli, mounts, all_filesystems)
+        .iter()
+        .map(|dataset| range_target(dataset, range, &zfs_info::snapshots_of(dataset, false)?))
+        .collect()
+}
+
+fn warn_missing_datasets(datasets: &ArgList) {
+    match zfs_info::missing_datasets(datasets) {
+        Ok(missing) => missing
+            .iter()
+            .for_each(|dataset| eprintln!("WARNING: dataset {} does not exist", dataset)),
+        Err(e) => eprintln!("WARNING: could not check datasets exist: {}", e),
+    }
+}
+
+// Turns file, pattern, or dataset arguments into a list of datasets. Files are mapped to their
+// datasets first, so -r recurses from there.
+fn dataset_list(cli: &Cli, mounts: MountList, all_filesystems: Filesystems) -> ArgList {
+    let mut arg_list = cli.object.clone();
+
     if cli.files {
-        let mounts = zfs_info::get_mounted_filesystems()?;
         arg_list = zfs_file::files_to_datasets(&arg_list, mounts);
     }
 
+    if cli.glob {
+        arg_list = zfs_info::expand_dataset_globs(&arg_list, &all_filesystems);
+    }
+
     if cli.recurse {
-        let all_filesystems = zfs_info::all_filesystems()?;
         arg_list = zfs_info::dataset_list_recursive(arg_list, all_filesystems);
     }
 
-    snapshot_list_from_dataset_paths(&arg_list)
+    arg_list
+}
+
+// Tidies dataset names given as arguments, and to --omit-fs. Snapshot names and files are not
+// dataset names, and -a takes the tails of names, which may start with a slash.
+fn normalize_datasets(cli: &mut Cli) -> anyhow::Result<()> {
+    if !cli.snaps && !cli.files && !cli.all {
+        cli.object = dataset::normalize_all(&cli.object)?;
+    }
+
+    if let Some(omit_fs) = &cli.omit_fs {
+        cli.omit_fs = Some(dataset::normalize_rules(omit_fs)?);
+    }
+
+    Ok(())
 }
 
 fn main() {
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+
+    if let Err(e) = normalize_datasets(&mut cli) {
+        eprintln!("ERROR: invalid dataset: {}", e);
+        exit(1);
+    }
+
     let opts = Opts {
         verbose: cli.verbose,
         noop: cli.noop,
     };
 
-    let mut snapshot_list = match snapshot_list(&cli) {
+    if let Some(range) = &cli.range {
+        let range_list = match range_list(&cli, range) {
+            Ok(list) => list,
+            Err(e) => {
+                eprintln!("ERROR: could not work out range: {}", e);
+                exit(1);
+            }
+        };
+
+        if let Err(err) = remove_snaps(range_list, cli.defer, opts) {
+            eprintln!("ERROR: could not remove snapshot range: {}", err);
+            exit(1);
+        }
+
+        exit(0);
+    }
+
+    let candidates = match snapshot_list(&cli) {
         Ok(list) => list,
         Err(e) => {
             eprintln!("ERROR: could not generate snapshot list: {}", e);
@@ -196,12 +576,22 @@ fn main() {
         }
     };
 
-    if let Some(omit_snaps) = cli.omit_snaps {
-        snapshot_list = filter_by_snap_name(snapshot_list, &omit_snaps);
-    }
+    let creation = if cli.newer_than.is_some() || cli.older_than.is_some() {
+        match zfs_info::all_snapshot_creation() {
+            Ok(creation) => Some(creation),
+            Err(e) => {
+                eprintln!("ERROR: could not get snapshot creation times: {}", e);
+                exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let (snapshot_list, kept) = apply_filters(candidates, &cli, creation.as_ref());
 
-    if let Some(omit_fs) = cli.omit_fs {
-        snapshot_list = filter_by_fs_name(snapshot_list, &omit_fs);
+    if cli.show_kept {
+        print_kept(&kept);
     }
 
     if snapshot_list.is_empty() {
@@ -209,14 +599,330 @@ fn main() {
         exit(0);
     }
 
-    if let Err(err) = remove_snaps(snapshot_list, opts) {
+    if let Err(err) = remove_snaps(snapshot_list, cli.defer, opts) {
         eprintln!("ERROR: could not remove snapshot: {}", err);
+        exit(1);
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use common::types::Mount;
+
+    #[test]
+    fn test_normalize_datasets() {
+        let mut cli = Cli::parse_from(["zfs-remove-snaps", "-o", "rpool/keep/,tank/ ", "rpool/"]);
+        normalize_datasets(&mut cli).unwrap();
+        assert_eq!(vec!["rpool".to_string()], cli.object);
+        assert_eq!(Some("rpool/keep,tank".to_string()), cli.omit_fs);
+
+        // -a takes tails, which may be given like paths.
+        let mut cli =
+            Cli::parse_from(["zfs-remove-snaps", "-a", "-o", "rpool/data/", "/web/logs/"]);
+        normalize_datasets(&mut cli).unwrap();
+        assert_eq!(vec!["/web/logs/".to_string()], cli.object);
+        assert_eq!(Some("rpool/data".to_string()), cli.omit_fs);
+
+        let mut cli = Cli::parse_from(["zfs-remove-snaps", "-o", "/rpool/data", "rpool"]);
+        assert!(normalize_datasets(&mut cli).is_err());
+    }
+
+    #[test]
+    fn test_filter_by_age() {
+        let input: SnapshotList = vec![
+            "rpool/test@09:00".to_string(),
+            "rpool/test@13:00".to_string(),
+            "rpool/test@14:00".to_string(),
+            "rpool/test@15:00".to_string(),
+            "rpool/test@unknown".to_string(),
+        ];
+
+        let creation: HashMap<String, i64> = [
+            ("rpool/test@09:00", 1730106000),
+            ("rpool/test@13:00", 1730120400),
+            ("rpool/test@14:00", 1730124000),
+            ("rpool/test@15:00", 1730127600),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), *v))
+        .collect();
+
+        assert_eq!(
+            vec![
+                "rpool/test@14:00".to_string(),
+                "rpool/test@15:00".to_string()
+            ],
+            filter_by_age(input.clone(), &creation, Some(1730120400), None)
+        );
+
+        assert_eq!(
+            vec!["rpool/test@09:00".to_string()],
+            filter_by_age(input.clone(), &creation, None, Some(1730120400))
+        );
+
+        assert_eq!(
+            vec![
+                "rpool/test@13:00".to_string(),
+                "rpool/test@14:00".to_string()
+            ],
+            filter_by_age(input.clone(), &creation, Some(1730110000), Some(1730127600))
+        );
+
+        assert!(
+            filter_by_age(input.clone(), &creation, Some(1730127600), Some(1730106000)).is_empty()
+        );
+
+        assert_eq!(4, filter_by_age(input, &creation, None, None).len());
+    }
+
+    #[test]
+    fn test_apply_filters() {
+        let candidates: SnapshotList = [
+            "rpool/test@monday",
+            "rpool/test@tuesday",
+            "rpool/test@ancient",
+            "rpool/keep@monday",
+            "tank/test@tuesday",
+            "tank/test@unknown",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let creation: HashMap<String, i64> = [
+            ("rpool/test@monday", 100),
+            ("rpool/test@tuesday", 200),
+            ("rpool/test@ancient", 10),
+            ("rpool/keep@monday", 100),
+            ("tank/test@tuesday", 200),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), *v))
+        .collect();
+
+        let cli = Cli::parse_from(["zfs-remove-snaps", "-O", "monday", "rpool/test"]);
+        let (remove, keep) = apply_filters(candidates.clone(), &cli, None);
+
+        assert_eq!(
+            vec![
+                "rpool/test@tuesday".to_string(),
+                "rpool/test@ancient".to_string(),
+                "tank/test@tuesday".to_string(),
+                "tank/test@unknown".to_string(),
+            ],
+            remove
+        );
+        assert_eq!(
+            vec![
+                "rpool/test@monday".to_string(),
+                "rpool/keep@monday".to_string(),
+            ],
+            keep
+        );
+
+        // Real cutoffs are relative to now, so set one by hand.
+        let mut cli = Cli::parse_from(["zfs-remove-snaps", "-o", "rpool/keep", "rpool/test"]);
+        cli.newer_than = Some(50);
+        let (remove, keep) = apply_filters(candidates.clone(), &cli, Some(&creation));
+
+        assert_eq!(
+            vec![
+                "rpool/test@monday".to_string(),
+                "rpool/test@tuesday".to_string(),
+                "tank/test@tuesday".to_string(),
+            ],
+            remove
+        );
+        assert_eq!(
+            vec![
+                "rpool/test@ancient".to_string(),
+                "rpool/keep@monday".to_string(),
+                "tank/test@unknown".to_string(),
+            ],
+            keep
+        );
+
+        let cli = Cli::parse_from(["zfs-remove-snaps", "rpool/test"]);
+        let (remove, keep) = apply_filters(candidates.clone(), &cli, None);
+        assert_eq!(candidates, remove);
+        assert!(keep.is_empty());
+    }
+
+    #[test]
+    fn test_by_dataset() {
+        let kept: Vec<String> = [
+            "tank/test@tuesday",
+            "rpool/test@monday",
+            "not-a-snapshot",
+            "rpool/test@ancient",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let grouped = by_dataset(&kept);
+
+        assert_eq!(
+            vec!["rpool/test", "tank/test"],
+            grouped.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["monday".to_string(), "ancient".to_string()],
+            grouped["rpool/test"]
+        );
+        assert_eq!(vec!["tuesday".to_string()], grouped["tank/test"]);
+        assert!(by_dataset(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_range_target() {
+        let snapshots: Vec<String> = ["monday", "tuesday", "wednesday", "thursday"]
+            .iter()
+            .map(|snap| format!("rpool/test@{}", snap))
+            .collect();
+
+        assert_eq!(
+            "rpool/test@tuesday%thursday",
+            range_target("rpool/test", "tuesday%thursday", &snapshots).unwrap()
+        );
+
+        assert_eq!(
+            "rpool/test@monday%monday",
+            range_target("rpool/test", "monday%monday", &snapshots).unwrap()
+        );
+
+        assert_eq!(
+            "rpool/test@friday does not exist",
+            range_target("rpool/test", "monday%friday", &snapshots)
+                .unwrap_err()
+                .to_string()
+        );
+
+        assert_eq!(
+            "rpool/test@sunday does not exist",
+            range_target("rpool/test", "sunday%monday", &snapshots)
+                .unwrap_err()
+                .to_string()
+        );
+
+        assert_eq!(
+            "rpool/test@thursday is newer than rpool/test@tuesday",
+            range_target("rpool/test", "thursday%tuesday", &snapshots)
+                .unwrap_err()
+                .to_string()
+        );
+
+        for bad in ["monday", "%monday", "monday%", "monday%tuesday%wednesday"] {
+            assert!(range_target("rpool/test", bad, &snapshots).is_err());
+        }
+    }
+
+    #[test]
+    fn test_filter_by_pattern() {
+        let input: SnapshotList = [
+            "rpool/backup/db@2023-01-01",
+            "rpool/backup/db@2024-01-01",
+            "rpool/backup@2023-06-30",
+            "tank/backup/web@2023-12-31",
+            "rpool/test@2023-01-01",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        assert_eq!(
+            vec![
+                "rpool/backup/db@2023-01-01".to_string(),
+                "tank/backup/web@2023-12-31".to_string(),
+            ],
+            filter_by_pattern(input.clone(), &parse_pattern("backup/.*@2023-.*").unwrap())
+        );
+
+        assert_eq!(
+            vec!["rpool/backup@2023-06-30".to_string()],
+            filter_by_pattern(
+                input.clone(),
+                &parse_pattern(r"^rpool/backup@\d{4}").unwrap()
+            )
+        );
+
+        assert!(filter_by_pattern(input, &parse_pattern("@monday$").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_parse_pattern() {
+        assert!(parse_pattern("backup/.*@2023-.*").is_ok());
+        assert!(parse_pattern("(unclosed")
+            .unwrap_err()
+            .starts_with("invalid regular expression: "));
+    }
+
+    #[test]
+    fn test_dependent_clones() {
+        let stderr = "cannot destroy 'rpool/test@monday': snapshot has dependent clones
+use '-R' to destroy the following datasets:
+rpool/test_clone
+rpool/other/clone
+";
+
+        assert_eq!(
+            Some(vec![
+                "rpool/test_clone".to_string(),
+                "rpool/other/clone".to_string()
+            ]),
+            dependent_clones(stderr)
+        );
+
+        assert_eq!(
+            None,
+            dependent_clones("could not find any snapshots to destroy; check snapshot names.")
+        );
+
+        assert_eq!(
+            Some(Vec::new()),
+            dependent_clones("cannot destroy 'rpool/test@monday': snapshot has dependent clones")
+        );
+    }
+
+    #[test]
+    fn test_destroy_error() {
+        let stderr = "cannot destroy 'rpool/test@monday': snapshot has dependent clones
+use '-R' to destroy the following datasets:
+rpool/test_clone
+";
+
+        assert!(destroy_error("rpool/test@monday", stderr)
+            .to_string()
+            .starts_with("rpool/test@monday has dependent clones: rpool/test_clone. "));
+
+        assert_eq!(
+            "rpool/test@monday: dataset is busy",
+            destroy_error("rpool/test@monday", "dataset is busy\n").to_string()
+        );
+    }
+
+    #[test]
+    fn test_filter_list_warns_about_non_snapshots() {
+        let input: SnapshotList = vec![
+            "rpool/test@snap1".to_string(),
+            "rpool/broken".to_string(),
+            "rpool/keep@snap2".to_string(),
+        ];
+
+        for is_snapshot in [true, false] {
+            let mut warnings = Vec::new();
+
+            assert_eq!(
+                vec!["rpool/test@snap1".to_string()],
+                filter_list_warning_to(input.clone(), "*keep,snap2", is_snapshot, &mut warnings)
+            );
+            assert_eq!(
+                "WARNING: ignoring rpool/broken: it is not a snapshot\n",
+                String::from_utf8(warnings).unwrap()
+            );
+        }
+    }
 
     #[test]
     fn test_filter_by_snap_name() {
@@ -292,4 +998,172 @@ mod test {
 
         assert_eq!(input, filter_by_fs_name(input.clone(), "snap"));
     }
+
+    #[test]
+    fn test_dataset_list_files_and_recurse() {
+        let mounts = vec![
+            Mount::new("/build/configs", "fast/zone/build/config"),
+            Mount::new("/build", "fast/zone/build/build"),
+            Mount::new("/rpool", "rpool"),
+        ];
+
+        let all_filesystems = vec![
+            "fast/zone/build/build".to_string(),
+            "fast/zone/build/build/cache".to_string(),
+            "fast/zone/build/build/cache/deep".to_string(),
+            "fast/zone/build/config".to_string(),
+            "rpool".to_string(),
+        ];
+
+        let cli = Cli::parse_from(["zfs-remove-snaps", "-f", "-r", "/build/file"]);
+        let mut actual = dataset_list(&cli, mounts.clone(), all_filesystems.clone());
+        actual.sort();
+
+        assert_eq!(
+            vec![
+                "fast/zone/build/build".to_string(),
+                "fast/zone/build/build/cache".to_string(),
+                "fast/zone/build/build/cache/deep".to_string(),
+            ],
+            actual
+        );
+
+        let cli = Cli::parse_from(["zfs-remove-snaps", "-f", "/build/file"]);
+
+        assert_eq!(
+            vec!["fast/zone/build/build".to_string()],
+            dataset_list(&cli, mounts, all_filesystems)
+        );
+    }
+
+    #[test]
+    fn test_snapshot_list_from_reader() {
+        let input = "rpool/test@monday\n\n  rpool/test@tuesday  \n\t\n tank@snap\n";
+
+        assert_eq!(
+            vec![
+                "rpool/test@monday".to_string(),
+                "rpool/test@tuesday".to_string(),
+                "tank@snap".to_string(),
+            ],
+            snapshot_list_from_reader(input.as_bytes()).unwrap()
+        );
+
+        assert!(snapshot_list_from_reader("".as_bytes()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_filter_by_dataset_tail() {
+        let input: SnapshotList = vec![
+            "rpool/web/logs@monday".to_string(),
+            "rpool/db/logs@monday".to_string(),
+            "tank/web/logs@tuesday".to_string(),
+            "rpool/catalogs@monday".to_string(),
+            "rpool/logs/web@monday".to_string(),
+            "logs@monday".to_string(),
+            "rpool/newweb/logs@monday".to_string(),
+        ];
+
+        let expected1: SnapshotList = vec![
+            "rpool/web/logs@monday".to_string(),
+            "rpool/db/logs@monday".to_string(),
+            "tank/web/logs@tuesday".to_string(),
+            "logs@monday".to_string(),
+            "rpool/newweb/logs@monday".to_string(),
+        ];
+
+        assert_eq!(
+            expected1,
+            filter_by_dataset_tail(input.clone(), &vec!["logs".to_string()], None).unwrap()
+        );
+
+        let expected2: SnapshotList = vec![
+            "rpool/web/logs@monday".to_string(),
+            "tank/web/logs@tuesday".to_string(),
+        ];
+
+        assert_eq!(
+            expected2,
+            filter_by_dataset_tail(input.clone(), &vec!["web/logs".to_string()], None).unwrap()
+        );
+
+        assert_eq!(
+            expected2,
+            filter_by_dataset_tail(input.clone(), &vec!["/web/logs/".to_string()], None).unwrap()
+        );
+
+        assert!(
+            filter_by_dataset_tail(input, &vec!["alogs".to_string()], None)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_filter_by_dataset_tail_from_pool_root() {
+        let input: SnapshotList = vec![
+            "logs@monday".to_string(),
+            "logs/web@monday".to_string(),
+            "tank/logs@monday".to_string(),
+            "rpool/tank/logs@monday".to_string(),
+            "xtank/logs@monday".to_string(),
+        ];
+
+        assert_eq!(
+            vec![
+                "logs@monday".to_string(),
+                "tank/logs@monday".to_string(),
+                "rpool/tank/logs@monday".to_string(),
+                "xtank/logs@monday".to_string()
+            ],
+            filter_by_dataset_tail(input.clone(), &vec!["logs".to_string()], None).unwrap()
+        );
+
+        assert_eq!(
+            vec![
+                "tank/logs@monday".to_string(),
+                "rpool/tank/logs@monday".to_string()
+            ],
+            filter_by_dataset_tail(input, &vec!["tank/logs".to_string()], None).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_filter_by_dataset_tail_under() {
+        let input: SnapshotList = vec![
+            "rpool/logs@monday".to_string(),
+            "rpool/web/logs@monday".to_string(),
+            "fast/logs@monday".to_string(),
+            "fast/rpool/logs@monday".to_string(),
+            "rpoolx/logs@monday".to_string(),
+            "rpool/web/logs/old@monday".to_string(),
+            "logs@monday".to_string(),
+        ];
+
+        let expected: SnapshotList = vec![
+            "rpool/logs@monday".to_string(),
+            "rpool/web/logs@monday".to_string(),
+        ];
+
+        assert_eq!(
+            expected,
+            filter_by_dataset_tail(input.clone(), &vec!["logs".to_string()], Some("rpool"))
+                .unwrap()
+        );
+
+        assert_eq!(
+            vec!["rpool/web/logs@monday".to_string()],
+            filter_by_dataset_tail(input, &vec!["logs".to_string()], Some("rpool/web/")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_under_needs_all_datasets() {
+        let cli = Cli::parse_from(["zfs-remove-snaps", "--under", "rpool", "rpool/logs"]);
+
+        assert_eq!(
+            "--under is only allowed with -a",
+            snapshot_list(&cli).unwrap_err().to_string()
+        );
+    }
 }
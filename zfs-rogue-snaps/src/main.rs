@@ -1,13 +1,38 @@
+use clap::Parser;
+use common::rules::CompiledRules;
 use common::zfs_info;
 use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-fn filter_fn(snapshot: &String, expected: &[String], regex: &Regex) -> Option<String> {
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+const MONTH: i64 = 30 * DAY;
+const YEAR: i64 = 365 * DAY;
+
+#[derive(Parser)]
+#[clap(version, about = "Finds snapshots which do not fit the naming scheme", long_about = None)]
+struct Cli {
+    /// File of expected snapshot names, one per line. Accepts * as a wildcard. Replaces the
+    /// built-in day and month names
+    #[clap(short, long)]
+    rules: Option<PathBuf>,
+    /// Show how long ago each rogue snapshot was taken, and list the oldest first
+    #[clap(short, long)]
+    age: bool,
+}
+
+fn filter_fn(snapshot: &String, expected: &CompiledRules, regex: &Regex) -> Option<String> {
     if let Some((fs, snap)) = snapshot.split_once("@") {
         if !fs.starts_with("rpool/VARSHARE/zones")
             && !fs.starts_with("rpool/ROOT")
             && snap != "initial"
             && !(regex.is_match(snap))
-            && !(expected.iter().any(|x| x == snap))
+            && expected.keeps(snap)
         {
             return Some(snapshot.to_string());
         }
@@ -17,14 +42,28 @@ fn filter_fn(snapshot: &String, expected: &[String], regex: &Regex) -> Option<St
 
 fn find_rogue_snapshots(snapshot_list: Vec<String>, expected_list: &[String]) -> Vec<String> {
     let regex = Regex::new(r"^[012]\d:[0-5]\d$").expect("invalid regex");
+    let expected = CompiledRules::new(expected_list);
     snapshot_list
         .into_iter()
-        .filter_map(|snap| filter_fn(&snap, expected_list, &regex))
+        .filter_map(|snap| filter_fn(&snap, &expected, &regex))
         .collect()
 }
 
-fn main() {
-    let defaults: Vec<String> = vec![
+// Blank lines and lines beginning with # are ignored.
+fn parse_rules(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+fn load_rules(file: &Path) -> io::Result<Vec<String>> {
+    Ok(parse_rules(&fs::read_to_string(file)?))
+}
+
+fn default_rules() -> Vec<String> {
+    vec![
         "monday".to_string(),
         "tuesday".to_string(),
         "wednesday".to_string(),
@@ -44,7 +83,22 @@ fn main() {
         "october".to_string(),
         "november".to_string(),
         "december".to_string(),
-    ];
+    ]
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let expected = match cli.rules {
+        Some(file) => match load_rules(&file) {
+            Ok(rules) => rules,
+            Err(e) => {
+                eprintln!("Failed to read rules from {}: {}", file.display(), e);
+                std::process::exit(2);
+            }
+        },
+        None => default_rules(),
+    };
 
     let all_snapshots = match zfs_info::all_snapshots() {
         Ok(list) => list,
@@ -54,8 +108,26 @@ fn main() {
         }
     };
 
-    let rogues = find_rogue_snapshots(all_snapshots, &defaults);
-    print_rogues(rogues);
+    let rogues = find_rogue_snapshots(all_snapshots, &expected);
+
+    if cli.age {
+        let creation = match zfs_info::all_snapshot_creation() {
+            Ok(map) => map,
+            Err(e) => {
+                eprintln!("Failed to get snapshot creation times: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+
+        print_rogues_with_age(oldest_first(rogues, &creation), &creation, now);
+    } else {
+        print_rogues(rogues);
+    }
 }
 
 fn print_rogues(snaps: Vec<String>) {
@@ -64,9 +136,60 @@ fn print_rogues(snaps: Vec<String>) {
     }
 }
 
+fn print_rogues_with_age(snaps: Vec<String>, creation: &HashMap<String, i64>, now: i64) {
+    let width = snaps.iter().map(|snap| snap.len()).max().unwrap_or(0);
+
+    for snap in snaps {
+        let age = match creation.get(&snap) {
+            Some(created) => format_age(*created, now),
+            None => "unknown".to_string(),
+        };
+
+        println!("{:<width$}  {}", snap, age, width = width);
+    }
+}
+
+// Snapshots whose creation time we don't know go last, keeping their order.
+fn oldest_first(mut snaps: Vec<String>, creation: &HashMap<String, i64>) -> Vec<String> {
+    snaps.sort_by_key(|snap| creation.get(snap).copied().unwrap_or(i64::MAX));
+    snaps
+}
+
+// Describes the time between created and now, both in seconds since the epoch, in the largest
+// whole unit, like "3 months ago".
+fn format_age(created: i64, now: i64) -> String {
+    let age = now - created;
+
+    if age < 0 {
+        return "in the future".to_string();
+    }
+
+    if age < MINUTE {
+        return "just now".to_string();
+    }
+
+    let (count, unit) = [
+        (YEAR, "year"),
+        (MONTH, "month"),
+        (DAY, "day"),
+        (HOUR, "hour"),
+    ]
+    .into_iter()
+    .find(|(size, _)| age >= *size)
+    .map(|(size, unit)| (age / size, unit))
+    .unwrap_or((age / MINUTE, "minute"));
+
+    if count == 1 {
+        format!("1 {} ago", unit)
+    } else {
+        format!("{} {}s ago", count, unit)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use tempfile::tempdir;
 
     #[test]
     fn test_find_rogue_snapshots() {
@@ -91,4 +214,93 @@ mod test {
             find_rogue_snapshots(all_snapshots, &defaults)
         );
     }
+
+    #[test]
+    fn test_format_age() {
+        let now = 1_730_000_000;
+
+        assert_eq!("just now", format_age(now - 59, now));
+        assert_eq!("1 minute ago", format_age(now - 60, now));
+        assert_eq!("45 minutes ago", format_age(now - 45 * MINUTE - 10, now));
+        assert_eq!("1 hour ago", format_age(now - HOUR, now));
+        assert_eq!("23 hours ago", format_age(now - DAY + 1, now));
+        assert_eq!("6 days ago", format_age(now - 6 * DAY, now));
+        assert_eq!("3 months ago", format_age(now - 100 * DAY, now));
+        assert_eq!("2 years ago", format_age(now - 2 * YEAR - 5 * DAY, now));
+        assert_eq!("in the future", format_age(now + 10, now));
+    }
+
+    #[test]
+    fn test_oldest_first() {
+        let creation: HashMap<String, i64> = [
+            ("rpool@rogue".to_string(), 300),
+            ("tank@old".to_string(), 100),
+            ("rpool/test@middle".to_string(), 200),
+        ]
+        .into_iter()
+        .collect();
+
+        let snaps = vec![
+            "rpool@rogue".to_string(),
+            "tank/gone@unknown".to_string(),
+            "tank@old".to_string(),
+            "rpool/test@middle".to_string(),
+        ];
+
+        assert_eq!(
+            vec![
+                "tank@old".to_string(),
+                "rpool/test@middle".to_string(),
+                "rpool@rogue".to_string(),
+                "tank/gone@unknown".to_string(),
+            ],
+            oldest_first(snaps, &creation)
+        );
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        assert_eq!(
+            vec![
+                "monday".to_string(),
+                "release-*".to_string(),
+                "*-keep".to_string()
+            ],
+            parse_rules("# weekdays\nmonday\n\n  release-*  \n# projects\n*-keep\n")
+        );
+
+        assert!(parse_rules("").is_empty());
+    }
+
+    #[test]
+    fn test_rules_file_changes_rogues() {
+        let all_snapshots = vec![
+            "rpool@monday".to_string(),
+            "rpool@release-1.2".to_string(),
+            "rpool@backup-keep".to_string(),
+            "rpool@rogue".to_string(),
+            "rpool@12:00".to_string(),
+        ];
+
+        assert_eq!(
+            vec![
+                "rpool@release-1.2".to_string(),
+                "rpool@backup-keep".to_string(),
+                "rpool@rogue".to_string(),
+            ],
+            find_rogue_snapshots(all_snapshots.clone(), &default_rules())
+        );
+
+        let tmp = tempdir().unwrap();
+        let rules_file = tmp.path().join("rules");
+        fs::write(&rules_file, "# expected names\nrelease-*\n*-keep\n").unwrap();
+        let rules = load_rules(&rules_file).unwrap();
+
+        assert_eq!(
+            vec!["rpool@monday".to_string(), "rpool@rogue".to_string()],
+            find_rogue_snapshots(all_snapshots, &rules)
+        );
+
+        assert!(load_rules(&tmp.path().join("missing")).is_err());
+    }
 }
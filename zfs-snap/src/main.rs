@@ -1,38 +1,103 @@
-use clap::Parser;
-use common::command_helpers::format_command;
-use common::constants::ZFS;
-use common::types::{Filesystems, Opts};
-use common::{rules, zfs_file, zfs_info};
+use clap::{ArgAction, Parser};
+use common::command_helpers::{format_command, run_with_retry};
+use common::constants::{TRANSIENT_ZFS_ERRORS, ZFS};
+use common::snapshot::Snapshot;
+use common::types::{Filesystems, MountList, Opts};
+use common::{dataset, lock, pool, rules, zfs_file, zfs_info};
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::process::{exit, Command};
+use std::time::Duration;
 use time::{format_description, OffsetDateTime};
 
+const SNAPSHOT_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const DEFAULT_LOCK_FILE: &str = "/tmp/zfs-snap.lock";
+
 #[derive(Parser)]
 #[clap(version, about = "Takes automatically named ZFS snapshots", long_about= None)]
 struct Cli {
     #[clap(
         short = 't',
         long = "type",
+        required_unless_present = "label",
+        conflicts_with = "label",
         long_help = "Specify the type of snapshot to take: this  determines the \
         snapshot names\n  e.g  day    @wednesday\n       month  @january\n       \
-        date   @2008-30-01\n       time   @08:45\n       now    @2008-30-01_08:45:00"
+        date   @2008-30-01\n       time   @08:45\n       now    @2008-30-01_08:45:00\n\
+        Give a comma-separated list, like day,month, to take several at once"
     )]
-    snap_type: String,
+    snap_type: Option<String>,
+    /// Use this as the snapshot name, rather than one generated by --type
+    #[clap(short, long)]
+    label: Option<String>,
+    /// Once a dataset has its labelled snapshot, destroy any other snapshots of it whose names
+    /// begin with this. The label must begin with it too
+    #[clap(long, requires = "label")]
+    replace_label_prefix: Option<String>,
     /// Specifies that args are files: the filesystems containing these files will be snapshotted
     #[clap(short, long)]
     files: bool,
     /// Print what would happen, without doing it                                                     
     #[clap(short, long)]
     noop: bool,
-    /// Be verbose                                                                                    
-    #[clap(short, long)]
-    verbose: bool,
+    /// Be verbose. Give more than once for more detail
+    #[clap(short, long, action = ArgAction::Count)]
+    verbose: u8,
     /// Recurse down dataset hierarchies                                                              
     #[clap(short, long)]
     recurse: bool,
+    /// With -r, only snapshot the datasets below those given, and not the given datasets
+    /// themselves
+    #[clap(long, requires = "recurse")]
+    children_only: bool,
     /// Comma-separated list of filesystems to NOT snapshot. Accepts * as a wildcard.
     #[clap(short, long)]
     omit: Option<String>,
+    /// Shell command to run before taking any snapshots. If it fails, no snapshots are taken
+    #[clap(long)]
+    pre: Option<String>,
+    /// Shell command to run after all snapshots have been taken
+    #[clap(long)]
+    post: Option<String>,
+    /// Skip datasets which have this user property set to false or off
+    #[clap(short, long)]
+    skip_property: Option<String>,
+    /// Treat args as dataset patterns, expanding * as a wildcard, in the same way as --omit
+    #[clap(short, long, conflicts_with = "files")]
+    glob: bool,
+    /// Specifies that args are mountpoints: the filesystems mounted there will be snapshotted
+    #[clap(short, long, conflicts_with_all = ["files", "glob"])]
+    mount: bool,
+    /// Do not snapshot any dataset mounted at or below this directory
+    #[clap(long)]
+    exclude_mounted_under: Option<PathBuf>,
+    /// Don't run if another zfs-snap holds this lock. Give a file as --lock=FILE. With no value,
+    /// uses /tmp/zfs-snap.lock
+    #[clap(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = DEFAULT_LOCK_FILE
+    )]
+    lock: Option<PathBuf>,
+    /// Write the names of any snapshots which could not be created to this file, one per line
+    #[clap(long)]
+    failure_log: Option<PathBuf>,
+    /// Snapshot volumes (zvols) as well as filesystems. Has no effect with -f or -m
+    #[clap(long)]
+    include_volumes: bool,
+    /// Only snapshot datasets which have been written to since their last snapshot
+    #[clap(long)]
+    if_changed: bool,
+    /// Only snapshot datasets which are mounted, and not read-only
+    #[clap(long)]
+    rw_only: bool,
+    /// Only snapshot datasets whose pool is ONLINE
+    #[clap(long)]
+    require_healthy: bool,
     /// Dataset or directory name. If not args are given, every dataset will be snapshotted.
     #[clap()]
     object: Option<Vec<String>>,
@@ -45,7 +110,72 @@ fn dataset_list(from_user: Option<Vec<String>>, all_filesystems: Filesystems) ->
     }
 }
 
+// One name for each of a comma-separated list of types, in the order given. Types which give the
+// same name only give it once.
+fn snapnames(snap_types: &str, timestamp: OffsetDateTime) -> Result<Vec<String>, String> {
+    let mut ret: Vec<String> = Vec::new();
+
+    for snap_type in snap_types.split(',') {
+        let name = snapname(snap_type, timestamp)?;
+
+        if !ret.contains(&name) {
+            ret.push(name);
+        }
+    }
+
+    Ok(ret)
+}
+
 fn snapname(snap_type: &str, timestamp: OffsetDateTime) -> Result<String, String> {
+    let name = raw_snapname(snap_type, timestamp)?;
+    rules::valid_snapshot_name(&name).map_err(|e| e.to_string())?;
+    Ok(name)
+}
+
+fn label_snapname(label: &str) -> Result<String, String> {
+    rules::valid_snapshot_name(label).map_err(|e| e.to_string())?;
+    Ok(label.to_string())
+}
+
+// An empty prefix would match every snapshot, so it isn't allowed.
+fn check_label_prefix(label: &str, prefix: &str) -> Result<(), String> {
+    if prefix.is_empty() {
+        Err("label prefix cannot be empty".to_string())
+    } else if !label.starts_with(prefix) {
+        Err(format!(
+            "label '{}' does not begin with '{}'",
+            label, prefix
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// Finds the snapshots of the given datasets which carry an older version of a rolling label. A
+// snapshot with the new label itself is left for snapshot_one() to replace.
+fn snapshots_to_replace(
+    existing: &[String],
+    dataset_list: &[String],
+    prefix: &str,
+    label: &str,
+) -> Vec<String> {
+    let datasets: HashSet<&str> = dataset_list.iter().map(String::as_str).collect();
+
+    existing
+        .iter()
+        .filter(|name| match Snapshot::parse(name) {
+            Some(snap) => {
+                datasets.contains(snap.dataset.as_str())
+                    && snap.name.starts_with(prefix)
+                    && snap.name != label
+            }
+            None => false,
+        })
+        .cloned()
+        .collect()
+}
+
+fn raw_snapname(snap_type: &str, timestamp: OffsetDateTime) -> Result<String, String> {
     match snap_type {
         "date" => Ok(timestamp.date().to_string()),
         "day" => Ok(timestamp.weekday().to_string().to_lowercase()),
@@ -64,87 +194,383 @@ fn format_time(timestamp: OffsetDateTime, format_str: &str) -> Result<String, St
         .map_err(|_| "Error formatting timestamp".to_string())
 }
 
+type Failures = Vec<(String, String)>;
+
 fn snapshot_exists(snapshot: &str, opts: &Opts) -> bool {
-    snapshot_command(snapshot, "list", opts, true)
+    snapshot_command(snapshot, "list", opts).is_ok()
 }
 
-fn destroy_snapshot(snapshot: &str, opts: &Opts) -> bool {
-    snapshot_command(snapshot, "destroy", opts, false)
+fn destroy_snapshot(snapshot: &str, opts: &Opts) -> Result<(), String> {
+    common::snapshot::assert_is_snapshot(snapshot).map_err(|e| e.to_string())?;
+    snapshot_command(snapshot, "destroy", opts)
 }
 
-fn take_snapshot(snapshot: &str, opts: &Opts) -> bool {
-    snapshot_command(snapshot, "snapshot", opts, false)
+// A busy dataset is worth another try.
+fn take_snapshot(snapshot: &str, opts: &Opts) -> Result<(), String> {
+    let mut cmd = Command::new(ZFS);
+    cmd.arg("snapshot").arg(snapshot);
+    run_command_with_retry(cmd, opts, SNAPSHOT_ATTEMPTS)
 }
 
-fn snapshot_command(snapshot: &str, action: &str, opts: &Opts, hush: bool) -> bool {
+fn snapshot_command(snapshot: &str, action: &str, opts: &Opts) -> Result<(), String> {
     let mut cmd = Command::new(ZFS);
     cmd.arg(action).arg(snapshot);
+    run_command(cmd, opts)
+}
 
-    if opts.verbose || opts.noop {
-        println!("{}", format_command(&cmd));
+fn run_command(cmd: Command, opts: &Opts) -> Result<(), String> {
+    run_command_with_retry(cmd, opts, 1)
+}
+
+// If the command can't be run, or exits non-zero, the error describes why, so the caller can
+// choose whether or not to report it. Transient zfs errors are retried, up to the given number
+// of attempts.
+fn run_command_with_retry(mut cmd: Command, opts: &Opts, attempts: u32) -> Result<(), String> {
+    let cmd_string = format_command(&cmd);
+
+    if opts.verbose > 0 || opts.noop {
+        println!("{}", cmd_string);
     }
 
     if opts.noop {
-        return true;
+        return Ok(());
     }
 
-    let output = cmd
-        .output()
-        .unwrap_or_else(|_| panic!("failed to run 'zfs {} {}'", action, snapshot));
+    let output = run_with_retry(&mut cmd, attempts, RETRY_BACKOFF, TRANSIENT_ZFS_ERRORS)
+        .map_err(|e| format!("failed to run '{}': {}", cmd_string, e))?;
 
     if output.status.success() {
-        true
+        Ok(())
     } else {
-        if !hush {
-            let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(format!("error running '{}': {}", cmd_string, stderr.trim()))
+    }
+}
+
+fn run_hook(hook: &str, opts: &Opts) -> Result<(), String> {
+    let mut cmd = Command::new("/bin/sh");
+    cmd.arg("-c").arg(hook);
+    run_command(cmd, opts)
+}
+
+// Replaces any existing snapshot of the same name.
+fn snapshot_one(snapshot: &str, opts: &Opts) -> Result<(), String> {
+    if snapshot_exists(snapshot, opts) {
+        destroy_snapshot(snapshot, opts)
+            .map_err(|e| format!("failed to destroy existing snapshot: {}", e))?;
+    }
+
+    take_snapshot(snapshot, opts)
+}
+
+// A failure on one dataset never stops the others. Returns every snapshot which could not be
+// taken, with the reason.
+fn snapshot_all<F>(dataset_list: Filesystems, snapnames: &[String], mut snapshotter: F) -> Failures
+where
+    F: FnMut(&str) -> Result<(), String>,
+{
+    let mut failures = Failures::new();
+
+    for dataset in dataset_list {
+        for snapname in snapnames {
+            let snapshot = format!("{}@{}", &dataset, snapname);
+            println!("Snapshotting {}", &snapshot);
+
+            if let Err(e) = snapshotter(&snapshot) {
+                failures.push((snapshot, e));
+            }
+        }
+    }
+
+    failures
+}
+
+fn report_failures(failures: &Failures) {
+    eprintln!("Failed to create {} snapshot(s):", failures.len());
+
+    for (snapshot, error) in failures {
+        eprintln!("  {}: {}", snapshot, error);
+    }
+}
+
+// The log is always written, so a wrapper retrying from it never sees a stale list.
+fn write_failure_log(log_file: &Path, failures: &Failures) -> io::Result<()> {
+    let contents: String = failures
+        .iter()
+        .map(|(snapshot, _error)| format!("{}\n", snapshot))
+        .collect();
+
+    fs::write(log_file, contents)
+}
+
+// Gives up before taking any snapshots. The failure log is emptied, so a wrapper never retries
+// from an earlier run's list.
+fn exit_without_snapshots(failure_log: Option<&Path>, code: i32) -> ! {
+    log_failures(failure_log, &Failures::new());
+    exit(code);
+}
+
+// Records the snapshots which could not be taken, if we were asked to.
+fn log_failures(failure_log: Option<&Path>, failures: &Failures) {
+    if let Some(log_file) = failure_log {
+        if let Err(e) = write_failure_log(log_file, failures) {
             eprintln!(
-                "Error running 'zfs {} {}': {}",
-                action,
-                snapshot,
-                stderr.trim()
+                "ERROR: could not write failure log {}: {}",
+                log_file.display(),
+                e
             );
         }
-        false
     }
 }
 
-fn do_the_snapshotting(dataset_list: Filesystems, snapname: String, opts: Opts) -> io::Result<()> {
-    let mut errs = 0;
+// Every snapshot we meant to take, all failed for the same reason.
+fn not_taken(dataset_list: &[String], snapnames: &[String], reason: &str) -> Failures {
+    dataset_list
+        .iter()
+        .flat_map(|dataset| {
+            snapnames
+                .iter()
+                .map(move |snapname| (format!("{}@{}", dataset, snapname), reason.to_string()))
+        })
+        .collect()
+}
 
-    for dataset in dataset_list {
-        let snapshot = format!("{}@{}", &dataset, &snapname);
-        println!("Snapshotting {}", &snapshot);
+// Everything about a run which isn't the datasets themselves. Replaces holds old snapshots, from
+// --replace-label-prefix, to be destroyed once their dataset has its new one.
+struct SnapshotRun<'a> {
+    snapnames: &'a [String],
+    pre: Option<&'a str>,
+    post: Option<&'a str>,
+    failure_log: Option<&'a Path>,
+    if_changed: bool,
+    replaces: &'a [String],
+}
+
+fn do_the_snapshotting(dataset_list: Filesystems, run: &SnapshotRun, opts: Opts) -> io::Result<()> {
+    do_the_snapshotting_with(dataset_list, run, &opts, zfs_info::written, |snapshot| {
+        snapshot_replacing(
+            snapshot,
+            run.replaces,
+            |new| snapshot_one(new, &opts),
+            |old| destroy_snapshot(old, &opts),
+        )
+    })
+}
+
+// Takes the snapshot, and only if that works, destroys the old snapshots of the same dataset which
+// it replaces. Failing to destroy one of those doesn't make the new snapshot a failure.
+fn snapshot_replacing<S, D>(
+    snapshot: &str,
+    replaces: &[String],
+    mut snapshotter: S,
+    mut destroyer: D,
+) -> Result<(), String>
+where
+    S: FnMut(&str) -> Result<(), String>,
+    D: FnMut(&str) -> Result<(), String>,
+{
+    snapshotter(snapshot)?;
+
+    let dataset = Snapshot::parse(snapshot).map(|snap| snap.dataset);
 
-        if snapshot_exists(&snapshot, &opts) && !destroy_snapshot(&snapshot, &opts) {
-            eprintln!("Failed to destroy existing {}", &snapshot);
-            errs += 1;
-            continue;
+    for old in replaces
+        .iter()
+        .filter(|old| Snapshot::parse(old).map(|snap| snap.dataset) == dataset)
+    {
+        println!("Removing {}", old);
+
+        if let Err(e) = destroyer(old) {
+            eprintln!("Failed to remove {}: {}", old, e);
         }
+    }
+
+    Ok(())
+}
 
-        if !take_snapshot(&snapshot, &opts) {
-            eprintln!("Failed to create {}", &snapshot);
-            errs += 1;
-            continue;
+// The failure log is written however we leave, so it always describes this run. Written bytes
+// come from the given function, for --if-changed.
+fn do_the_snapshotting_with<W, E, F>(
+    dataset_list: Filesystems,
+    run: &SnapshotRun,
+    opts: &Opts,
+    written: W,
+    snapshotter: F,
+) -> io::Result<()>
+where
+    W: FnOnce(&[String]) -> Result<HashMap<String, u64>, E>,
+    E: std::fmt::Display,
+    F: FnMut(&str) -> Result<(), String>,
+{
+    if let Some(hook) = run.pre {
+        if let Err(e) = run_hook(hook, opts) {
+            let reason = format!("pre-snapshot hook failed: {}", e);
+            log_failures(
+                run.failure_log,
+                &not_taken(&dataset_list, run.snapnames, &reason),
+            );
+
+            return Err(std::io::Error::other(format!(
+                "ERROR: {}: no snapshots were taken",
+                reason
+            )));
         }
     }
 
-    if errs > 0 {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("ERROR: {} snapshots were not created", errs),
-        ))
+    // This comes after the pre hook, so anything it makes the application write is seen.
+    let dataset_list = if run.if_changed {
+        match written(&dataset_list) {
+            Ok(written) => drop_unchanged(dataset_list, &written),
+            Err(e) => {
+                eprintln!(
+                    "Could not get written bytes, snapshotting everything: {}",
+                    e
+                );
+                dataset_list
+            }
+        }
+    } else {
+        dataset_list
+    };
+
+    let failures = snapshot_all(dataset_list, run.snapnames, snapshotter);
+    log_failures(run.failure_log, &failures);
+
+    let post_result = match run.post {
+        Some(hook) => run_hook(hook, opts),
+        None => Ok(()),
+    };
+
+    if !failures.is_empty() {
+        report_failures(&failures);
+        Err(std::io::Error::other(format!(
+            "ERROR: {} snapshots were not created",
+            failures.len()
+        )))
+    } else if let Err(e) = post_result {
+        Err(std::io::Error::other(format!(
+            "ERROR: post-snapshot hook failed: {}",
+            e
+        )))
     } else {
         Ok(())
     }
 }
 
+// A dataset we know nothing about is snapshotted, to be safe.
+fn should_snapshot(written: Option<u64>) -> bool {
+    written != Some(0)
+}
+
+fn drop_unchanged(dataset_list: Filesystems, written: &HashMap<String, u64>) -> Filesystems {
+    dataset_list
+        .into_iter()
+        .filter(|dataset| {
+            let keep = should_snapshot(written.get(dataset).copied());
+
+            if !keep {
+                println!("Skipping {}: unchanged since its last snapshot", dataset);
+            }
+
+            keep
+        })
+        .collect()
+}
+
+// Each pool is only asked about once, however many datasets it holds.
+fn pool_health(dataset_list: &[String]) -> HashMap<String, Result<String, String>> {
+    dataset_list
+        .iter()
+        .map(|dataset| pool::name_of(dataset).to_string())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .map(|name| {
+            let health = pool::health(&name).map_err(|e| e.to_string());
+            (name, health)
+        })
+        .collect()
+}
+
+// A pool whose health we can't find out is treated as unhealthy.
+fn drop_unhealthy(
+    dataset_list: Filesystems,
+    health: &HashMap<String, Result<String, String>>,
+) -> Filesystems {
+    dataset_list
+        .into_iter()
+        .filter(|dataset| {
+            let name = pool::name_of(dataset);
+
+            match health.get(name) {
+                Some(Ok(state)) if state == pool::HEALTHY => true,
+                Some(Ok(state)) => {
+                    println!("Skipping {}: pool {} is {}", dataset, name, state);
+                    false
+                }
+                Some(Err(e)) => {
+                    println!(
+                        "Skipping {}: could not get health of pool {}: {}",
+                        dataset, name, e
+                    );
+                    false
+                }
+                None => {
+                    println!("Skipping {}: health of pool {} is unknown", dataset, name);
+                    false
+                }
+            }
+        })
+        .collect()
+}
+
+fn warn_missing_datasets(datasets: &[String]) {
+    match zfs_info::missing_datasets(datasets) {
+        Ok(missing) => missing
+            .iter()
+            .for_each(|dataset| eprintln!("WARNING: dataset {} does not exist", dataset)),
+        Err(e) => eprintln!("WARNING: could not check datasets exist: {}", e),
+    }
+}
+
+// Tidies dataset names given as arguments, and to --omit. Files and mountpoints are paths, so
+// can't be tidied like dataset names.
+fn normalize_datasets(cli: &mut Cli) -> Result<(), String> {
+    if !cli.files && !cli.mount {
+        if let Some(object) = &cli.object {
+            cli.object = Some(dataset::normalize_all(object).map_err(|e| e.to_string())?);
+        }
+    }
+
+    if let Some(omit) = &cli.omit {
+        cli.omit = Some(dataset::normalize_rules(omit).map_err(|e| e.to_string())?);
+    }
+
+    Ok(())
+}
+
 fn main() {
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+
+    if let Err(e) = normalize_datasets(&mut cli) {
+        eprintln!("Invalid dataset: {}", e);
+        exit(2);
+    }
+
     let opts = Opts {
         verbose: cli.verbose,
         noop: cli.noop,
     };
 
+    // Held until we exit.
+    let _lock = cli.lock.as_deref().map(|lock_file| {
+        lock::acquire(lock_file).unwrap_or_else(|e| {
+            eprintln!("Not snapshotting: {}", e);
+            exit(1);
+        })
+    });
+
+    // From here, the log belongs to this run, however it ends.
+    let failure_log = cli.failure_log.as_deref();
+
     // If the user gives us a list of files, we don't need this information, and it's potentially
     // expensive.
     let all_filesystems: Vec<String> = if cli.files {
@@ -152,49 +578,159 @@ fn main() {
     } else {
         zfs_info::all_filesystems().unwrap_or_else(|e| {
             eprintln!("Could not get a list of filesystems: {}", e);
-            exit(1);
+            exit_without_snapshots(failure_log, 1);
         })
     };
 
+    let all_filesystems = if cli.include_volumes && !cli.files {
+        let volumes = zfs_info::all_volumes().unwrap_or_else(|e| {
+            eprintln!("Could not get a list of volumes: {}", e);
+            exit_without_snapshots(failure_log, 1);
+        });
+        with_volumes(all_filesystems, volumes)
+    } else {
+        all_filesystems
+    };
+
+    let mounts = if cli.files || cli.mount || cli.rw_only || cli.exclude_mounted_under.is_some() {
+        zfs_info::get_mounted_filesystems().unwrap_or_else(|e| {
+            eprintln!("Failed to get list of mounted filesystems: {}", e);
+            exit_without_snapshots(failure_log, 1);
+        })
+    } else {
+        MountList::new()
+    };
+
+    let object = if cli.glob {
+        cli.object
+            .map(|patterns| zfs_info::expand_dataset_globs(&patterns, &all_filesystems))
+    } else if cli.mount {
+        cli.object.map(|mountpoints| {
+            let (datasets, unmatched) = datasets_at_mountpoints(&mountpoints, &mounts);
+            unmatched
+                .iter()
+                .for_each(|dir| eprintln!("nothing is mounted at {}", dir));
+            datasets
+        })
+    } else {
+        cli.object
+    };
+
+    // A mistyped dataset name would otherwise just be snapshotted as nothing at all.
+    if !cli.files && !cli.glob && !cli.mount {
+        if let Some(datasets) = &object {
+            warn_missing_datasets(datasets);
+        }
+    }
+
     let mut dataset_list = if cli.files {
-        if cli.object.is_none() {
+        let Some(files) = object else {
             eprintln!("-f requires one or more files");
-            exit(2);
-        }
-        match zfs_info::get_mounted_filesystems() {
-            Ok(mounts) => zfs_file::files_to_datasets(&cli.object.unwrap(), mounts),
-            Err(e) => {
-                eprintln!("Failed to get list of mounted filesystems: {}", e);
-                exit(1);
-            }
-        }
+            exit_without_snapshots(failure_log, 2);
+        };
+        zfs_file::files_to_datasets_detailed(&files, mounts.clone())
+            .iter()
+            .filter(|(_file, dataset)| dataset.is_none())
+            .for_each(|(file, _)| eprintln!("{} is not on a ZFS filesystem", file));
+        zfs_file::files_to_datasets(&files, mounts.clone())
     } else if cli.recurse {
-        if cli.object.is_none() {
+        let Some(filesystems) = object else {
             eprintln!("-r makes no sense without a list of filesystems");
-            exit(2);
+            exit_without_snapshots(failure_log, 2);
+        };
+        let recursive = zfs_info::dataset_list_recursive(filesystems.clone(), all_filesystems);
+
+        if cli.children_only {
+            drop_parents(recursive, &filesystems)
         } else {
-            zfs_info::dataset_list_recursive(cli.object.unwrap(), all_filesystems)
+            recursive
         }
     } else {
-        dataset_list(cli.object, all_filesystems)
+        dataset_list(object, all_filesystems)
     };
 
     if let Some(omit_rules) = cli.omit {
         dataset_list = omit_filesystems(dataset_list, omit_rules);
     }
 
+    if let Some(prefix) = cli.exclude_mounted_under {
+        dataset_list = drop_mounted_under(dataset_list, &mounts, &prefix);
+    }
+
+    if let Some(property) = cli.skip_property {
+        match zfs_info::get_property(&property, &dataset_list) {
+            Ok(values) => dataset_list = drop_opted_out(dataset_list, &values),
+            Err(e) => {
+                eprintln!("Failed to get {} property: {}", property, e);
+                exit_without_snapshots(failure_log, 1);
+            }
+        }
+    }
+
+    if cli.rw_only {
+        match zfs_info::get_property("readonly", &dataset_list) {
+            Ok(readonly) => {
+                dataset_list = drop_unwritable(dataset_states(dataset_list, &readonly, &mounts))
+            }
+            Err(e) => {
+                eprintln!("Failed to get readonly property: {}", e);
+                exit_without_snapshots(failure_log, 1);
+            }
+        }
+    }
+
+    if cli.require_healthy {
+        let health = pool_health(&dataset_list);
+        dataset_list = drop_unhealthy(dataset_list, &health);
+    }
+
     if dataset_list.is_empty() {
         println!("Nothing to snapshot.");
-        exit(1);
+        exit_without_snapshots(failure_log, 1);
     }
 
-    let now = OffsetDateTime::now_local().expect("Could not get local time");
-    let snapname = snapname(&cli.snap_type, now).unwrap_or_else(|_| {
-        eprintln!("Invalid snapshot type");
-        exit(3);
+    let snapnames = match (&cli.label, &cli.snap_type) {
+        (Some(label), _) => label_snapname(label).map(|name| vec![name]),
+        (None, Some(snap_types)) => {
+            let now = OffsetDateTime::now_local().expect("Could not get local time");
+            snapnames(snap_types, now)
+        }
+        (None, None) => Err("no snapshot type or label given".to_string()),
+    }
+    .unwrap_or_else(|e| {
+        eprintln!("Invalid snapshot name: {}", e);
+        exit_without_snapshots(failure_log, 3);
     });
 
-    match do_the_snapshotting(dataset_list, snapname, opts) {
+    // This needs a label, so there is only one name.
+    let replaces = if let Some(prefix) = &cli.replace_label_prefix {
+        let snapname = &snapnames[0];
+
+        if let Err(e) = check_label_prefix(snapname, prefix) {
+            eprintln!("{}", e);
+            exit_without_snapshots(failure_log, 2);
+        }
+
+        let existing = zfs_info::all_snapshots().unwrap_or_else(|e| {
+            eprintln!("Could not get a list of snapshots: {}", e);
+            exit_without_snapshots(failure_log, 1);
+        });
+
+        snapshots_to_replace(&existing, &dataset_list, prefix, snapname)
+    } else {
+        Vec::new()
+    };
+
+    let run = SnapshotRun {
+        snapnames: &snapnames,
+        pre: cli.pre.as_deref(),
+        post: cli.post.as_deref(),
+        failure_log,
+        if_changed: cli.if_changed,
+        replaces: &replaces,
+    };
+
+    match do_the_snapshotting(dataset_list, &run, opts) {
         Ok(_) => exit(0),
         Err(e) => {
             println!("{}", e);
@@ -203,20 +739,355 @@ fn main() {
     }
 }
 
+// Drops the datasets the user named, leaving only what was found below them.
+fn drop_parents(dataset_list: Filesystems, parents: &[String]) -> Filesystems {
+    dataset_list
+        .into_iter()
+        .filter(|dataset| !parents.contains(dataset))
+        .collect()
+}
+
+// Volumes are sorted in among the filesystems, so children follow their parents, as they do in
+// zfs list.
+fn with_volumes(filesystems: Filesystems, volumes: Filesystems) -> Filesystems {
+    let mut ret: Filesystems = filesystems.into_iter().chain(volumes).collect();
+    ret.sort();
+    ret.dedup();
+    ret
+}
+
 fn omit_filesystems(filesystem_list: Filesystems, omit_rules: String) -> Filesystems {
     let rules: Vec<_> = omit_rules.split(',').map(|s| s.to_string()).collect();
+    let rules = rules::CompiledRules::new(&rules);
 
     filesystem_list
         .into_iter()
-        .filter(|item| rules::omit_rules_match(item, &rules))
+        .filter(|item| rules.keeps(item))
+        .collect()
+}
+
+// Finds the dataset mounted at each of the given mountpoints. Those with nothing mounted on them
+// come back in the second list.
+fn datasets_at_mountpoints(
+    mountpoints: &[String],
+    mounts: &MountList,
+) -> (Filesystems, Vec<String>) {
+    let mut datasets = Filesystems::new();
+    let mut unmatched = Vec::new();
+
+    for dir in mountpoints {
+        match mounts
+            .iter()
+            .find(|mount| mount.mountpoint == Path::new(dir))
+        {
+            Some(mount) => datasets.push(mount.dataset.clone()),
+            None => unmatched.push(dir.to_string()),
+        }
+    }
+
+    (datasets, unmatched)
+}
+
+// Drops any dataset whose mountpoint is the prefix, or anywhere beneath it. Paths are compared by
+// component, so /zones does not exclude /zonesbackup. Unmounted datasets are kept.
+fn drop_mounted_under(
+    filesystem_list: Filesystems,
+    mounts: &MountList,
+    prefix: &Path,
+) -> Filesystems {
+    let excluded: HashSet<&str> = mounts
+        .iter()
+        .filter(|mount| mount.mountpoint.starts_with(prefix))
+        .map(|mount| mount.dataset.as_str())
+        .collect();
+
+    filesystem_list
+        .into_iter()
+        .filter(|fs| !excluded.contains(fs.as_str()))
+        .collect()
+}
+
+// Drops any dataset whose property has been explicitly turned off. Unset properties, which zfs
+// shows as '-', are ignored.
+fn drop_opted_out(filesystem_list: Filesystems, values: &HashMap<String, String>) -> Filesystems {
+    filesystem_list
+        .into_iter()
+        .filter(|fs| match values.get(fs) {
+            Some(value) => {
+                !(value.eq_ignore_ascii_case("false") || value.eq_ignore_ascii_case("off"))
+            }
+            None => true,
+        })
+        .collect()
+}
+
+// Pairs each dataset with whether it is read-only, and whether it is mounted.
+fn dataset_states(
+    dataset_list: Filesystems,
+    readonly: &HashMap<String, String>,
+    mounts: &MountList,
+) -> Vec<(String, bool, bool)> {
+    let mounted: HashSet<&str> = mounts.iter().map(|mount| mount.dataset.as_str()).collect();
+
+    dataset_list
+        .into_iter()
+        .map(|dataset| {
+            let is_readonly = readonly.get(&dataset).is_some_and(|value| value == "on");
+            let is_mounted = mounted.contains(dataset.as_str());
+            (dataset, is_readonly, is_mounted)
+        })
+        .collect()
+}
+
+// Keeps datasets which are mounted and writable.
+fn drop_unwritable(states: Vec<(String, bool, bool)>) -> Filesystems {
+    states
+        .into_iter()
+        .filter_map(|(dataset, readonly, mounted)| {
+            if !mounted {
+                println!("Skipping {}: not mounted", dataset);
+                None
+            } else if readonly {
+                println!("Skipping {}: read-only", dataset);
+                None
+            } else {
+                Some(dataset)
+            }
+        })
         .collect()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use common::types::Mount;
+    use tempfile::tempdir;
     use time::{Date, Month, OffsetDateTime, Time, UtcOffset};
 
+    #[test]
+    fn test_dataset_states() {
+        let readonly: HashMap<String, String> = [
+            ("rpool/a", "off"),
+            ("rpool/b", "on"),
+            ("rpool/c", "off"),
+            ("rpool/d", "-"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let mounts: MountList = vec![
+            Mount::new("/a", "rpool/a"),
+            Mount::new("/b", "rpool/b"),
+            Mount::new("/d", "rpool/d"),
+        ];
+
+        assert_eq!(
+            vec![
+                ("rpool/a".to_string(), false, true),
+                ("rpool/b".to_string(), true, true),
+                ("rpool/c".to_string(), false, false),
+                ("rpool/d".to_string(), false, true),
+                ("rpool/e".to_string(), false, false),
+            ],
+            dataset_states(
+                ["rpool/a", "rpool/b", "rpool/c", "rpool/d", "rpool/e"]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+                &readonly,
+                &mounts
+            )
+        );
+    }
+
+    #[test]
+    fn test_drop_unwritable() {
+        let states = vec![
+            ("rpool/rw".to_string(), false, true),
+            ("rpool/ro".to_string(), true, true),
+            ("rpool/unmounted".to_string(), false, false),
+            ("rpool/ro_unmounted".to_string(), true, false),
+            ("rpool/rw2".to_string(), false, true),
+        ];
+
+        assert_eq!(
+            vec!["rpool/rw".to_string(), "rpool/rw2".to_string()],
+            drop_unwritable(states)
+        );
+        assert!(drop_unwritable(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_should_snapshot() {
+        assert!(!should_snapshot(Some(0)));
+        assert!(should_snapshot(Some(1)));
+        assert!(should_snapshot(Some(104_857_600)));
+        assert!(should_snapshot(None));
+    }
+
+    #[test]
+    fn test_drop_unchanged() {
+        let dataset_list = vec![
+            "rpool".to_string(),
+            "rpool/idle".to_string(),
+            "rpool/busy".to_string(),
+            "rpool/unknown".to_string(),
+        ];
+
+        let written: HashMap<String, u64> = [
+            ("rpool".to_string(), 4096),
+            ("rpool/idle".to_string(), 0),
+            ("rpool/busy".to_string(), 73_400_320),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            vec![
+                "rpool".to_string(),
+                "rpool/busy".to_string(),
+                "rpool/unknown".to_string(),
+            ],
+            drop_unchanged(dataset_list, &written)
+        );
+    }
+
+    #[test]
+    fn test_normalize_datasets() {
+        let mut cli = Cli::parse_from(["zfs-snap", "-t", "day", "-o", "rpool/data/", "rpool/"]);
+        normalize_datasets(&mut cli).unwrap();
+        assert_eq!(Some(vec!["rpool".to_string()]), cli.object);
+        assert_eq!(Some("rpool/data".to_string()), cli.omit);
+
+        let mut cli = Cli::parse_from(["zfs-snap", "-t", "day", "-o", "rpool@monday", "rpool"]);
+        assert!(normalize_datasets(&mut cli).is_err());
+    }
+
+    #[test]
+    fn test_conflicting_args() {
+        for args in [
+            vec!["zfs-snap", "-t", "day", "-g", "-f", "rpool/*"],
+            vec!["zfs-snap", "-t", "day", "-m", "-f", "/home"],
+            vec!["zfs-snap", "-t", "day", "-m", "-g", "/home"],
+        ] {
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+
+        assert!(Cli::try_parse_from(["zfs-snap", "-t", "day", "-g", "rpool/*"]).is_ok());
+    }
+
+    #[test]
+    fn test_lock_arg() {
+        let cli = Cli::parse_from(["zfs-snap", "-t", "day", "--lock", "tank"]);
+        assert_eq!(Some(PathBuf::from(DEFAULT_LOCK_FILE)), cli.lock);
+        assert_eq!(Some(vec!["tank".to_string()]), cli.object);
+
+        let cli = Cli::parse_from(["zfs-snap", "-t", "day", "--lock=/run/snap.lock", "tank"]);
+        assert_eq!(Some(PathBuf::from("/run/snap.lock")), cli.lock);
+        assert_eq!(Some(vec!["tank".to_string()]), cli.object);
+
+        let cli = Cli::parse_from(["zfs-snap", "-t", "day", "tank"]);
+        assert_eq!(None, cli.lock);
+    }
+
+    #[test]
+    fn test_drop_unhealthy() {
+        let dataset_list: Filesystems = [
+            "rpool",
+            "rpool/home",
+            "tank/data",
+            "tank/data@monday",
+            "fast/scratch",
+            "lost/thing",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let health: HashMap<String, Result<String, String>> = [
+            ("rpool".to_string(), Ok("ONLINE".to_string())),
+            ("tank".to_string(), Ok("DEGRADED".to_string())),
+            ("fast".to_string(), Err("no such pool 'fast'".to_string())),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            vec!["rpool".to_string(), "rpool/home".to_string()],
+            drop_unhealthy(dataset_list, &health)
+        );
+        assert!(drop_unhealthy(Vec::new(), &health).is_empty());
+    }
+
+    #[test]
+    fn test_drop_parents() {
+        let all_filesystems: Filesystems = [
+            "rpool",
+            "rpool/data",
+            "rpool/data/a",
+            "rpool/data/a/deep",
+            "rpool/data/b",
+            "rpool/database",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let parents = vec!["rpool/data".to_string()];
+        let mut children = drop_parents(
+            zfs_info::dataset_list_recursive(parents.clone(), all_filesystems),
+            &parents,
+        );
+        children.sort();
+
+        assert_eq!(
+            vec![
+                "rpool/data/a".to_string(),
+                "rpool/data/a/deep".to_string(),
+                "rpool/data/b".to_string(),
+            ],
+            children
+        );
+
+        assert!(drop_parents(parents.clone(), &parents).is_empty());
+    }
+
+    #[test]
+    fn test_with_volumes() {
+        let filesystems = vec![
+            "rpool".to_string(),
+            "rpool/zones".to_string(),
+            "tank".to_string(),
+        ];
+
+        let volumes = vec!["rpool/swap".to_string(), "rpool/zones/disk0".to_string()];
+
+        let merged = with_volumes(filesystems.clone(), volumes);
+
+        assert_eq!(
+            vec![
+                "rpool".to_string(),
+                "rpool/swap".to_string(),
+                "rpool/zones".to_string(),
+                "rpool/zones/disk0".to_string(),
+                "tank".to_string(),
+            ],
+            merged
+        );
+
+        assert_eq!(filesystems, with_volumes(filesystems.clone(), Vec::new()));
+
+        let mut recursive =
+            zfs_info::dataset_list_recursive(vec!["rpool/zones".to_string()], merged);
+        recursive.sort();
+
+        assert_eq!(
+            vec!["rpool/zones".to_string(), "rpool/zones/disk0".to_string()],
+            recursive
+        );
+    }
+
     #[test]
     fn test_omit_filesystems() {
         let filesystem_list = vec![
@@ -272,6 +1143,203 @@ mod test {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn test_datasets_at_mountpoints() {
+        let mounts: MountList = vec![
+            Mount::new("/build/configs", "fast/zone/build/config"),
+            Mount::new("/build", "fast/zone/build/build"),
+            Mount::new("/rpool", "rpool"),
+        ];
+
+        let mountpoints = vec![
+            "/build".to_string(),
+            "/build/configs/".to_string(),
+            "/build/configs/subdir".to_string(),
+            "/nowhere".to_string(),
+        ];
+
+        assert_eq!(
+            (
+                vec![
+                    "fast/zone/build/build".to_string(),
+                    "fast/zone/build/config".to_string()
+                ],
+                vec!["/build/configs/subdir".to_string(), "/nowhere".to_string()]
+            ),
+            datasets_at_mountpoints(&mountpoints, &mounts)
+        );
+    }
+
+    #[test]
+    fn test_drop_mounted_under() {
+        let filesystem_list = vec![
+            "rpool".to_string(),
+            "rpool/zones".to_string(),
+            "rpool/zones/web".to_string(),
+            "rpool/zones/web/ROOT".to_string(),
+            "rpool/zonesbackup".to_string(),
+            "rpool/unmounted".to_string(),
+        ];
+
+        let mounts: MountList = vec![
+            Mount::new("/zones/web/root", "rpool/zones/web/ROOT"),
+            Mount::new("/zonesbackup", "rpool/zonesbackup"),
+            Mount::new("/zones/web", "rpool/zones/web"),
+            Mount::new("/zones", "rpool/zones"),
+            Mount::new("/rpool", "rpool"),
+        ];
+
+        assert_eq!(
+            vec![
+                "rpool".to_string(),
+                "rpool/zones".to_string(),
+                "rpool/zonesbackup".to_string(),
+                "rpool/unmounted".to_string(),
+            ],
+            drop_mounted_under(filesystem_list.clone(), &mounts, Path::new("/zones/web"))
+        );
+
+        assert_eq!(
+            vec![
+                "rpool".to_string(),
+                "rpool/zonesbackup".to_string(),
+                "rpool/unmounted".to_string(),
+            ],
+            drop_mounted_under(filesystem_list, &mounts, Path::new("/zones"))
+        );
+    }
+
+    #[test]
+    fn test_drop_opted_out() {
+        let filesystem_list = vec![
+            "rpool".to_string(),
+            "rpool/false".to_string(),
+            "rpool/off".to_string(),
+            "rpool/true".to_string(),
+            "rpool/unset".to_string(),
+            "rpool/missing".to_string(),
+            "rpool/shouty".to_string(),
+        ];
+
+        let values: HashMap<String, String> = [
+            ("rpool", "true"),
+            ("rpool/false", "false"),
+            ("rpool/off", "off"),
+            ("rpool/true", "true"),
+            ("rpool/unset", "-"),
+            ("rpool/shouty", "FALSE"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        assert_eq!(
+            vec![
+                "rpool".to_string(),
+                "rpool/true".to_string(),
+                "rpool/unset".to_string(),
+                "rpool/missing".to_string(),
+            ],
+            drop_opted_out(filesystem_list, &values)
+        );
+    }
+
+    #[test]
+    fn test_label_snapname() {
+        assert_eq!(
+            Ok("release-1.2.3".to_string()),
+            label_snapname("release-1.2.3")
+        );
+        assert_eq!(
+            Ok("pre_upgrade:2".to_string()),
+            label_snapname("pre_upgrade:2")
+        );
+        assert!(label_snapname("").is_err());
+        assert!(label_snapname("has space").is_err());
+        assert!(label_snapname("bad@name").is_err());
+        assert!(label_snapname("bad/name").is_err());
+    }
+
+    #[test]
+    fn test_check_label_prefix() {
+        assert!(check_label_prefix("release-1.2.3", "release-").is_ok());
+        assert!(check_label_prefix("release-1.2.3", "").is_err());
+        assert!(check_label_prefix("release-1.2.3", "nightly-").is_err());
+    }
+
+    #[test]
+    fn test_snapshots_to_replace() {
+        let existing = vec![
+            "rpool/app@release-1.2.1".to_string(),
+            "rpool/app@release-1.2.2".to_string(),
+            "rpool/app@release-1.2.3".to_string(),
+            "rpool/app@monday".to_string(),
+            "rpool/app/data@release-1.2.2".to_string(),
+            "rpool/other@release-1.0.0".to_string(),
+            "rpool/app@prerelease-1".to_string(),
+        ];
+
+        let dataset_list = vec!["rpool/app".to_string(), "rpool/app/data".to_string()];
+
+        assert_eq!(
+            vec![
+                "rpool/app@release-1.2.1".to_string(),
+                "rpool/app@release-1.2.2".to_string(),
+                "rpool/app/data@release-1.2.2".to_string(),
+            ],
+            snapshots_to_replace(&existing, &dataset_list, "release-", "release-1.2.3")
+        );
+
+        assert!(snapshots_to_replace(&existing, &dataset_list, "nightly-", "nightly-1").is_empty());
+    }
+
+    #[test]
+    fn test_snapnames() {
+        let test_time = OffsetDateTime::new_in_offset(
+            Date::from_calendar_date(2024, Month::October, 27).expect("date fail"),
+            Time::from_hms(9, 45, 23).expect("time fail"),
+            UtcOffset::from_hms(0, 0, 0).expect("utc offset fail"),
+        );
+
+        assert_eq!(Ok(vec!["sunday".to_string()]), snapnames("day", test_time));
+        assert_eq!(
+            Ok(vec!["sunday".to_string(), "october".to_string()]),
+            snapnames("day,month", test_time)
+        );
+        assert_eq!(
+            Ok(vec!["sunday".to_string()]),
+            snapnames("day,day", test_time)
+        );
+        assert!(snapnames("day,fortnight", test_time).is_err());
+        assert!(snapnames("day,", test_time).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_all_with_several_names() {
+        let dataset_list = vec!["rpool".to_string(), "rpool/test".to_string()];
+        let mut attempted = Vec::new();
+
+        let failures = snapshot_all(
+            dataset_list,
+            &["sunday".to_string(), "october".to_string()],
+            |snapshot| {
+                attempted.push(snapshot.to_string());
+                Ok(())
+            },
+        );
+
+        assert!(failures.is_empty());
+        assert_eq!(
+            vec![
+                "rpool@sunday".to_string(),
+                "rpool@october".to_string(),
+                "rpool/test@sunday".to_string(),
+                "rpool/test@october".to_string(),
+            ],
+            attempted
+        );
+    }
+
     #[test]
     fn test_snapname() {
         let test_time = OffsetDateTime::new_in_offset(
@@ -294,4 +1362,372 @@ mod test {
 
         assert!(snapname("junk", test_time).is_err());
     }
+
+    #[test]
+    fn test_run_command_with_bad_binary() {
+        let opts = Opts {
+            verbose: 0,
+            noop: false,
+        };
+
+        let mut cmd = Command::new("/no/such/zfs");
+        cmd.arg("snapshot").arg("rpool@test");
+        let err = run_command(cmd, &opts).unwrap_err();
+        assert!(err.starts_with("failed to run '/no/such/zfs snapshot rpool@test'"));
+    }
+
+    #[test]
+    fn test_snapshot_all_with_failures() {
+        let dataset_list = vec![
+            "rpool".to_string(),
+            "rpool/busy".to_string(),
+            "rpool/test".to_string(),
+            "tank/gone".to_string(),
+        ];
+
+        let mut attempted = Vec::new();
+
+        let failures = snapshot_all(dataset_list, &["monday".to_string()], |snapshot| {
+            attempted.push(snapshot.to_string());
+            match snapshot {
+                "rpool/busy@monday" => Err("dataset is busy".to_string()),
+                "tank/gone@monday" => Err("dataset does not exist".to_string()),
+                _ => Ok(()),
+            }
+        });
+
+        assert_eq!(
+            vec![
+                "rpool@monday".to_string(),
+                "rpool/busy@monday".to_string(),
+                "rpool/test@monday".to_string(),
+                "tank/gone@monday".to_string(),
+            ],
+            attempted
+        );
+
+        assert_eq!(
+            vec![
+                (
+                    "rpool/busy@monday".to_string(),
+                    "dataset is busy".to_string()
+                ),
+                (
+                    "tank/gone@monday".to_string(),
+                    "dataset does not exist".to_string()
+                ),
+            ],
+            failures
+        );
+    }
+
+    #[test]
+    fn test_write_failure_log() {
+        let tmp = tempdir().unwrap();
+        let log_file = tmp.path().join("failures");
+
+        let failures = vec![
+            (
+                "rpool/busy@monday".to_string(),
+                "dataset is busy".to_string(),
+            ),
+            (
+                "tank/gone@monday".to_string(),
+                "dataset does not exist".to_string(),
+            ),
+        ];
+
+        write_failure_log(&log_file, &failures).unwrap();
+        assert_eq!(
+            "rpool/busy@monday\ntank/gone@monday\n",
+            fs::read_to_string(&log_file).unwrap()
+        );
+
+        write_failure_log(&log_file, &Failures::new()).unwrap();
+        assert_eq!("", fs::read_to_string(&log_file).unwrap());
+    }
+
+    #[test]
+    fn test_failing_pre_hook_prevents_snapshotting() {
+        let tmp = tempdir().unwrap();
+        let marker = tmp.path().join("post_ran");
+        let opts = Opts {
+            verbose: 0,
+            noop: false,
+        };
+
+        let post = format!("touch {}", marker.display());
+        let run = SnapshotRun {
+            snapnames: &["test".to_string()],
+            pre: Some("exit 1"),
+            post: Some(&post),
+            failure_log: None,
+            if_changed: false,
+            replaces: &[],
+        };
+
+        let result = do_the_snapshotting(vec!["rpool/test".to_string()], &run, opts);
+
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("pre-snapshot hook"));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_failure_log_written_on_error() {
+        let tmp = tempdir().unwrap();
+        let log_file = tmp.path().join("failures");
+        let opts = Opts {
+            verbose: 0,
+            noop: false,
+        };
+
+        let run = SnapshotRun {
+            snapnames: &["test".to_string()],
+            pre: None,
+            post: None,
+            failure_log: Some(&log_file),
+            if_changed: false,
+            replaces: &[],
+        };
+
+        let result = do_the_snapshotting_with(
+            vec!["rpool/busy".to_string(), "rpool/fine".to_string()],
+            &run,
+            &opts,
+            no_writes,
+            |snapshot| match snapshot {
+                "rpool/busy@test" => Err("dataset is busy".to_string()),
+                _ => Ok(()),
+            },
+        );
+
+        assert!(result.is_err());
+        assert_eq!("rpool/busy@test\n", fs::read_to_string(&log_file).unwrap());
+
+        // A run which fails before it starts leaves nothing stale behind.
+        let run = SnapshotRun {
+            pre: Some("exit 1"),
+            ..run
+        };
+
+        let mut attempted = Vec::new();
+        let result = do_the_snapshotting_with(
+            vec!["rpool/busy".to_string(), "rpool/fine".to_string()],
+            &run,
+            &opts,
+            no_writes,
+            |snapshot| {
+                attempted.push(snapshot.to_string());
+                Ok(())
+            },
+        );
+
+        assert!(result.is_err());
+        assert!(attempted.is_empty());
+        assert_eq!(
+            "rpool/busy@test\nrpool/fine@test\n",
+            fs::read_to_string(&log_file).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_snapshot_replacing() {
+        let replaces = vec![
+            "rpool/a@release-1".to_string(),
+            "rpool/a@release-2".to_string(),
+            "rpool/b@release-1".to_string(),
+        ];
+
+        let mut destroyed = Vec::new();
+
+        assert!(snapshot_replacing(
+            "rpool/a@release-3",
+            &replaces,
+            |_| Ok(()),
+            |old| {
+                destroyed.push(old.to_string());
+                Err("dataset is busy".to_string())
+            },
+        )
+        .is_ok());
+
+        assert_eq!(
+            vec![
+                "rpool/a@release-1".to_string(),
+                "rpool/a@release-2".to_string()
+            ],
+            destroyed
+        );
+
+        // The old snapshots stay if the new one can't be taken.
+        destroyed.clear();
+
+        assert_eq!(
+            Err("out of space".to_string()),
+            snapshot_replacing(
+                "rpool/b@release-3",
+                &replaces,
+                |_| Err("out of space".to_string()),
+                |old| {
+                    destroyed.push(old.to_string());
+                    Ok(())
+                },
+            )
+        );
+
+        assert!(destroyed.is_empty());
+    }
+
+    #[test]
+    fn test_replacing_with_if_changed() {
+        let opts = Opts {
+            verbose: 0,
+            noop: false,
+        };
+
+        let replaces = vec![
+            "rpool/changed@release-1".to_string(),
+            "rpool/unchanged@release-1".to_string(),
+        ];
+
+        let run = SnapshotRun {
+            snapnames: &["release-2".to_string()],
+            pre: None,
+            post: None,
+            failure_log: None,
+            if_changed: true,
+            replaces: &replaces,
+        };
+
+        let written = |_: &[String]| -> Result<HashMap<String, u64>, String> {
+            Ok([
+                ("rpool/changed".to_string(), 4096),
+                ("rpool/unchanged".to_string(), 0),
+            ]
+            .into_iter()
+            .collect())
+        };
+
+        let mut taken = Vec::new();
+        let mut destroyed = Vec::new();
+
+        assert!(do_the_snapshotting_with(
+            vec!["rpool/changed".to_string(), "rpool/unchanged".to_string()],
+            &run,
+            &opts,
+            written,
+            |snapshot| {
+                snapshot_replacing(
+                    snapshot,
+                    run.replaces,
+                    |new| {
+                        taken.push(new.to_string());
+                        Ok(())
+                    },
+                    |old| {
+                        destroyed.push(old.to_string());
+                        Ok(())
+                    },
+                )
+            },
+        )
+        .is_ok());
+
+        assert_eq!(vec!["rpool/changed@release-2".to_string()], taken);
+        assert_eq!(vec!["rpool/changed@release-1".to_string()], destroyed);
+    }
+
+    // For runs without --if-changed, which never ask.
+    fn no_writes(_: &[String]) -> Result<HashMap<String, u64>, String> {
+        Ok(HashMap::new())
+    }
+
+    #[test]
+    fn test_not_taken() {
+        assert_eq!(
+            vec![
+                ("a@monday".to_string(), "no".to_string()),
+                ("a@day".to_string(), "no".to_string()),
+                ("b@monday".to_string(), "no".to_string()),
+                ("b@day".to_string(), "no".to_string()),
+            ],
+            not_taken(
+                &["a".to_string(), "b".to_string()],
+                &["monday".to_string(), "day".to_string()],
+                "no"
+            )
+        );
+        assert!(not_taken(&[], &["monday".to_string()], "no").is_empty());
+    }
+
+    #[test]
+    fn test_hooks_run_around_snapshotting() {
+        let tmp = tempdir().unwrap();
+        let pre_marker = tmp.path().join("pre_ran");
+        let post_marker = tmp.path().join("post_ran");
+        let opts = Opts {
+            verbose: 0,
+            noop: false,
+        };
+
+        let pre = format!("touch {}", pre_marker.display());
+        let post = format!("touch {}", post_marker.display());
+        let run = SnapshotRun {
+            snapnames: &["test".to_string()],
+            pre: Some(&pre),
+            post: Some(&post),
+            failure_log: None,
+            if_changed: false,
+            replaces: &[],
+        };
+
+        assert!(do_the_snapshotting(Vec::new(), &run, opts).is_ok());
+
+        assert!(pre_marker.exists());
+        assert!(post_marker.exists());
+
+        let noop_opts = Opts {
+            verbose: 0,
+            noop: true,
+        };
+
+        let noop_marker = tmp.path().join("noop_ran");
+
+        let noop_pre = format!("touch {}", noop_marker.display());
+        let noop_run = SnapshotRun {
+            pre: Some(&noop_pre),
+            post: None,
+            ..run
+        };
+
+        assert!(do_the_snapshotting(Vec::new(), &noop_run, noop_opts).is_ok());
+
+        assert!(!noop_marker.exists());
+    }
+
+    #[test]
+    fn test_run_command() {
+        let opts = Opts {
+            verbose: 0,
+            noop: false,
+        };
+
+        assert!(run_command(Command::new("/bin/true"), &opts).is_ok());
+
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("echo 'dataset is busy' >&2; exit 1");
+        assert!(run_command(cmd, &opts)
+            .unwrap_err()
+            .ends_with(": dataset is busy"));
+
+        let noop_opts = Opts {
+            verbose: 0,
+            noop: true,
+        };
+
+        assert!(run_command(Command::new("/no/such/zfs"), &noop_opts).is_ok());
+    }
 }
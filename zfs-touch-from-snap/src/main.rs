@@ -1,97 +1,279 @@
 use anyhow::anyhow;
-use clap::Parser;
+use clap::{ArgAction, Parser};
+use common::timefmt;
 use common::types::Opts;
+use common::verbose;
 use common::zfs_file;
-use common::zfs_info::dataset_root;
 use filetime::{set_file_times, FileTime};
 use glob::glob;
 use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::{metadata, File};
 use std::io;
 use std::path::{Path, PathBuf};
+use std::thread;
 use std::time::SystemTime;
-use time::{format_description::well_known::Rfc2822, Duration, OffsetDateTime};
+use time::{Duration, OffsetDateTime};
 
 type MTimeMap = BTreeMap<PathBuf, SystemTime>;
 
+// What happened to a single live file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    Changed,
+    Correct,
+    MissingInSnapshot,
+    Failed,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Summary {
+    changed: usize,
+    correct: usize,
+    missing: usize,
+    errors: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "changed {}, already-correct {}, missing-in-snapshot {}, errors {}",
+            self.changed, self.correct, self.missing, self.errors
+        )
+    }
+}
+
 #[derive(Parser)]
 #[clap(version, about = "Aligns file timestamps with those in a given snapshot", long_about = None)]
 struct Cli {
     /// use specified snapshot name, rather than yesterday's
     #[clap(short, long)]
     snapname: Option<String>,
+    /// Take each file's time from the newest snapshot which has it. Give a comma-separated list,
+    /// like --search=monday,tuesday, to only search those snapshots
+    #[clap(
+        long,
+        value_name = "SNAPSHOTS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "",
+        conflicts_with = "snapname"
+    )]
+    search: Option<String>,
     /// Print what would happen, without doing it
     #[clap(short, long)]
     noop: bool,
-    /// Be verbose
-    #[clap(short, long)]
-    verbose: bool,
-    /// directory name
+    /// Be verbose. Give more than once for more detail
+    #[clap(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Read file timestamps with this many threads
+    #[clap(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+    /// file or directory name
     #[arg(required = true)]
     object: Vec<String>,
 }
 
-fn touch_directory(dir: &Path, snapshot_name: &str, opts: &Opts) -> anyhow::Result<()> {
-    let snapshot_top_level = match zfs_file::snapshot_dir_from_file(dir) {
-        Some(snapshot_root) => snapshot_root.join(snapshot_name),
-        None => {
-            return Err(anyhow!(
-                "{} does not appear to be a ZFS filesystem",
-                dir.display()
-            ))
-        }
-    };
+// Where the snapshot copies of a file or directory are: the snapshot directory of its dataset, its
+// path relative to that dataset's root, and the names of the snapshots which might hold it.
+struct SnapshotSource {
+    snapshot_root: PathBuf,
+    relative_path: PathBuf,
+    available: Vec<String>,
+}
 
-    if !snapshot_top_level.exists() {
-        return Err(anyhow!("{} has no ZFS snapshot directory", dir.display()));
-    }
+// Works out the SnapshotSource for a path. This reads the control directory, so is done once for
+// each path, however many snapshots are searched.
+fn snapshot_source(path: &Path) -> anyhow::Result<SnapshotSource> {
+    let snapshot_root = zfs_file::snapshot_dir_from_file(path)
+        .ok_or_else(|| anyhow!("{} does not appear to be a ZFS filesystem", path.display()))?;
 
-    let dataset_root = dataset_root(dir)?;
+    let (dataset_root, relative_path) = zfs_file::split_at_dataset_root(path)?;
+    let available = zfs_file::snapshot_names(&dataset_root)?;
+
+    Ok(SnapshotSource {
+        snapshot_root,
+        relative_path,
+        available,
+    })
+}
 
-    let snapshot_dir = if dir == dataset_root {
+// Finds the copy of the given file or directory in the named snapshot.
+fn snapshot_path(
+    path: &Path,
+    source: &SnapshotSource,
+    snapshot_name: &str,
+) -> anyhow::Result<PathBuf> {
+    zfs_file::check_snapshot_name(snapshot_name, &source.available)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+    let snapshot_top_level = source.snapshot_root.join(snapshot_name);
+
+    let ret = if source.relative_path.as_os_str().is_empty() {
         snapshot_top_level
     } else {
-        let relative_path = dir
-            .to_string_lossy()
-            .replace(&format!("{}/", dataset_root.to_string_lossy()), "");
-
-        snapshot_top_level.join(&relative_path)
+        snapshot_top_level.join(&source.relative_path)
     };
 
-    if !snapshot_dir.exists() {
-        return Err(anyhow!("No source directory: {}", snapshot_dir.display()));
+    if ret.exists() {
+        Ok(ret)
+    } else {
+        Err(anyhow!("No source in snapshot: {}", ret.display()))
+    }
+}
+
+// The copies of the given file or directory in those of the named snapshots which have one, in
+// the same order. If none do, the error is the one from the last snapshot tried.
+fn snapshot_paths(path: &Path, snapshot_names: &[String]) -> anyhow::Result<Vec<PathBuf>> {
+    let source = snapshot_source(path)?;
+    let mut ret = Vec::new();
+    let mut last_error = anyhow!("no snapshots to search");
+
+    for snapshot_name in snapshot_names {
+        match snapshot_path(path, &source, snapshot_name) {
+            Ok(path) => ret.push(path),
+            Err(e) => last_error = e,
+        }
     }
 
-    let live_timestamps = timestamps_for(dir, opts);
-    let snapshot_timestamps = timestamps_for(&snapshot_dir, opts);
-    let mut errs = 0;
+    if ret.is_empty() {
+        Err(last_error)
+    } else {
+        Ok(ret)
+    }
+}
 
-    for (file, ts) in snapshot_timestamps {
-        if let Some(live_ts) = live_timestamps.get(&file) {
-            let target_file = dir.join(&file);
-            if &ts != live_ts {
-                if opts.noop || opts.verbose {
-                    println!("{} -> {}", target_file.display(), format_time(ts));
-                }
+// The snapshots of the dataset holding path, newest first. If any are wanted, only those.
+fn snapshots_to_search(path: &Path, wanted: &[String]) -> anyhow::Result<Vec<String>> {
+    let (dataset_root, _) = zfs_file::split_at_dataset_root(path)?;
+    let newest_first: Vec<String> = zfs_file::snapshot_dirs(&dataset_root)
+        .ok_or_else(|| anyhow!("{} has no snapshots", dataset_root.display()))?
+        .iter()
+        .filter_map(|dir| dir.file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .collect();
 
-                if !opts.noop && set_timestamp(&target_file, ts).is_err() {
-                    errs += 1;
-                }
-            } else if opts.verbose {
-                println!("{} : correct", file.display());
-            }
-        } else if opts.verbose {
-            println!("{} : no source in snapshot", file.display());
-        }
+    searched_snapnames(newest_first, wanted)
+}
+
+// Every wanted snapshot must exist, but the order is always that of the snapshots.
+fn searched_snapnames(newest_first: Vec<String>, wanted: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut sorted = newest_first.clone();
+    sorted.sort();
+
+    for snapname in wanted {
+        zfs_file::check_snapshot_name(snapname, &sorted)?;
     }
 
-    if errs == 0 {
+    Ok(newest_first
+        .into_iter()
+        .filter(|snapname| wanted.is_empty() || wanted.contains(snapname))
+        .collect())
+}
+
+fn touch_directory(
+    dir: &Path,
+    snapshot_names: &[String],
+    jobs: usize,
+    opts: &Opts,
+) -> anyhow::Result<()> {
+    let snapshot_dirs = snapshot_paths(dir, snapshot_names)?;
+    let summary = align_directory(dir, &snapshot_dirs, jobs, opts);
+    println!("{}: {}", dir.display(), summary);
+
+    if summary.errors == 0 {
         Ok(())
     } else {
-        Err(anyhow!("Failed to set times in {} files", errs))
+        Err(anyhow!("Failed to set times in {} files", summary.errors))
     }
 }
 
+// Each live file under dir takes its time from the first of the snapshot directories which has a
+// copy of it, so they should be newest first.
+fn align_directory(dir: &Path, snapshot_dirs: &[PathBuf], jobs: usize, opts: &Opts) -> Summary {
+    let (live_timestamps, snapshot_timestamps) = thread::scope(|s| {
+        let live = s.spawn(|| timestamps_for(dir, jobs, opts));
+        let snapshots: Vec<MTimeMap> = snapshot_dirs
+            .iter()
+            .map(|snapshot_dir| timestamps_for(snapshot_dir, jobs, opts))
+            .collect();
+        (live.join().expect("timestamp thread panicked"), snapshots)
+    });
+
+    let outcomes = live_timestamps.iter().map(|(file, live_ts)| {
+        if let Some(ts) = newest_timestamp(file, &snapshot_timestamps) {
+            align_timestamp(&dir.join(file), file, ts, *live_ts, opts).unwrap_or(Outcome::Failed)
+        } else {
+            verbose!(opts, "{} : no source in snapshot", file.display());
+            Outcome::MissingInSnapshot
+        }
+    });
+
+    tally(outcomes)
+}
+
+fn newest_timestamp(file: &Path, snapshot_timestamps: &[MTimeMap]) -> Option<SystemTime> {
+    snapshot_timestamps
+        .iter()
+        .find_map(|timestamps| timestamps.get(file).copied())
+}
+
+fn tally(outcomes: impl IntoIterator<Item = Outcome>) -> Summary {
+    let mut summary = Summary::default();
+
+    for outcome in outcomes {
+        match outcome {
+            Outcome::Changed => summary.changed += 1,
+            Outcome::Correct => summary.correct += 1,
+            Outcome::MissingInSnapshot => summary.missing += 1,
+            Outcome::Failed => summary.errors += 1,
+        }
+    }
+
+    summary
+}
+
+fn touch_file(file: &Path, snapshot_names: &[String], opts: &Opts) -> anyhow::Result<()> {
+    let snapshot_file = &snapshot_paths(file, snapshot_names)?[0];
+    touch_file_from(file, snapshot_file, opts)
+}
+
+fn touch_file_from(file: &Path, snapshot_file: &Path, opts: &Opts) -> anyhow::Result<()> {
+    let ts = metadata(snapshot_file)?.modified()?;
+    let live_ts = metadata(file)?.modified()?;
+
+    align_timestamp(file, file, ts, live_ts, opts)
+        .map(|_| ())
+        .map_err(|e| anyhow!("Failed to set time on {}: {}", file.display(), e))
+}
+
+// Sets the target file's timestamps to ts, if it isn't already. label is how the file is
+// described when it needs no change. With noop set, files which would be changed count as
+// changed.
+fn align_timestamp(
+    target_file: &Path,
+    label: &Path,
+    ts: SystemTime,
+    live_ts: SystemTime,
+    opts: &Opts,
+) -> io::Result<Outcome> {
+    if ts == live_ts {
+        verbose!(opts, "{} : correct", label.display());
+        return Ok(Outcome::Correct);
+    }
+
+    if opts.noop || opts.verbose > 0 {
+        println!("{} -> {}", target_file.display(), format_time(ts));
+    }
+
+    if !opts.noop {
+        set_timestamp(target_file, ts)?;
+    }
+
+    Ok(Outcome::Changed)
+}
+
 fn set_timestamp(file: &Path, ts: SystemTime) -> io::Result<()> {
     let mtime = FileTime::from_system_time(ts);
     File::open(file)?;
@@ -99,21 +281,46 @@ fn set_timestamp(file: &Path, ts: SystemTime) -> io::Result<()> {
 }
 
 fn format_time(time: SystemTime) -> String {
-    let datetime = OffsetDateTime::from(time);
-    datetime.format(&Rfc2822).unwrap()
+    let timestamp = OffsetDateTime::from(time).unix_timestamp();
+    timefmt::format_local(timestamp, timefmt::RFC2822_FORMAT)
 }
 
-fn timestamps_for(dir: &Path, opts: &Opts) -> MTimeMap {
-    if opts.verbose {
-        println!("Collecting timestamps for {}", dir.display());
-    }
+// Walking the tree is done in one thread, but the files it finds are split between the given
+// number of threads to be statted. The map is the same however many jobs there are.
+fn timestamps_for(dir: &Path, jobs: usize, opts: &Opts) -> MTimeMap {
+    verbose!(opts, "Collecting timestamps for {}", dir.display());
 
     let pattern = format!("{}/**/*", dir.to_string_lossy());
-    glob(&pattern)
+    let paths: Vec<PathBuf> = glob(&pattern)
         .expect("Failed to read glob pattern")
         .filter_map(Result::ok)
+        .collect();
+
+    if jobs <= 1 || paths.len() < 2 {
+        return mtimes(&paths, dir);
+    }
+
+    let chunk_size = paths.len().div_ceil(jobs);
+
+    thread::scope(|s| {
+        let workers: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| s.spawn(move || mtimes(chunk, dir)))
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("timestamp thread panicked"))
+            .collect()
+    })
+}
+
+// Paths are made relative to dir. Anything we can't stat is left out.
+fn mtimes(paths: &[PathBuf], dir: &Path) -> MTimeMap {
+    paths
+        .iter()
         .filter_map(|path| {
-            let metadata = metadata(&path).ok()?;
+            let metadata = metadata(path).ok()?;
             let relative_path = path.strip_prefix(dir).ok()?;
             let modified_time = metadata.modified().ok()?;
             Some((relative_path.to_path_buf(), modified_time))
@@ -142,14 +349,37 @@ fn main() {
         }
     };
 
+    let wanted: Option<Vec<String>> = cli.search.map(|list| {
+        list.split(',')
+            .filter(|snapname| !snapname.is_empty())
+            .map(String::from)
+            .collect()
+    });
+
     for f in cli.object {
         let f = PathBuf::from(f);
-        if !f.is_dir() {
-            println!("WARNING: {} is not a valid directory", f.display());
+
+        let snapnames = match &wanted {
+            Some(wanted) => match snapshots_to_search(&f, wanted) {
+                Ok(snapnames) => snapnames,
+                Err(e) => {
+                    eprintln!("ERROR: {}", e);
+                    std::process::exit(1)
+                }
+            },
+            None => vec![snapname.clone()],
+        };
+
+        let result = if f.is_dir() {
+            touch_directory(&f, &snapnames, cli.jobs.into(), &opts)
+        } else if f.is_file() {
+            touch_file(&f, &snapnames, &opts)
+        } else {
+            println!("WARNING: {} is not a valid file or directory", f.display());
             continue;
-        }
+        };
 
-        if let Err(e) = touch_directory(&f, &snapname, &opts) {
+        if let Err(e) = result {
             eprintln!("ERROR: {}", e);
             std::process::exit(1)
         }
@@ -159,16 +389,19 @@ fn main() {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::fs;
+    use std::time::Duration as StdDuration;
+    use tempfile::tempdir;
     use time::{Date, Month, OffsetDateTime, Time, UtcOffset};
 
     #[test]
     fn test_timestamps_for() {
         let opts = Opts {
-            verbose: false,
+            verbose: 0,
             noop: false,
         };
 
-        let result = timestamps_for(&PathBuf::from("test/resources"), &opts);
+        let result = timestamps_for(&PathBuf::from("test/resources"), 1, &opts);
         let mut actual_files: Vec<PathBuf> = result.keys().cloned().collect();
 
         let mut expected_files = vec![
@@ -188,6 +421,212 @@ mod test {
         assert_eq!(expected_files, actual_files);
     }
 
+    #[test]
+    fn test_timestamps_for_in_parallel() {
+        let opts = Opts {
+            verbose: 0,
+            noop: false,
+        };
+
+        let dir = PathBuf::from("test/resources");
+        let sequential = timestamps_for(&dir, 1, &opts);
+
+        for jobs in [2, 3, 8, 100] {
+            assert_eq!(sequential, timestamps_for(&dir, jobs, &opts));
+        }
+    }
+
+    #[test]
+    fn test_tally() {
+        assert_eq!(Summary::default(), tally(Vec::new()));
+
+        let summary = tally(vec![
+            Outcome::Changed,
+            Outcome::Correct,
+            Outcome::Correct,
+            Outcome::MissingInSnapshot,
+            Outcome::Changed,
+            Outcome::Failed,
+            Outcome::Correct,
+        ]);
+
+        assert_eq!(
+            Summary {
+                changed: 2,
+                correct: 3,
+                missing: 1,
+                errors: 1,
+            },
+            summary
+        );
+
+        assert_eq!(
+            "changed 2, already-correct 3, missing-in-snapshot 1, errors 1",
+            summary.to_string()
+        );
+    }
+
+    #[test]
+    fn test_touch_file_from() {
+        let tmp = tempdir().unwrap();
+        let live_file = tmp.path().join("live");
+        let snapshot_file = tmp.path().join("snapshot");
+        fs::write(&live_file, "live").unwrap();
+        fs::write(&snapshot_file, "snapshot").unwrap();
+
+        let snapshot_ts = SystemTime::UNIX_EPOCH + StdDuration::from_secs(1730563919);
+        set_timestamp(&snapshot_file, snapshot_ts).unwrap();
+
+        let noop_opts = Opts {
+            verbose: 0,
+            noop: true,
+        };
+
+        touch_file_from(&live_file, &snapshot_file, &noop_opts).unwrap();
+        assert_ne!(
+            snapshot_ts,
+            metadata(&live_file).unwrap().modified().unwrap()
+        );
+
+        let opts = Opts {
+            verbose: 0,
+            noop: false,
+        };
+
+        touch_file_from(&live_file, &snapshot_file, &opts).unwrap();
+        assert_eq!(
+            snapshot_ts,
+            metadata(&live_file).unwrap().modified().unwrap()
+        );
+        assert_eq!("live", fs::read_to_string(&live_file).unwrap());
+
+        assert!(touch_file_from(&live_file, &tmp.path().join("missing"), &opts).is_err());
+    }
+
+    #[test]
+    fn test_searched_snapnames() {
+        let newest_first: Vec<String> = ["wednesday", "tuesday", "monday"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        assert_eq!(
+            newest_first,
+            searched_snapnames(newest_first.clone(), &[]).unwrap()
+        );
+
+        assert_eq!(
+            vec!["wednesday".to_string(), "monday".to_string()],
+            searched_snapnames(
+                newest_first.clone(),
+                &["monday".to_string(), "wednesday".to_string()]
+            )
+            .unwrap()
+        );
+
+        assert_eq!(
+            "no snapshot called 'friday'. Choose from: monday, tuesday, wednesday",
+            searched_snapnames(newest_first, &["friday".to_string()])
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_snapshot_path() {
+        let tmp = tempdir().unwrap();
+        let snapshot_root = tmp.path().join(".zfs/snapshot");
+        fs::create_dir_all(snapshot_root.join("monday/dir")).unwrap();
+        fs::create_dir_all(snapshot_root.join("tuesday")).unwrap();
+
+        let source = SnapshotSource {
+            snapshot_root: snapshot_root.clone(),
+            relative_path: PathBuf::from("dir"),
+            available: vec!["monday".to_string(), "tuesday".to_string()],
+        };
+
+        let live = tmp.path().join("dir");
+
+        assert_eq!(
+            snapshot_root.join("monday/dir"),
+            snapshot_path(&live, &source, "monday").unwrap()
+        );
+        assert!(snapshot_path(&live, &source, "tuesday")
+            .unwrap_err()
+            .to_string()
+            .starts_with("No source in snapshot"));
+        assert_eq!(
+            format!(
+                "{}: no snapshot called 'friday'. Choose from: monday, tuesday",
+                live.display()
+            ),
+            snapshot_path(&live, &source, "friday")
+                .unwrap_err()
+                .to_string()
+        );
+
+        let root_source = SnapshotSource {
+            relative_path: PathBuf::new(),
+            ..source
+        };
+
+        assert_eq!(
+            snapshot_root.join("tuesday"),
+            snapshot_path(tmp.path(), &root_source, "tuesday").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_align_directory_from_several_snapshots() {
+        let tmp = tempdir().unwrap();
+        let live = tmp.path().join("live");
+        let tuesday = tmp.path().join("tuesday");
+        let monday = tmp.path().join("monday");
+
+        for dir in [&live, &tuesday, &monday] {
+            fs::create_dir(dir).unwrap();
+        }
+
+        for file in ["in_both", "in_monday", "in_neither"] {
+            fs::write(live.join(file), "live").unwrap();
+        }
+
+        let monday_ts = SystemTime::UNIX_EPOCH + StdDuration::from_secs(1730106000);
+        let tuesday_ts = SystemTime::UNIX_EPOCH + StdDuration::from_secs(1730192400);
+
+        for (dir, file, ts) in [
+            (&monday, "in_both", monday_ts),
+            (&monday, "in_monday", monday_ts),
+            (&tuesday, "in_both", tuesday_ts),
+        ] {
+            fs::write(dir.join(file), "snapshot").unwrap();
+            set_timestamp(&dir.join(file), ts).unwrap();
+        }
+
+        let opts = Opts {
+            verbose: 0,
+            noop: false,
+        };
+
+        let summary = align_directory(&live, &[tuesday, monday], 1, &opts);
+
+        assert_eq!(
+            Summary {
+                changed: 2,
+                correct: 0,
+                missing: 1,
+                errors: 0,
+            },
+            summary
+        );
+
+        let mtime = |file: &str| metadata(live.join(file)).unwrap().modified().unwrap();
+        assert_eq!(tuesday_ts, mtime("in_both"));
+        assert_eq!(monday_ts, mtime("in_monday"));
+        assert_ne!(monday_ts, mtime("in_neither"));
+        assert_ne!(tuesday_ts, mtime("in_neither"));
+    }
+
     #[test]
     fn test_default_snapname() {
         let test_time = OffsetDateTime::new_in_offset(
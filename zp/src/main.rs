@@ -1,8 +1,12 @@
 use clap::{ArgAction, Parser};
-use common::file_copier;
+use common::constants::COPY_BUFFER_SIZE;
 use common::types::ZpZrOpts;
+use common::verbose;
+use common::{file_copier, pathutil, zfs_file};
 use std::ffi::OsStr;
 use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
@@ -11,120 +15,203 @@ struct Cli {
     /// Print what would happen, without doing it
     #[clap(short, long)]
     noop: bool,
-    /// Be verbose
-    #[clap(short, long)]
-    verbose: bool,
+    /// Be verbose. Give more than once for more detail
+    #[clap(short, long, action = ArgAction::Count)]
+    verbose: u8,
     /// By default, existing live files are overwritten. With this option, they are not
     #[clap(short = 'N', long, action=ArgAction::SetTrue)]
     noclobber: bool,
+    /// Add the snapshot name to the promoted file's name, e.g. file.monday
+    #[clap(short, long)]
+    keep_snapname: bool,
+    /// Octal mode for any directories which have to be created. By default, they get the mode of
+    /// the same directory in the snapshot
+    #[clap(long, value_parser = parse_mode)]
+    mkdir_mode: Option<u32>,
+    /// Rather than overwrite existing files, move them under this directory, by their full path
+    #[clap(long)]
+    trash_dir: Option<PathBuf>,
+    /// Print each file in the snapshot, and where it would be promoted to, without promoting
+    /// anything
+    #[clap(long)]
+    list_targets: bool,
     /// File(s) to promote
     #[clap(required = true, num_args = 1..)]
     file_list: Vec<String>,
 }
 
-fn in_snapshot(file: &Path) -> bool {
-    let components: Vec<_> = file.components().map(|s| s.as_os_str()).collect();
+// zfs_file::is_in_snapshot_in() only looks at the path. This makes sure the .zfs in it is a real
+// ZFS control directory, and that we aren't about to copy a file onto itself.
+fn verify_promotion(file: &Path, target: &Path, ctldir: &str) -> Result<(), String> {
+    let zfs_dir = file
+        .ancestors()
+        .find(|dir| dir.file_name() == Some(OsStr::new(ctldir)))
+        .ok_or_else(|| format!("{} is not inside a ZFS snapshot", file.display()))?;
 
-    if let Some(zfs_index) = components.iter().position(|&c| c == OsStr::new(".zfs")) {
-        if let Some(snapshot_idx) = components.get(zfs_index + 1) {
-            return snapshot_idx == &OsStr::new("snapshot");
-        }
+    if !zfs_file::is_control_dir(zfs_dir, ctldir) {
+        return Err(format!(
+            "{} is not a ZFS control directory",
+            zfs_dir.display()
+        ));
     }
 
-    false
+    if file == target {
+        return Err(format!(
+            "refusing to promote {} onto itself",
+            file.display()
+        ));
+    }
+
+    Ok(())
 }
 
-fn target_file(file: &Path) -> Option<PathBuf> {
+fn target_file(file: &Path, keep_snapname: bool, ctldir: &str) -> Option<PathBuf> {
     let components: Vec<_> = file.components().map(|s| s.as_os_str()).collect();
+    let zfs_index = components.iter().position(|&c| c == OsStr::new(ctldir))?;
 
-    if let Some(zfs_index) = components.iter().position(|&c| c == OsStr::new(".zfs")) {
-        let ret = components
-            .iter()
-            .enumerate()
-            .filter_map(|(i, c)| {
-                if i < zfs_index || i > (zfs_index + 2) {
-                    Some(c)
-                } else {
-                    None
-                }
-            })
-            .collect();
-        Some(ret)
+    let ret: PathBuf = components
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)| {
+            if i < zfs_index || i > (zfs_index + 2) {
+                Some(c)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if keep_snapname {
+        let snapname = components.get(zfs_index + 2)?;
+        let mut name = ret.into_os_string();
+        name.push(".");
+        name.push(snapname);
+        Some(PathBuf::from(name))
     } else {
-        None
+        Some(ret)
     }
 }
 
-fn main() {
-    let cli = Cli::parse();
+// Where a file in a snapshot would be promoted to.
+fn target_for(file: &Path, keep_snapname: bool, ctldir: &str) -> Result<PathBuf, String> {
+    if !zfs_file::is_in_snapshot_in(file, ctldir) {
+        return Err(format!("{} is not inside a ZFS snapshot", file.display()));
+    }
 
-    let opts = ZpZrOpts {
-        verbose: cli.verbose,
-        noop: cli.noop,
-        noclobber: cli.noclobber,
-    };
+    target_file(file, keep_snapname, ctldir)
+        .ok_or_else(|| format!("Could not find target for {}", file.display()))
+}
 
-    let mut errs = 0;
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("'{}' is not an octal file mode", mode)),
+    }
+}
 
-    for file in cli.file_list {
-        let file = PathBuf::from(file);
+// Creates target_dir, and any missing parents. Each new directory is given the mode, if there is
+// one, or else the mode of its counterpart under snapshot_dir.
+fn create_target_dir(
+    target_dir: &Path,
+    snapshot_dir: &Path,
+    mode: Option<u32>,
+    opts: &ZpZrOpts,
+) -> io::Result<()> {
+    let missing: Vec<(&Path, &Path)> = target_dir
+        .ancestors()
+        .zip(snapshot_dir.ancestors())
+        .take_while(|(dir, _)| !dir.exists())
+        .collect();
 
-        let file_path = match file.canonicalize() {
-            Ok(path) => path,
-            Err(e) => {
-                eprintln!("Failed to canonicalize {}", e);
-                continue;
-            }
+    for (dir, snapshot_dir) in missing.into_iter().rev() {
+        let mode = match mode {
+            Some(mode) => mode,
+            None => fs::metadata(snapshot_dir)?.permissions().mode() & 0o7777,
         };
 
-        if !in_snapshot(&file_path) {
-            eprintln!("{} is not inside a ZFS snapshot", &file_path.display());
-            errs += 1;
-            continue;
+        verbose!(opts, "Creating {} with mode {:o}", dir.display(), mode);
+
+        if !opts.noop {
+            fs::create_dir(dir)?;
+            fs::set_permissions(dir, fs::Permissions::from_mode(mode))?;
         }
+    }
 
-        let target_file = match target_file(&file_path) {
-            Some(path) => path,
-            None => {
-                eprintln!("Could not find target for {}", &file_path.display());
-                errs += 1;
-                continue;
-            }
-        };
+    Ok(())
+}
 
-        let target_dir = match target_file.parent() {
-            Some(dir) => dir,
-            None => {
-                eprintln!(
+// Copies a file or directory out of a snapshot to its live location. A directory is promoted
+// with everything under it, and it and every directory below it are created, if need be, with
+// their snapshot modes, or mode.
+fn promote(src: &Path, target: &Path, mode: Option<u32>, opts: &ZpZrOpts) -> Result<(), String> {
+    let (target_dir, snapshot_dir) = if src.is_dir() {
+        (target, src)
+    } else {
+        match (target.parent(), src.parent()) {
+            (Some(target_dir), Some(snapshot_dir)) => (target_dir, snapshot_dir),
+            _ => {
+                return Err(format!(
                     "Could not find target directory for {}",
-                    &target_file.display()
-                );
-                errs += 1;
-                continue;
+                    target.display()
+                ))
             }
-        };
+        }
+    };
 
-        if !target_dir.exists() {
-            if opts.verbose {
-                println!("Creating {}", target_dir.display());
-            }
+    create_target_dir(target_dir, snapshot_dir, mode, opts)
+        .map_err(|e| format!("Failed to create directory {}: {}", target_dir.display(), e))?;
 
-            if !opts.noop {
-                if let Err(e) = fs::create_dir_all(target_dir) {
-                    eprintln!("Failed to create directory {}: {}", target_dir.display(), e);
-                    errs += 1;
-                    continue;
-                }
-            }
-        }
+    let mkdir = |dir: &Path, snapshot_dir: &Path| create_target_dir(dir, snapshot_dir, mode, opts);
 
-        if let Err(e) = file_copier::copy_file(&file, &target_file, &opts) {
-            eprintln!(
-                "Failed to copy {} to {}: {}",
-                &file.display(),
-                &target_file.display(),
-                e,
-            );
+    file_copier::copy_file_with(src, target, opts, &mkdir).map_err(|e| {
+        format!(
+            "Failed to copy {} to {}: {}",
+            src.display(),
+            target.display(),
+            e
+        )
+    })?;
+
+    Ok(())
+}
+
+// Everything which happens to one of the files on the command line. Errors name the file, so
+// they make sense among many.
+fn promote_file(file: &str, cli: &Cli, opts: &ZpZrOpts, ctldir: &str) -> Result<(), String> {
+    let file_path = pathutil::expand(file)
+        .canonicalize()
+        .map_err(|e| format!("Failed to canonicalize {}: {}", file, e))?;
+
+    let target_file = target_for(&file_path, cli.keep_snapname, ctldir)?;
+    verify_promotion(&file_path, &target_file, ctldir)?;
+
+    if cli.list_targets {
+        println!("{} -> {}", file_path.display(), target_file.display());
+        return Ok(());
+    }
+
+    promote(&file_path, &target_file, cli.mkdir_mode, opts)
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let opts = ZpZrOpts {
+        verbose: cli.verbose,
+        noop: cli.noop,
+        noclobber: cli.noclobber,
+        buffer_size: COPY_BUFFER_SIZE,
+        trash_dir: cli.trash_dir.clone(),
+        preserve_owner: false,
+        preserve_xattr: false,
+    };
+
+    let ctldir = zfs_file::control_dir_name();
+    let mut errs = 0;
+
+    for file in &cli.file_list {
+        if let Err(e) = promote_file(file, &cli, &opts, &ctldir) {
+            eprintln!("{}", e);
             errs += 1;
         }
     }
@@ -138,26 +225,282 @@ fn main() {
 #[cfg(test)]
 mod test {
     use super::*;
+    use tempfile::tempdir;
 
     #[test]
     fn test_target_file() {
         assert_eq!(
             Some(PathBuf::from("/test/dir/file")),
-            target_file(&PathBuf::from("/test/.zfs/snapshot/monday/dir/file"))
+            target_file(
+                &PathBuf::from("/test/.zfs/snapshot/monday/dir/file"),
+                false,
+                ".zfs"
+            )
         );
 
         assert_eq!(
             Some(PathBuf::from("/test/u01/u02/mtpt/deep/dir/file")),
-            target_file(&PathBuf::from(
-                "/test/u01/u02/mtpt/.zfs/snapshot/test/deep/dir/file"
-            ))
+            target_file(
+                &PathBuf::from("/test/u01/u02/mtpt/.zfs/snapshot/test/deep/dir/file"),
+                false,
+                ".zfs"
+            )
+        );
+
+        assert_eq!(
+            None,
+            target_file(&PathBuf::from("/test/dir/file"), false, ".zfs")
+        );
+    }
+
+    #[test]
+    fn test_target_for() {
+        let mappings: Vec<Result<PathBuf, String>> = [
+            "/test/.zfs/snapshot/monday/dir/file",
+            "/test/.zfs/snapshot/monday/dir",
+            "/test/deep/mtpt/.zfs/snapshot/tuesday/file",
+            "/test/dir/file",
+            "/test/.zfs",
+        ]
+        .iter()
+        .map(|file| target_for(Path::new(file), false, ".zfs"))
+        .collect();
+
+        assert_eq!(
+            vec![
+                Ok(PathBuf::from("/test/dir/file")),
+                Ok(PathBuf::from("/test/dir")),
+                Ok(PathBuf::from("/test/deep/mtpt/file")),
+                Err("/test/dir/file is not inside a ZFS snapshot".to_string()),
+                Err("/test/.zfs is not inside a ZFS snapshot".to_string()),
+            ],
+            mappings
+        );
+
+        assert_eq!(
+            Ok(PathBuf::from("/test/dir/file.monday")),
+            target_for(
+                Path::new("/test/.zfs/snapshot/monday/dir/file"),
+                true,
+                ".zfs"
+            )
         );
     }
 
     #[test]
-    fn test_in_snapshot() {
-        assert!(in_snapshot(&PathBuf::from("/test/.zfs/snapshot/monday/d")));
-        assert!(!in_snapshot(&PathBuf::from("/build/dir")));
-        assert!(!in_snapshot(&PathBuf::from("/test/snapshot/dir")));
+    fn test_target_file_for_directory() {
+        assert_eq!(
+            Some(PathBuf::from("/test/dir")),
+            target_file(
+                &PathBuf::from("/test/.zfs/snapshot/monday/dir/"),
+                false,
+                ".zfs"
+            )
+        );
+
+        assert_eq!(
+            Some(PathBuf::from("/test")),
+            target_file(&PathBuf::from("/test/.zfs/snapshot/monday"), false, ".zfs")
+        );
+    }
+
+    #[test]
+    fn test_promote_directory() {
+        let tmp = tempdir().unwrap();
+        let snapshot_dir = tmp.path().join(".zfs/snapshot/monday/dir");
+        fs::create_dir_all(snapshot_dir.join("sub")).unwrap();
+        fs::write(snapshot_dir.join("a"), "file a").unwrap();
+        fs::write(snapshot_dir.join("sub/b"), "file b").unwrap();
+        fs::set_permissions(&snapshot_dir, fs::Permissions::from_mode(0o750)).unwrap();
+        fs::set_permissions(snapshot_dir.join("sub"), fs::Permissions::from_mode(0o700)).unwrap();
+
+        let opts = ZpZrOpts::default();
+
+        let target = target_file(&snapshot_dir, false, ".zfs").unwrap();
+        assert_eq!(tmp.path().join("dir"), target);
+
+        let noop_opts = ZpZrOpts {
+            noop: true,
+            ..Default::default()
+        };
+
+        promote(&snapshot_dir, &target, None, &noop_opts).unwrap();
+        assert!(!target.exists());
+
+        promote(&snapshot_dir, &target, None, &opts).unwrap();
+
+        assert_eq!("file a", fs::read_to_string(target.join("a")).unwrap());
+        assert_eq!("file b", fs::read_to_string(target.join("sub/b")).unwrap());
+        assert_eq!(
+            0o750,
+            fs::metadata(&target).unwrap().permissions().mode() & 0o7777
+        );
+        assert_eq!(
+            0o700,
+            fs::metadata(target.join("sub"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        );
+
+        fs::write(snapshot_dir.join("a"), "changed").unwrap();
+        promote(&snapshot_dir, &target, None, &opts).unwrap();
+        assert_eq!("changed", fs::read_to_string(target.join("a")).unwrap());
+
+        // A given mode goes on every directory created.
+        let moded_target = target_file(&snapshot_dir, true, ".zfs").unwrap();
+        promote(&snapshot_dir, &moded_target, Some(0o755), &opts).unwrap();
+
+        for dir in [moded_target.clone(), moded_target.join("sub")] {
+            assert_eq!(
+                0o755,
+                fs::metadata(&dir).unwrap().permissions().mode() & 0o7777
+            );
+        }
+    }
+
+    #[test]
+    fn test_target_file_keep_snapname() {
+        assert_eq!(
+            Some(PathBuf::from("/test/dir/file.monday")),
+            target_file(
+                &PathBuf::from("/test/.zfs/snapshot/monday/dir/file"),
+                true,
+                ".zfs"
+            )
+        );
+
+        assert_eq!(
+            Some(PathBuf::from("/test/dir/file.txt.2024-10-27_09:45")),
+            target_file(
+                &PathBuf::from("/test/.zfs/snapshot/2024-10-27_09:45/dir/file.txt"),
+                true,
+                ".zfs"
+            )
+        );
+
+        assert_eq!(
+            Some(PathBuf::from("/test.tuesday")),
+            target_file(&PathBuf::from("/test/.zfs/snapshot/tuesday"), true, ".zfs")
+        );
+
+        assert_eq!(
+            None,
+            target_file(&PathBuf::from("/test/.zfs/snapshot"), true, ".zfs")
+        );
+    }
+
+    #[test]
+    fn test_verify_promotion_with_decoy() {
+        let tmp = tempdir().unwrap();
+        let decoy_dir = tmp.path().join(".zfs/snapshot/monday/dir");
+        fs::create_dir_all(&decoy_dir).unwrap();
+        let file = decoy_dir.join("file");
+        fs::write(&file, "not in a snapshot").unwrap();
+
+        assert!(zfs_file::is_in_snapshot_in(&file, ".zfs"));
+
+        let target = target_file(&file, false, ".zfs").unwrap();
+        assert_eq!(tmp.path().join("dir/file"), target);
+
+        assert!(verify_promotion(&file, &target, ".zfs")
+            .unwrap_err()
+            .ends_with("is not a ZFS control directory"));
+
+        assert!(
+            verify_promotion(&tmp.path().join("dir/file"), &target, ".zfs")
+                .unwrap_err()
+                .ends_with("is not inside a ZFS snapshot")
+        );
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(Ok(0o755), parse_mode("755"));
+        assert_eq!(Ok(0o2775), parse_mode("2775"));
+        assert!(parse_mode("789").is_err());
+        assert!(parse_mode("17777").is_err());
+        assert!(parse_mode("rwx").is_err());
+    }
+
+    #[test]
+    fn test_create_target_dir() {
+        let tmp = tempdir().unwrap();
+        let snapshot_dir = tmp.path().join("snap/a/b");
+        fs::create_dir_all(&snapshot_dir).unwrap();
+        fs::set_permissions(tmp.path().join("snap/a"), fs::Permissions::from_mode(0o750)).unwrap();
+        fs::set_permissions(&snapshot_dir, fs::Permissions::from_mode(0o700)).unwrap();
+        fs::create_dir(tmp.path().join("live")).unwrap();
+
+        let mode_of = |dir: &Path| fs::metadata(dir).unwrap().permissions().mode() & 0o7777;
+
+        let opts = ZpZrOpts::default();
+
+        let target_dir = tmp.path().join("live/a/b");
+        create_target_dir(&target_dir, &snapshot_dir, None, &opts).unwrap();
+        assert_eq!(0o750, mode_of(&tmp.path().join("live/a")));
+        assert_eq!(0o700, mode_of(&target_dir));
+
+        let target_dir = tmp.path().join("other/a/b");
+        create_target_dir(&target_dir, &snapshot_dir, Some(0o711), &opts).unwrap();
+        assert_eq!(0o711, mode_of(&tmp.path().join("other")));
+        assert_eq!(0o711, mode_of(&target_dir));
+    }
+
+    #[test]
+    fn test_promote_file() {
+        let tmp = tempdir().unwrap();
+        let snapshot_dir = tmp.path().join(".snapshots/snapshot/monday");
+        fs::create_dir_all(&snapshot_dir).unwrap();
+        fs::write(snapshot_dir.join("file"), "in a snapshot").unwrap();
+        fs::write(tmp.path().join("live"), "not in a snapshot").unwrap();
+
+        let cli = Cli {
+            noop: false,
+            verbose: 0,
+            noclobber: false,
+            keep_snapname: false,
+            mkdir_mode: None,
+            trash_dir: None,
+            list_targets: false,
+            file_list: Vec::new(),
+        };
+
+        let opts = ZpZrOpts::default();
+
+        let missing = tmp.path().join("missing");
+        let err = promote_file(missing.to_str().unwrap(), &cli, &opts, ".snapshots").unwrap_err();
+        assert!(err.starts_with(&format!("Failed to canonicalize {}: ", missing.display())));
+
+        let live = tmp.path().join("live").canonicalize().unwrap();
+        assert_eq!(
+            Err(format!("{} is not inside a ZFS snapshot", live.display())),
+            promote_file(live.to_str().unwrap(), &cli, &opts, ".snapshots")
+        );
+
+        let in_snapshot = snapshot_dir.join("file");
+        assert!(promote_file(in_snapshot.to_str().unwrap(), &cli, &opts, ".snapshots").is_ok());
+        assert_eq!(
+            "in a snapshot",
+            fs::read_to_string(tmp.path().join("file")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_custom_control_dir() {
+        let tmp = tempdir().unwrap();
+        let snapshot_dir = tmp.path().join(".snapshots/snapshot/monday/dir");
+        fs::create_dir_all(&snapshot_dir).unwrap();
+        let file = snapshot_dir.join("file");
+        fs::write(&file, "in a snapshot").unwrap();
+
+        assert!(zfs_file::is_in_snapshot_in(&file, ".snapshots"));
+        assert!(!zfs_file::is_in_snapshot_in(&file, ".zfs"));
+
+        let target = target_file(&file, false, ".snapshots").unwrap();
+        assert_eq!(tmp.path().join("dir/file"), target);
+        assert_eq!(None, target_file(&file, false, ".zfs"));
+        assert!(verify_promotion(&file, &target, ".snapshots").is_ok());
     }
 }
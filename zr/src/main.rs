@@ -1,69 +1,251 @@
 mod types;
 mod user_interaction;
 
-use crate::types::{Candidate, Candidates, CopyAction};
+use crate::types::{Candidate, Candidates, CopyAction, NotOnZfs};
 use clap::{ArgAction, Parser};
-use common::constants::DIFF;
-use common::types::ZpZrOpts;
-use common::{file_copier, zfs_info};
+use common::constants::{COPY_BUFFER_SIZE, DIFF, ZFS_SNAPDIR_ENV};
+use common::file_copier::CopyStats;
+use common::types::{MountList, Opts, ZpZrOpts};
+use common::verbose;
+use common::{duration, file_copier, pathutil, zfs_file, zfs_info};
+use seahash::SeaHasher;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::io::Read;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::{fs, io};
 
+const MAX_FIND_RESULTS: usize = 50;
+
 #[derive(Parser)]
 #[clap(version, about = "Restores files from ZFS snapshots")]
 struct Cli {
     /// Print what would happen, without doing it
     #[clap(short, long)]
     noop: bool,
-    /// Be verbose
-    #[clap(short, long)]
-    verbose: bool,
+    /// Be verbose. Give more than once for more detail
+    #[clap(short, long, action = ArgAction::Count)]
+    verbose: u8,
     /// Automatically recover the newest backup
     #[clap(short, long)]
     auto: bool,
+    /// Automatically recover the oldest backup
+    #[clap(short = 'o', long, conflicts_with = "auto")]
+    auto_oldest: bool,
     /// By default, existing live files are overwritten. With this option, they are not
     #[clap(short = 'N', long, action=ArgAction::SetTrue)]
     noclobber: bool,
+    /// Don't ask for confirmation before overwriting a live file which is newer than the restored
+    /// version
+    #[clap(short, long)]
+    force: bool,
+    /// If a file isn't found, look for files whose names contain the given name in the most recent
+    /// snapshot
+    #[clap(short = 'F', long)]
+    find: bool,
+    /// Extension given to the live file when it is kept with the 'k' command
+    #[clap(long, default_value = "backup")]
+    backup_ext: String,
+    /// Rather than overwrite existing files, move them under this directory, by their full path
+    #[clap(long)]
+    trash_dir: Option<PathBuf>,
+    /// Give restored files the owner and group they have in the snapshot. This needs root
+    #[clap(long)]
+    preserve_owner: bool,
+    /// Give restored files the extended attributes they have in the snapshot, where the platform
+    /// supports them
+    #[clap(long)]
+    preserve_xattr: bool,
+    /// Show a short checksum of each version, to tell apart files of the same size. This reads
+    /// every version in full, so can be slow
+    #[clap(short, long)]
+    checksums: bool,
+    /// Only look in snapshots taken before this. Either a date, like 2024-10-27 or
+    /// "2024-10-27 09:45", or an age, like 3d
+    #[clap(short, long, value_parser = duration::parse_cutoff)]
+    before: Option<i64>,
+    /// Never colour the output. Colour is also off if NO_COLOR is set, or output is not a terminal
+    #[clap(long)]
+    no_color: bool,
+    /// Also look in the snapshots of the datasets above the file's own, in case it has moved
+    /// between datasets
+    #[clap(short = 'R', long)]
+    recursive_datasets: bool,
+    /// List the versions of each file, and exit without restoring anything
+    #[clap(short, long)]
+    list: bool,
+    /// With --list, print each file's versions as a line of JSON
+    #[clap(long, requires = "list")]
+    json: bool,
+    /// Only look in the N most recent snapshots. Useful on datasets with very many snapshots
+    #[clap(short, long)]
+    max_snapshots: Option<usize>,
+    /// Look for snapshots in this directory, rather than .zfs/snapshot. A relative path is taken
+    /// from the root of each dataset. Defaults to $ZFS_SNAPDIR, if that is set
+    #[clap(long)]
+    snapdir: Option<PathBuf>,
     /// File(s) to restore
     #[clap(required = true, num_args = 1..)]
     file_list: Vec<String>,
 }
 
-fn all_snapshot_dirs(dataset_root: &Path) -> Option<Vec<PathBuf>> {
-    let snapshot_root = dataset_root.join(".zfs").join("snapshot");
+impl Cli {
+    // True if zr should choose a version itself, rather than asking.
+    fn unattended(&self) -> bool {
+        self.auto || self.auto_oldest
+    }
 
-    if snapshot_root.exists() {
-        std::fs::read_dir(snapshot_root)
-            .ok()?
-            .map(|entry| entry.ok().map(|f| f.path()))
-            .collect()
-    } else {
-        None
+    fn snapdir(&self) -> PathBuf {
+        snapdir_from(self.snapdir.clone(), std::env::var_os(ZFS_SNAPDIR_ENV))
     }
 }
 
+// The flag beats the environment, and both beat the control directory, which may itself have
+// been renamed with ZFS_TOOLS_CTLDIR.
+fn snapdir_from(flag: Option<PathBuf>, env: Option<std::ffi::OsString>) -> PathBuf {
+    flag.filter(|dir| !dir.as_os_str().is_empty())
+        .or_else(|| env.filter(|dir| !dir.is_empty()).map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from(zfs_file::control_dir_name()).join("snapshot"))
+}
+
+// The directories of every snapshot of the dataset mounted at root, newest first. Joining an
+// absolute snapdir gives the snapdir itself.
+fn all_snapshot_dirs(root: &Path, snapdir: &Path) -> Option<Vec<PathBuf>> {
+    zfs_file::snapshot_dirs_at(&root.join(snapdir))
+}
+
+// Sorts snapshot directories newest-first, and keeps no more than max_snapshots of them.
+fn snapshot_dirs_to_probe(
+    snapshot_dirs: Vec<PathBuf>,
+    max_snapshots: Option<usize>,
+) -> Vec<PathBuf> {
+    let mut ret = zfs_file::newest_first(snapshot_dirs);
+
+    if let Some(max) = max_snapshots {
+        ret.truncate(max);
+    }
+
+    ret
+}
+
+// The file, and any number of the directories above it, may have been removed. So we canonicalize
+// the nearest ancestor which does exist, and put the missing parts back on the end. Returns that
+// ancestor and the full path.
+fn resolve_missing_path(file: &Path) -> io::Result<(PathBuf, PathBuf)> {
+    for ancestor in file.ancestors().skip(1) {
+        if ancestor.exists() {
+            let existing = ancestor.canonicalize()?;
+            let missing = file.strip_prefix(ancestor).unwrap_or(file);
+            let resolved = existing.join(missing);
+            return Ok((existing, resolved));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No part of {} exists", file.display()),
+    ))
+}
+
+// A filesystem with a snapshot directory will do, even if it isn't ZFS: it may be a bind mount,
+// found through ZFS_TOOLS_CTLDIR or --snapdir.
+fn on_zfs(root: &Path, snapdir: &Path, mnttab_mounts: &MountList) -> bool {
+    all_snapshot_dirs(root, snapdir).is_some()
+        || zfs_info::dataset_name_for_root(root, &MountList::new(), mnttab_mounts).is_some()
+}
+
+// Creation times of the snapshots of the dataset mounted at the given root, keyed by snapshot name.
+type CreationTimes<'a> = &'a dyn Fn(&Path) -> anyhow::Result<HashMap<String, i64>>;
+
 fn restore_action(file: &Path, cli: &Cli) -> anyhow::Result<CopyAction> {
-    // file may well not exist, so let's assume user error if its PARENT isn't there
-    let parent = file.parent().unwrap();
-    let target_dir = parent.canonicalize()?;
-    let filesystem_root = zfs_info::dataset_root(&target_dir)?;
-    let mut candidates = candidates(&filesystem_root, file)?;
+    restore_action_with(file, cli, &snapshot_creation_times, cli.find)
+}
+
+// Like restore_action(), but gets snapshot creation times from the given function. A file picked
+// with --find is restored with find off, so a file which turns out to have no usable versions
+// can't send us round again.
+fn restore_action_with(
+    file: &Path,
+    cli: &Cli,
+    creation_times: CreationTimes,
+    find: bool,
+) -> anyhow::Result<CopyAction> {
+    let (existing_dir, file) = resolve_missing_path(file)?;
+    let file = file.as_path();
+    let filesystem_root = zfs_info::dataset_root(&existing_dir)?;
+    let snapdir = cli.snapdir();
+
+    if let Ok(mnttab_mounts) = zfs_info::get_mnttab_filesystems() {
+        if !on_zfs(&filesystem_root, &snapdir, &mnttab_mounts) {
+            return Err(NotOnZfs(file.to_path_buf()).into());
+        }
+    }
+
+    let mut roots = vec![filesystem_root.clone()];
+
+    if cli.recursive_datasets {
+        roots.extend(ancestor_roots(&filesystem_root, &snapdir));
+    }
+
+    let mut candidates = candidates_in_roots(
+        &roots,
+        &snapdir,
+        file,
+        cli.max_snapshots,
+        &Opts {
+            verbose: cli.verbose,
+            noop: cli.noop,
+        },
+        cli.checksums,
+    )?;
+
+    // If the cutoff leaves nothing, the file did exist, so there's nothing for --find to do.
+    let cut_off_everything = if let Some(cutoff) = cli.before {
+        let found_any = !candidates.is_empty();
+        candidates =
+            candidates_before_in_roots(candidates, &filesystem_root, creation_times, cutoff)?;
+        found_any && candidates.is_empty()
+    } else {
+        false
+    };
+
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.mtime));
+
+    // Tools want an empty list, rather than a message, when there is nothing.
+    if cli.json {
+        println!("{}", candidates_json(&candidates)?);
+        return Ok(None);
+    }
+
+    if cut_off_everything {
+        println!("No matches found before the cutoff.");
+        return Ok(None);
+    }
 
     if candidates.is_empty() {
+        if find {
+            if let Some(found) = find_and_choose(&filesystem_root, file, cli, creation_times)? {
+                return restore_action_with(&found, cli, creation_times, false);
+            }
+        }
+
         println!("No matches found.");
         return Ok(None);
     }
 
-    candidates.sort_by_key(|c| std::cmp::Reverse(c.mtime));
+    let original_file = original_details(file, cli.checksums)?;
 
-    let original_file = original_details(file)?;
+    if cli.list {
+        user_interaction::print_options(&original_file, &candidates);
+        return Ok(None);
+    }
 
-    let choice_tuple = if cli.auto {
-        Some((0_usize, None))
+    let choice_tuple = if let Some(index) = auto_choice(&candidates, cli) {
+        Some((index, None, None))
     } else {
-        user_interaction::print_options(original_file, &candidates);
+        user_interaction::print_options(&original_file, &candidates);
         let user_input = user_interaction::get_choice()?;
         user_interaction::parse_choice(&user_input)
     };
@@ -72,7 +254,7 @@ fn restore_action(file: &Path, cli: &Cli) -> anyhow::Result<CopyAction> {
         return Ok(None);
     }
 
-    let (candidate_index, command_option) = choice_tuple.unwrap();
+    let (candidate_index, command_option, other_index) = choice_tuple.unwrap();
 
     let candidate_object = match candidates.get(candidate_index) {
         Some(obj) => obj,
@@ -82,11 +264,35 @@ fn restore_action(file: &Path, cli: &Cli) -> anyhow::Result<CopyAction> {
         }
     };
 
+    let keeping = command_option.as_deref() == Some("k");
+
+    if !keeping
+        && command_option.as_deref() != Some("d")
+        && needs_confirmation(
+            original_file.as_ref(),
+            candidate_object,
+            cli.unattended() || cli.force,
+        )
+        && !user_interaction::confirm(&format!(
+            "{} is newer than the version in {}. Overwrite it?",
+            file.display(),
+            candidate_object.label()
+        ))?
+    {
+        return Ok(None);
+    }
+
     if let Some(command) = command_option {
         match command.as_str() {
             "k" => backup_target(file, cli)?,
             "d" => {
-                diff_files(&candidate_object.path, file);
+                match other_index {
+                    Some(index) => match candidates.get(index) {
+                        Some(other) => diff_files(&candidate_object.path, &other.path),
+                        None => eprintln!("Cannot find requested item"),
+                    },
+                    None => diff_files(&candidate_object.path, file),
+                }
                 return Ok(None);
             }
             &_ => (),
@@ -96,6 +302,198 @@ fn restore_action(file: &Path, cli: &Cli) -> anyhow::Result<CopyAction> {
     Ok(Some((candidate_object.path.clone(), file.to_path_buf())))
 }
 
+// Maps the names of the snapshots of the dataset mounted at root to their creation times.
+fn snapshot_creation_times(root: &Path) -> anyhow::Result<HashMap<String, i64>> {
+    let zfs_mounts = zfs_info::get_mounted_filesystems()?;
+    let mnttab_mounts = zfs_info::get_mnttab_filesystems()?;
+
+    let (_root, dataset) = zfs_info::dataset_root_checked(root, &zfs_mounts, &mnttab_mounts)?;
+
+    zfs_info::snapshot_creation(&dataset)
+}
+
+// Keeps candidates from snapshots created before the cutoff. If we don't know when a snapshot was
+// created, we can't say it's old enough.
+fn candidates_before(
+    candidates: Candidates,
+    creation: &HashMap<String, i64>,
+    cutoff: i64,
+) -> Candidates {
+    candidates
+        .into_iter()
+        .filter(|c| {
+            creation
+                .get(&c.snapname)
+                .is_some_and(|created| *created < cutoff)
+        })
+        .collect()
+}
+
+// Applies candidates_before() to the versions from each dataset root in turn, with the creation
+// times of that dataset's own snapshots.
+fn candidates_before_in_roots(
+    candidates: Candidates,
+    filesystem_root: &Path,
+    creation_times: CreationTimes,
+    cutoff: i64,
+) -> anyhow::Result<Candidates> {
+    let mut by_root: Vec<(PathBuf, Candidates)> = Vec::new();
+
+    for candidate in candidates {
+        let root = candidate
+            .root
+            .clone()
+            .unwrap_or_else(|| filesystem_root.to_path_buf());
+
+        match by_root.iter_mut().find(|(r, _)| *r == root) {
+            Some((_, list)) => list.push(candidate),
+            None => by_root.push((root, vec![candidate])),
+        }
+    }
+
+    let mut ret = Candidates::new();
+
+    for (root, list) in by_root {
+        ret.extend(candidates_before(list, &creation_times(&root)?, cutoff));
+    }
+
+    Ok(ret)
+}
+
+// The snapshot directory version of candidates_before().
+fn snapshot_dirs_before(
+    snapshot_dirs: Vec<PathBuf>,
+    creation: &HashMap<String, i64>,
+    cutoff: i64,
+) -> Vec<PathBuf> {
+    snapshot_dirs
+        .into_iter()
+        .filter(|dir| {
+            dir.file_name()
+                .and_then(|name| creation.get(name.to_string_lossy().as_ref()))
+                .is_some_and(|created| *created < cutoff)
+        })
+        .collect()
+}
+
+// Candidates are sorted newest first, so --auto takes the first and --auto-oldest the last. None
+// means the user has to choose.
+fn auto_choice(candidates: &Candidates, cli: &Cli) -> Option<usize> {
+    if cli.auto {
+        Some(0)
+    } else if cli.auto_oldest {
+        candidates.len().checked_sub(1)
+    } else {
+        None
+    }
+}
+
+// Overwriting a live file with an older version could lose recent work, so that needs a second
+// opinion, unless the user has told us not to ask.
+fn needs_confirmation(original: Option<&Candidate>, chosen: &Candidate, dont_ask: bool) -> bool {
+    !dont_ask && original.is_some_and(|live| live.mtime > chosen.mtime)
+}
+
+// Looks in the newest snapshot, under the directory which should hold the file, for anything whose
+// name contains the file's name. With --before, that is the newest snapshot before the cutoff.
+// Returns the live path of whichever the user picks.
+fn find_and_choose(
+    filesystem_root: &Path,
+    file: &Path,
+    cli: &Cli,
+    creation_times: CreationTimes,
+) -> anyhow::Result<Option<PathBuf>> {
+    let (Some(live_dir), Some(fragment)) = (file.parent(), file.file_name()) else {
+        return Ok(None);
+    };
+
+    let Some(relative_dir) = zfs_file::path_relative_to_root(live_dir, filesystem_root) else {
+        return Ok(None);
+    };
+
+    let mut all_dirs = all_snapshot_dirs(filesystem_root, &cli.snapdir()).unwrap_or_default();
+
+    if let Some(cutoff) = cli.before {
+        all_dirs = snapshot_dirs_before(all_dirs, &creation_times(filesystem_root)?, cutoff);
+    }
+
+    let Some(newest) = snapshot_dirs_to_probe(all_dirs, Some(1)).pop() else {
+        return Ok(None);
+    };
+
+    let (matches, truncated) =
+        find_in_snapshot(&newest.join(relative_dir), &fragment.to_string_lossy());
+
+    if truncated {
+        eprintln!(
+            "WARNING: more than {} matches, only showing the first {}. Try a longer name.",
+            MAX_FIND_RESULTS, MAX_FIND_RESULTS
+        );
+    }
+
+    let choice = match matches.len() {
+        0 => None,
+        1 if cli.unattended() => matches.first().cloned(),
+        _ => {
+            user_interaction::print_matches(&matches);
+            user_interaction::get_match_choice(&matches)?
+        }
+    };
+
+    Ok(choice.map(|relative| live_dir.join(relative)))
+}
+
+// Recursively searches dir for files and directories whose names contain the fragment, returning
+// their paths relative to dir, sorted. Stops after MAX_FIND_RESULTS, with the second value being
+// true if it did.
+fn find_in_snapshot(dir: &Path, fragment: &str) -> (Vec<PathBuf>, bool) {
+    let mut ret = Vec::new();
+    let truncated = find_in_dir(dir, dir, fragment, &mut ret);
+    ret.sort();
+    (ret, truncated)
+}
+
+fn find_in_dir(root: &Path, dir: &Path, fragment: &str, found: &mut Vec<PathBuf>) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        if entry.file_name().to_string_lossy().contains(fragment) {
+            if found.len() == MAX_FIND_RESULTS {
+                return true;
+            }
+
+            if let Ok(relative) = path.strip_prefix(root) {
+                found.push(relative.to_path_buf());
+            }
+        }
+
+        if path.is_dir() && !path.is_symlink() && find_in_dir(root, &path, fragment, found) {
+            return true;
+        }
+    }
+
+    false
+}
+
+// Recreates any directories which were removed along with the file, then copies it back.
+fn restore_file(src: &Path, dest: &Path, opts: &ZpZrOpts) -> io::Result<CopyStats> {
+    if let Some(dest_dir) = dest.parent() {
+        if !dest_dir.exists() {
+            verbose!(opts, "Creating {}", dest_dir.display());
+
+            if !opts.noop {
+                fs::create_dir_all(dest_dir)?;
+            }
+        }
+    }
+
+    file_copier::copy_file(src, dest, opts)
+}
+
 fn diff_files(source_file: &Path, target_file: &Path) {
     let mut cmd = Command::new(DIFF);
     cmd.arg(source_file).arg(target_file);
@@ -114,26 +512,104 @@ fn diff_files(source_file: &Path, target_file: &Path) {
 }
 
 fn backup_target(src: &Path, cli: &Cli) -> io::Result<()> {
-    let dest = src.with_extension("backup");
+    let dest = backup_destination(src, &cli.backup_ext);
 
-    if cli.verbose || cli.noop {
+    if cli.verbose > 0 || cli.noop {
         println!("{} -> {}", src.display(), dest.display());
     }
 
     if cli.noop {
         Ok(())
-    } else if dest.exists() {
-        Err(io::Error::new(
-            io::ErrorKind::AlreadyExists,
-            format!("Backup target {} exists", dest.display()),
-        ))
     } else {
         fs::rename(src, dest)
     }
 }
 
-fn candidates(filesystem_root: &Path, file: &Path) -> io::Result<Candidates> {
-    let snapshot_dirs = match all_snapshot_dirs(filesystem_root) {
+// Finds a backup filename which does not already exist. If file.ext is taken, we try file.ext.1,
+// file.ext.2 and so on.
+fn backup_destination(src: &Path, ext: &str) -> PathBuf {
+    let dest = src.with_extension(ext);
+
+    if !dest.exists() {
+        return dest;
+    }
+
+    (1..)
+        .map(|n| {
+            let mut name = dest.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        })
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+fn candidates_json(candidates: &Candidates) -> serde_json::Result<String> {
+    serde_json::to_string(candidates)
+}
+
+// Finds the roots of the filesystems above the given one which have snapshots, nearest first. An
+// absolute snapdir holds the snapshots of the file's own dataset only, so there are none to find.
+fn ancestor_roots(filesystem_root: &Path, snapdir: &Path) -> Vec<PathBuf> {
+    let mut ret = Vec::new();
+
+    if snapdir.is_absolute() {
+        return ret;
+    }
+    let mut dir = filesystem_root.parent().map(Path::to_path_buf);
+
+    while let Some(parent) = dir {
+        let Ok(root) = zfs_info::dataset_root(&parent) else {
+            break;
+        };
+
+        if all_snapshot_dirs(&root, snapdir).is_some() {
+            ret.push(root.clone());
+        }
+
+        dir = root.parent().map(Path::to_path_buf);
+    }
+
+    ret
+}
+
+// Searches the snapshots of each of the given dataset roots in turn, the file's own first. Versions
+// found under any other root carry it, so they can be told apart.
+fn candidates_in_roots(
+    roots: &[PathBuf],
+    snapdir: &Path,
+    file: &Path,
+    max_snapshots: Option<usize>,
+    opts: &Opts,
+    checksums: bool,
+) -> io::Result<Candidates> {
+    let mut ret = Candidates::new();
+
+    for (i, root) in roots.iter().enumerate() {
+        let found = candidates(root, snapdir, file, max_snapshots, opts, checksums)?;
+
+        if i == 0 {
+            ret.extend(found);
+        } else {
+            ret.extend(found.into_iter().map(|c| Candidate {
+                root: Some(root.clone()),
+                ..c
+            }));
+        }
+    }
+
+    Ok(ret)
+}
+
+fn candidates(
+    filesystem_root: &Path,
+    snapdir: &Path,
+    file: &Path,
+    max_snapshots: Option<usize>,
+    opts: &Opts,
+    checksums: bool,
+) -> io::Result<Candidates> {
+    let all_dirs = match all_snapshot_dirs(filesystem_root, snapdir) {
         Some(dirs) => dirs,
         None => {
             eprintln!("No snapshots found under {}", filesystem_root.display());
@@ -141,7 +617,21 @@ fn candidates(filesystem_root: &Path, file: &Path) -> io::Result<Candidates> {
         }
     };
 
-    let relative_path = match path_relative_to_fs_root(file, filesystem_root) {
+    let snapshot_count = all_dirs.len();
+    let snapshot_dirs = snapshot_dirs_to_probe(all_dirs, max_snapshots);
+
+    if snapshot_dirs.len() < snapshot_count {
+        verbose!(
+            opts,
+            "Found {} snapshots, checking the {} most recent",
+            snapshot_count,
+            snapshot_dirs.len()
+        );
+    } else {
+        verbose!(opts, "Found {} snapshots", snapshot_count);
+    }
+
+    let relative_path = match zfs_file::path_relative_to_root(file, filesystem_root) {
         Some(path) => path,
         None => {
             eprintln!("Failed to calculate relative path for {}", file.display());
@@ -152,9 +642,14 @@ fn candidates(filesystem_root: &Path, file: &Path) -> io::Result<Candidates> {
     let ret: Candidates = snapshot_dirs
         .iter()
         .filter_map(|snapdir| {
+            // Opening the snapshot mounts it, so the exists() check below is believable.
+            if !zfs_file::is_snapshot_mounted(snapdir) {
+                verbose!(opts, "Could not open {}", snapdir.display());
+            }
+
             let candidate = snapdir.join(&relative_path);
             if candidate.exists() {
-                match details_of(snapdir, &candidate) {
+                match details_of(snapdir, &candidate, checksums) {
                     Ok(candidate) => Some(candidate),
                     Err(e) => {
                         eprintln!("Failed to get mtime for {}: {}", candidate.display(), e);
@@ -170,7 +665,7 @@ fn candidates(filesystem_root: &Path, file: &Path) -> io::Result<Candidates> {
     Ok(ret)
 }
 
-fn details_of(snapdir: &Path, file: &Path) -> io::Result<Candidate> {
+fn details_of(snapdir: &Path, file: &Path, checksums: bool) -> io::Result<Candidate> {
     let metadata = fs::metadata(file)?;
 
     let candidate = Candidate {
@@ -178,12 +673,14 @@ fn details_of(snapdir: &Path, file: &Path) -> io::Result<Candidate> {
         path: file.to_owned(),
         mtime: metadata.mtime(),
         size: metadata.size(),
+        checksum: checksum_if(checksums, file)?,
+        root: None,
     };
 
     Ok(candidate)
 }
 
-fn original_details(file: &Path) -> io::Result<Option<Candidate>> {
+fn original_details(file: &Path, checksums: bool) -> io::Result<Option<Candidate>> {
     let ret = if file.exists() {
         let metadata = fs::metadata(file)?;
 
@@ -192,6 +689,8 @@ fn original_details(file: &Path) -> io::Result<Option<Candidate>> {
             path: file.to_owned(),
             mtime: metadata.mtime(),
             size: metadata.size(),
+            checksum: checksum_if(checksums, file)?,
+            root: None,
         })
     } else {
         None
@@ -200,8 +699,28 @@ fn original_details(file: &Path) -> io::Result<Option<Candidate>> {
     Ok(ret)
 }
 
-fn path_relative_to_fs_root(file: &Path, filesystem_root: &Path) -> Option<PathBuf> {
-    file.strip_prefix(filesystem_root).ok().map(PathBuf::from)
+fn checksum_if(wanted: bool, file: &Path) -> io::Result<Option<String>> {
+    if wanted && file.is_file() {
+        short_checksum(file).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+// Only for telling versions apart by eye, so eight hex digits is plenty.
+fn short_checksum(file: &Path) -> io::Result<String> {
+    let mut reader = fs::File::open(file)?;
+    let mut hasher = SeaHasher::new();
+    let mut buf = [0; 64 * 1024];
+
+    loop {
+        match reader.read(&mut buf)? {
+            0 => break,
+            count => hasher.write(&buf[..count]),
+        }
+    }
+
+    Ok(format!("{:08x}", hasher.finish() >> 32))
 }
 
 // We need to canonicalize the source file, whether it exists or not.
@@ -218,15 +737,26 @@ fn canonical_file(file: PathBuf) -> io::Result<PathBuf> {
 fn main() {
     let cli = Cli::parse();
     let mut errs = 0;
+    let mut skips = 0;
 
     let opts = ZpZrOpts {
         verbose: cli.verbose,
         noop: cli.noop,
         noclobber: cli.noclobber,
+        buffer_size: COPY_BUFFER_SIZE,
+        trash_dir: cli.trash_dir.clone(),
+        preserve_owner: cli.preserve_owner,
+        preserve_xattr: cli.preserve_xattr,
     };
 
+    user_interaction::set_colour(cli.no_color);
+
+    if cli.recursive_datasets && cli.snapdir().is_absolute() {
+        eprintln!("WARNING: -R is ignored with an absolute snapdir");
+    }
+
     for file in &cli.file_list {
-        let f = match canonical_file(PathBuf::from(file)) {
+        let f = match canonical_file(pathutil::expand(file)) {
             Ok(file) => file,
             Err(e) => {
                 eprintln!("Failed to canonicalize {}: {}", file, e);
@@ -236,20 +766,33 @@ fn main() {
         };
 
         match restore_action(&PathBuf::from(&f), &cli) {
-            Ok(Some((src, dest))) => {
-                if let Err(e) = file_copier::copy_file(&src, &dest, &opts) {
+            Ok(Some((src, dest))) => match restore_file(&src, &dest, &opts) {
+                Ok(stats) => {
+                    for target in &stats.skipped {
+                        eprintln!("skipped: {}: target exists (noclobber)", target.display());
+                    }
+                    skips += stats.skipped.len();
+                }
+                Err(e) => {
                     eprintln!("ERROR restoring {}: {}", &f.display(), e);
                     errs += 1;
                 }
-            }
+            },
             Ok(None) => (),
-            Err(e) => {
-                eprintln!("ERROR working out how to restore {}: {}", &f.display(), e);
-                errs += 1;
-            }
+            Err(e) => match e.downcast_ref::<NotOnZfs>() {
+                Some(not_on_zfs) => eprintln!("{}, skipping", not_on_zfs),
+                None => {
+                    eprintln!("ERROR working out how to restore {}: {}", &f.display(), e);
+                    errs += 1;
+                }
+            },
         }
     }
 
+    if skips > 0 {
+        eprintln!("Skipped {} existing files because of --noclobber", skips);
+    }
+
     if errs > 0 {
         eprintln!("Encountered {} errors", errs);
         std::process::exit(1);
@@ -260,29 +803,23 @@ fn main() {
 mod test {
     use super::*;
     use common::spec_helper::fixture;
+    use common::types::Mount;
     use std::fs;
     use tempfile::tempdir;
 
     #[cfg(target_os = "illumos")]
     #[test]
-    fn test_all_snapshot_dirs() {
-        let result = all_snapshot_dirs(&fixture("")).unwrap();
-        assert!(!result.is_empty());
-        assert_eq!(None, all_snapshot_dirs(&PathBuf::from("/tmp")));
-    }
-
-    #[test]
-    fn test_path_relative_to_fs_root() {
+    fn test_snapshot_dirs() {
+        let mut result = zfs_file::snapshot_dirs(&fixture("")).unwrap();
+        result.sort();
         assert_eq!(
-            PathBuf::from("d/e/f"),
-            path_relative_to_fs_root(&PathBuf::from("/a/b/c/d/e/f"), &PathBuf::from("/a/b/c"))
-                .unwrap()
-        );
-
-        assert_eq!(
-            None,
-            path_relative_to_fs_root(&PathBuf::from("/a/b/c/d/e/f"), &PathBuf::from("/g/h/i"))
+            vec![
+                fixture(".zfs/snapshot/monday"),
+                fixture(".zfs/snapshot/tuesday")
+            ],
+            result
         );
+        assert_eq!(None, zfs_file::snapshot_dirs(&PathBuf::from("/tmp")));
     }
 
     #[test]
@@ -292,11 +829,18 @@ mod test {
             fixture(".zfs/snapshot/tuesday/file_in_both"),
         ];
 
-        let mut actual = candidates(&fixture(""), &fixture("file_in_both"))
-            .unwrap()
-            .into_iter()
-            .map(|c| c.path)
-            .collect::<Vec<PathBuf>>();
+        let mut actual = candidates(
+            &fixture(""),
+            &zfs_snapdir(),
+            &fixture("file_in_both"),
+            None,
+            &QUIET,
+            false,
+        )
+        .unwrap()
+        .into_iter()
+        .map(|c| c.path)
+        .collect::<Vec<PathBuf>>();
 
         expected.sort();
         actual.sort();
@@ -304,16 +848,537 @@ mod test {
 
         assert_eq!(
             vec![fixture(".zfs/snapshot/monday/file_in_monday"),],
-            candidates(&fixture(""), &fixture("file_in_monday"))
+            candidates(
+                &fixture(""),
+                &zfs_snapdir(),
+                &fixture("file_in_monday"),
+                None,
+                &QUIET,
+                false
+            )
+            .unwrap()
+            .into_iter()
+            .map(|c| c.path)
+            .collect::<Vec<PathBuf>>()
+        );
+
+        assert!(candidates(
+            &fixture(""),
+            &zfs_snapdir(),
+            &fixture("file_in_neither"),
+            None,
+            &QUIET,
+            false
+        )
+        .unwrap()
+        .is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_dirs_to_probe() {
+        let all_dirs = zfs_file::snapshot_dirs(&fixture("")).unwrap();
+
+        assert_eq!(2, snapshot_dirs_to_probe(all_dirs.clone(), None).len());
+        assert_eq!(2, snapshot_dirs_to_probe(all_dirs.clone(), Some(5)).len());
+        assert_eq!(1, snapshot_dirs_to_probe(all_dirs.clone(), Some(1)).len());
+        assert!(snapshot_dirs_to_probe(all_dirs, Some(0)).is_empty());
+
+        let tmp = tempdir().unwrap();
+        let now = std::time::SystemTime::now();
+        let mut dirs = Vec::new();
+
+        for (name, age) in [("old", 300), ("newest", 0), ("middle", 100)] {
+            let dir = tmp.path().join(name);
+            fs::create_dir(&dir).unwrap();
+            fs::File::open(&dir)
                 .unwrap()
-                .into_iter()
-                .map(|c| c.path)
-                .collect::<Vec<PathBuf>>()
+                .set_modified(now - std::time::Duration::from_secs(age))
+                .unwrap();
+            dirs.push(dir);
+        }
+
+        assert_eq!(
+            vec![tmp.path().join("newest"), tmp.path().join("middle")],
+            snapshot_dirs_to_probe(dirs, Some(2))
+        );
+    }
+
+    #[test]
+    fn test_backup_destination() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("file.txt");
+        fs::write(&src, "live").unwrap();
+
+        assert_eq!(
+            tmp.path().join("file.backup"),
+            backup_destination(&src, "backup")
+        );
+
+        assert_eq!(
+            tmp.path().join("file.orig"),
+            backup_destination(&src, "orig")
+        );
+
+        fs::write(tmp.path().join("file.backup"), "first").unwrap();
+
+        assert_eq!(
+            tmp.path().join("file.backup.1"),
+            backup_destination(&src, "backup")
+        );
+
+        fs::write(tmp.path().join("file.backup.1"), "second").unwrap();
+
+        assert_eq!(
+            tmp.path().join("file.backup.2"),
+            backup_destination(&src, "backup")
+        );
+    }
+
+    #[test]
+    fn test_candidates_before() {
+        let candidate = |snapname: &str| Candidate {
+            snapname: snapname.to_string(),
+            path: PathBuf::from("/build/file"),
+            size: 100,
+            mtime: 1000,
+            checksum: None,
+            root: None,
+        };
+
+        let candidates = vec![
+            candidate("wednesday"),
+            candidate("tuesday"),
+            candidate("monday"),
+            candidate("unknown"),
+        ];
+
+        let creation: HashMap<String, i64> = [
+            ("monday".to_string(), 1730073600),
+            ("tuesday".to_string(), 1730160000),
+            ("wednesday".to_string(), 1730246400),
+        ]
+        .into_iter()
+        .collect();
+
+        let names = |candidates: Candidates| -> Vec<String> {
+            candidates.into_iter().map(|c| c.snapname).collect()
+        };
+
+        assert_eq!(
+            vec!["tuesday".to_string(), "monday".to_string()],
+            names(candidates_before(candidates.clone(), &creation, 1730246400))
+        );
+
+        assert_eq!(
+            Vec::<String>::new(),
+            names(candidates_before(candidates, &creation, 1730073600))
         );
+    }
+
+    #[test]
+    fn test_candidates_before_in_roots() {
+        let candidate = |snapname: &str, root: Option<&str>| Candidate {
+            snapname: snapname.to_string(),
+            path: PathBuf::from("/build/file"),
+            size: 100,
+            mtime: 1000,
+            checksum: None,
+            root: root.map(PathBuf::from),
+        };
+
+        let candidates = vec![
+            candidate("tuesday", None),
+            candidate("monday", None),
+            candidate("monday", Some("/")),
+            candidate("sunday", Some("/")),
+        ];
+
+        // The two datasets both have a monday snapshot, taken at different times.
+        let creation = |root: &Path| -> anyhow::Result<HashMap<String, i64>> {
+            let times: &[(&str, i64)] = if root == Path::new("/build") {
+                &[("monday", 200), ("tuesday", 300)]
+            } else {
+                &[("sunday", 100), ("monday", 250)]
+            };
+
+            Ok(times.iter().map(|(n, t)| (n.to_string(), *t)).collect())
+        };
 
-        assert!(candidates(&fixture(""), &fixture("file_in_neither"))
+        let labels = |candidates: Candidates| -> Vec<String> {
+            candidates.iter().map(|c| c.label()).collect()
+        };
+
+        assert_eq!(
+            vec!["monday", "sunday (/)"],
+            labels(
+                candidates_before_in_roots(candidates.clone(), Path::new("/build"), &creation, 225)
+                    .unwrap()
+            )
+        );
+
+        assert_eq!(
+            vec!["tuesday", "monday", "monday (/)", "sunday (/)"],
+            labels(
+                candidates_before_in_roots(candidates, Path::new("/build"), &creation, 1000)
+                    .unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_auto_choice() {
+        let candidate = |snapname: &str, mtime: i64| Candidate {
+            snapname: snapname.to_string(),
+            path: PathBuf::from("/build/file"),
+            size: 100,
+            mtime,
+            checksum: None,
+            root: None,
+        };
+
+        let mut candidates = vec![
+            candidate("tuesday", 2000),
+            candidate("monday", 1000),
+            candidate("wednesday", 3000),
+        ];
+
+        candidates.sort_by_key(|c| std::cmp::Reverse(c.mtime));
+
+        let cli = Cli::parse_from(["zr", "--auto-oldest", "file"]);
+        let index = auto_choice(&candidates, &cli).unwrap();
+        assert_eq!("monday", candidates[index].snapname);
+        assert_eq!(None, auto_choice(&Vec::new(), &cli));
+
+        let cli = Cli::parse_from(["zr", "--auto", "file"]);
+        let index = auto_choice(&candidates, &cli).unwrap();
+        assert_eq!("wednesday", candidates[index].snapname);
+
+        let cli = Cli::parse_from(["zr", "file"]);
+        assert_eq!(None, auto_choice(&candidates, &cli));
+
+        assert!(Cli::try_parse_from(["zr", "-a", "-o", "file"]).is_err());
+    }
+
+    #[test]
+    fn test_needs_confirmation() {
+        let candidate = |mtime: i64| Candidate {
+            snapname: "monday".to_string(),
+            path: PathBuf::from("/build/file"),
+            size: 100,
+            mtime,
+            checksum: None,
+            root: None,
+        };
+
+        let live = candidate(2000);
+
+        assert!(needs_confirmation(Some(&live), &candidate(1000), false));
+        assert!(!needs_confirmation(Some(&live), &candidate(1000), true));
+        assert!(!needs_confirmation(Some(&live), &candidate(2000), false));
+        assert!(!needs_confirmation(Some(&live), &candidate(3000), false));
+        assert!(!needs_confirmation(None, &candidate(1000), false));
+    }
+
+    #[test]
+    fn test_find_in_snapshot() {
+        let snapshot = fixture(".zfs/snapshot/monday");
+
+        assert_eq!(
+            (
+                vec![
+                    PathBuf::from("dir_in_monday"),
+                    PathBuf::from("file_in_monday"),
+                ],
+                false
+            ),
+            find_in_snapshot(&snapshot, "in_monday")
+        );
+
+        assert_eq!(
+            (vec![PathBuf::from("dir_in_monday/file_in_dir")], false),
+            find_in_snapshot(&snapshot, "in_dir")
+        );
+
+        assert_eq!(
+            (Vec::<PathBuf>::new(), false),
+            find_in_snapshot(&snapshot, "nothing_called_this")
+        );
+
+        assert_eq!(
+            (Vec::<PathBuf>::new(), false),
+            find_in_snapshot(&fixture("no_such_dir"), "file")
+        );
+    }
+
+    #[test]
+    fn test_find_in_snapshot_cap() {
+        let tmp = tempdir().unwrap();
+
+        for i in 0..(MAX_FIND_RESULTS + 5) {
+            fs::write(tmp.path().join(format!("report_{}", i)), "").unwrap();
+        }
+
+        let (matches, truncated) = find_in_snapshot(tmp.path(), "report");
+        assert_eq!(MAX_FIND_RESULTS, matches.len());
+        assert!(truncated);
+    }
+
+    #[test]
+    fn test_resolve_missing_path() {
+        let tmp = tempdir().unwrap();
+        let canonical_tmp = tmp.path().canonicalize().unwrap();
+        let file = tmp.path().join("gone").join("deeper").join("file");
+
+        assert_eq!(
+            (
+                canonical_tmp.clone(),
+                canonical_tmp.join("gone/deeper/file")
+            ),
+            resolve_missing_path(&file).unwrap()
+        );
+
+        let existing = tmp.path().join("here");
+        fs::write(&existing, "present").unwrap();
+
+        assert_eq!(
+            (canonical_tmp.clone(), canonical_tmp.join("here")),
+            resolve_missing_path(&existing).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_short_checksum() {
+        let tmp = tempdir().unwrap();
+        let other = tmp.path().join("other");
+        fs::write(&other, "date\n").unwrap();
+
+        let monday = short_checksum(&fixture(".zfs/snapshot/monday/file_in_both")).unwrap();
+        let tuesday = short_checksum(&fixture(".zfs/snapshot/tuesday/file_in_both")).unwrap();
+
+        assert_eq!(8, monday.len());
+        assert_eq!(monday, tuesday);
+        assert_ne!(monday, short_checksum(&other).unwrap());
+        assert_eq!(None, checksum_if(false, &other).unwrap());
+    }
+
+    #[test]
+    fn test_candidates_in_missing_directory() {
+        assert_eq!(
+            vec![fixture(".zfs/snapshot/monday/dir_in_monday/file_in_dir")],
+            candidates(
+                &fixture(""),
+                &zfs_snapdir(),
+                &fixture("dir_in_monday/file_in_dir"),
+                None,
+                &QUIET,
+                false
+            )
             .unwrap()
-            .is_empty());
+            .into_iter()
+            .map(|c| c.path)
+            .collect::<Vec<PathBuf>>()
+        );
+    }
+
+    #[test]
+    fn test_candidates_json() {
+        let mut candidates = candidates(
+            &fixture(""),
+            &zfs_snapdir(),
+            &fixture("file_in_both"),
+            None,
+            &QUIET,
+            false,
+        )
+        .unwrap();
+        candidates.sort_by(|a, b| a.snapname.cmp(&b.snapname));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&candidates_json(&candidates).unwrap()).unwrap();
+        let list = json.as_array().unwrap();
+
+        assert_eq!(2, list.len());
+
+        for (entry, snapname) in list.iter().zip(["monday", "tuesday"]) {
+            let path = fixture(&format!(".zfs/snapshot/{}/file_in_both", snapname));
+            let metadata = fs::metadata(&path).unwrap();
+
+            assert_eq!(snapname, entry["snapname"]);
+            assert_eq!(path.to_str().unwrap(), entry["path"]);
+            assert_eq!(metadata.len(), entry["size"]);
+            assert_eq!(metadata.mtime(), entry["mtime"]);
+            assert!(entry.get("checksum").is_none());
+        }
+
+        assert_eq!("[]", candidates_json(&Candidates::new()).unwrap());
+    }
+
+    #[test]
+    fn test_candidates_in_roots() {
+        let tmp = tempdir().unwrap();
+        let parent = tmp.path().to_path_buf();
+        let child = parent.join("child");
+
+        fs::create_dir_all(parent.join(".zfs/snapshot/monday/child")).unwrap();
+        fs::create_dir_all(child.join(".zfs/snapshot/tuesday")).unwrap();
+        fs::write(parent.join(".zfs/snapshot/monday/child/file"), "old").unwrap();
+        fs::write(child.join(".zfs/snapshot/tuesday/file"), "new").unwrap();
+
+        let file = child.join("file");
+
+        let own_only = candidates_in_roots(
+            std::slice::from_ref(&child),
+            &zfs_snapdir(),
+            &file,
+            None,
+            &QUIET,
+            false,
+        )
+        .unwrap();
+        assert_eq!(1, own_only.len());
+        assert_eq!("tuesday", own_only[0].snapname);
+
+        let mut both = candidates_in_roots(
+            &[child.clone(), parent.clone()],
+            &zfs_snapdir(),
+            &file,
+            None,
+            &QUIET,
+            false,
+        )
+        .unwrap();
+        both.sort_by(|a, b| a.snapname.cmp(&b.snapname));
+
+        assert_eq!(
+            vec!["monday", "tuesday"],
+            both.iter().map(|c| c.snapname.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(parent.clone()), both[0].root);
+        assert_eq!(None, both[1].root);
+        assert_eq!(format!("monday ({})", parent.display()), both[0].label());
+        assert_eq!("tuesday", both[1].label());
+        assert_eq!(parent.join(".zfs/snapshot/monday/child/file"), both[0].path);
+    }
+
+    #[test]
+    fn test_ancestor_roots_absolute_snapdir() {
+        let tmp = tempdir().unwrap();
+        let snapdir = tmp.path().join("snapshots");
+        fs::create_dir_all(snapdir.join("monday")).unwrap();
+
+        let child = tmp.path().join("child");
+        fs::create_dir(&child).unwrap();
+
+        assert!(ancestor_roots(&child, &snapdir).is_empty());
+    }
+
+    #[test]
+    fn test_restore_action_absolute_snapdir_recursive() {
+        let tmp = tempdir().unwrap();
+        let file = tmp.path().join("file");
+        fs::write(&file, "live").unwrap();
+        let snapdir = fixture_snapdir(tmp.path(), &file, &["monday"]);
+
+        let mut cli = Cli::parse_from(["zr", "-a", "-R", &file.to_string_lossy()]);
+        cli.snapdir = Some(snapdir.clone());
+
+        let relative = file
+            .strip_prefix(zfs_info::dataset_root(tmp.path()).unwrap())
+            .unwrap();
+
+        // Only the file's own dataset is searched, so there is one version, from one place.
+        assert_eq!(
+            Some((snapdir.join("monday").join(relative), file.clone())),
+            restore_action(&file, &cli).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_snapdir_from() {
+        assert_eq!(zfs_snapdir(), snapdir_from(None, None));
+        assert_eq!(
+            zfs_snapdir(),
+            snapdir_from(Some(PathBuf::new()), Some("".into()))
+        );
+        assert_eq!(
+            PathBuf::from("/snaps"),
+            snapdir_from(None, Some("/snaps".into()))
+        );
+        assert_eq!(
+            PathBuf::from(".snapshots"),
+            snapdir_from(Some(PathBuf::from(".snapshots")), Some("/snaps".into()))
+        );
+    }
+
+    #[test]
+    fn test_candidates_in_other_snapdir() {
+        let tmp = tempdir().unwrap();
+        let root = tmp.path();
+
+        fs::create_dir_all(root.join("snapshot/monday")).unwrap();
+        fs::create_dir_all(root.join("snapshot/tuesday")).unwrap();
+        fs::write(root.join("snapshot/monday/file"), "old").unwrap();
+
+        let found = candidates(
+            root,
+            Path::new("snapshot"),
+            &root.join("file"),
+            None,
+            &QUIET,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(1, found.len());
+        assert_eq!("monday", found[0].snapname);
+        assert_eq!(root.join("snapshot/monday/file"), found[0].path);
+
+        let absolute = candidates(
+            Path::new("/nowhere"),
+            &root.join("snapshot"),
+            Path::new("/nowhere/file"),
+            None,
+            &QUIET,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(1, absolute.len());
+        assert!(candidates(
+            root,
+            &zfs_snapdir(),
+            &root.join("file"),
+            None,
+            &QUIET,
+            false
+        )
+        .unwrap()
+        .is_empty());
+        assert!(on_zfs(root, Path::new("snapshot"), &MountList::new()));
+        assert!(!on_zfs(root, &zfs_snapdir(), &MountList::new()));
+    }
+
+    #[test]
+    fn test_restore_file_into_missing_directory() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("snapshot_copy");
+        fs::write(&src, "restored").unwrap();
+        let dest = tmp.path().join("gone").join("deeper").join("file");
+
+        let opts = ZpZrOpts::default();
+
+        assert!(restore_file(&src, &dest, &opts).is_ok());
+        assert_eq!("restored", fs::read_to_string(&dest).unwrap());
+
+        let noop_dest = tmp.path().join("also_gone").join("file");
+
+        let noop_opts = ZpZrOpts {
+            noop: true,
+            ..Default::default()
+        };
+
+        assert!(restore_file(&src, &noop_dest, &noop_opts).is_ok());
+        assert!(!tmp.path().join("also_gone").exists());
     }
 
     #[test]
@@ -321,39 +1386,133 @@ mod test {
         let temp_dir = tempdir().unwrap();
         let file_path = temp_dir.path().join("test_file.txt");
         fs::write(&file_path, "test content").unwrap();
+        let snapdir = fixture_snapdir(temp_dir.path(), &file_path, &["monday", "tuesday"]);
 
-        let cli = Cli {
-            file_list: vec![file_path.to_string_lossy().into()],
-            verbose: false,
-            noop: false,
-            auto: true,
-            noclobber: false,
-        };
+        let mut cli = Cli::parse_from(["zr", "-a", &file_path.to_string_lossy()]);
+        cli.snapdir = Some(snapdir.clone());
 
-        let result = restore_action(&file_path, &cli);
-        assert!(result.is_ok());
+        let relative = file_path
+            .strip_prefix(zfs_info::dataset_root(temp_dir.path()).unwrap())
+            .unwrap();
 
-        if let Some((src, dest)) = result.unwrap() {
-            assert_eq!(src, file_path);
-            assert_eq!(dest, file_path);
-        }
+        assert_eq!(
+            Some((snapdir.join("tuesday").join(relative), file_path.clone())),
+            restore_action(&file_path, &cli).unwrap()
+        );
     }
 
     #[test]
     fn test_restore_action_no_candidates() {
         let temp_dir = tempdir().unwrap();
         let file_path = temp_dir.path().join("nonexistent_file.txt");
+        let other_file = temp_dir.path().join("other_file.txt");
+        let snapdir = fixture_snapdir(temp_dir.path(), &other_file, &["monday"]);
+
+        let mut cli = Cli::parse_from(["zr", &file_path.to_string_lossy()]);
+        cli.snapdir = Some(snapdir);
+
+        assert!(restore_action(&file_path, &cli).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_restore_action_before_and_find() {
+        let tmp = tempdir().unwrap();
+        let file = tmp.path().join("gone.txt");
+        let snapdir = fixture_snapdir(tmp.path(), &file, &["monday"]);
+        let old_name = tmp.path().join("gone.txt.old");
+        fixture_snapdir(tmp.path(), &old_name, &["tuesday"]);
 
-        let cli = Cli {
-            file_list: vec![file_path.to_string_lossy().into()],
-            verbose: false,
-            noop: false,
-            auto: false,
-            noclobber: false,
+        let creation = |_root: &Path| -> anyhow::Result<HashMap<String, i64>> {
+            Ok([("monday".to_string(), 200), ("tuesday".to_string(), 300)]
+                .into_iter()
+                .collect())
         };
 
-        let result = restore_action(&file_path, &cli);
-        assert!(result.is_ok());
-        assert!(result.unwrap().is_none());
+        let mut cli = Cli::parse_from(["zr", "-a", "-F", &file.to_string_lossy()]);
+        cli.snapdir = Some(snapdir.clone());
+        cli.before = Some(100);
+
+        // Every version is too new, so find must not be tried.
+        assert!(restore_action_with(&file, &cli, &creation, true)
+            .unwrap()
+            .is_none());
+
+        // Only tuesday has anything like the missing file, and it is too new as well.
+        let missing = tmp.path().join("gone.txt.o");
+        assert!(restore_action_with(&missing, &cli, &creation, true)
+            .unwrap()
+            .is_none());
+
+        cli.before = Some(250);
+        assert!(restore_action_with(&missing, &cli, &creation, true)
+            .unwrap()
+            .is_none());
+
+        cli.before = None;
+        let (src, dest) = restore_action_with(&missing, &cli, &creation, true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(old_name, dest);
+        assert!(src.starts_with(snapdir.join("tuesday")));
+        assert!(src.ends_with("gone.txt.old"));
+    }
+
+    #[test]
+    fn test_snapshot_dirs_before() {
+        let dirs = vec![
+            PathBuf::from("/s/monday"),
+            PathBuf::from("/s/tuesday"),
+            PathBuf::from("/s/unknown"),
+        ];
+
+        let creation: HashMap<String, i64> =
+            [("monday".to_string(), 100), ("tuesday".to_string(), 200)]
+                .into_iter()
+                .collect();
+
+        assert_eq!(
+            vec![PathBuf::from("/s/monday")],
+            snapshot_dirs_before(dirs.clone(), &creation, 150)
+        );
+        assert!(snapshot_dirs_before(dirs, &creation, 100).is_empty());
+    }
+
+    // Makes a snapshot directory, outside the dataset, with a copy of the file in each of the named
+    // snapshots. The copy has the file's path relative to its dataset root, as --snapdir expects.
+    fn fixture_snapdir(tmp: &Path, file: &Path, snapnames: &[&str]) -> PathBuf {
+        let snapdir = tmp.join("snapshots");
+        let root = zfs_info::dataset_root(tmp).unwrap();
+        let relative = file.strip_prefix(&root).unwrap();
+
+        for snapname in snapnames {
+            let copy = snapdir.join(snapname).join(relative);
+            fs::create_dir_all(copy.parent().unwrap()).unwrap();
+            fs::write(&copy, snapname).unwrap();
+        }
+
+        snapdir
+    }
+
+    const QUIET: Opts = Opts {
+        verbose: 0,
+        noop: false,
+    };
+
+    fn zfs_snapdir() -> PathBuf {
+        PathBuf::from(".zfs/snapshot")
+    }
+
+    // Temporary directories are not usually on ZFS, but they might be.
+    #[test]
+    fn test_on_zfs() {
+        let tmp = tempdir().unwrap();
+        let root = tmp.path();
+
+        let mnttab_mounts = vec![Mount::new(root, "rpool/export")];
+        assert!(on_zfs(root, &zfs_snapdir(), &mnttab_mounts));
+        assert!(!on_zfs(root, &zfs_snapdir(), &MountList::new()));
+
+        fs::create_dir_all(root.join(".zfs/snapshot/monday")).unwrap();
+        assert!(on_zfs(root, &zfs_snapdir(), &MountList::new()));
     }
 }
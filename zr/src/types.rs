@@ -1,13 +1,44 @@
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
 use std::path::PathBuf;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct Candidate {
     pub snapname: String,
     pub path: PathBuf,
     pub size: u64,
     pub mtime: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    // The root of the dataset whose snapshot holds this version, if that isn't the file's own.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
 }
 
+impl Candidate {
+    // How the version is shown to the user: versions from other datasets carry the dataset's root.
+    pub fn label(&self) -> String {
+        match &self.root {
+            Some(root) => format!("{} ({})", self.snapname, root.display()),
+            None => self.snapname.clone(),
+        }
+    }
+}
+
+// The file to be restored is somewhere without snapshots. This is not worth counting as an error.
+#[derive(Debug)]
+pub struct NotOnZfs(pub PathBuf);
+
+impl fmt::Display for NotOnZfs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not on a ZFS filesystem", self.0.display())
+    }
+}
+
+impl Error for NotOnZfs {}
+
 pub type Candidates = Vec<Candidate>;
 pub type CopyAction = Option<(PathBuf, PathBuf)>;
-pub type UserChoice = Option<(usize, Option<String>)>;
+// The chosen index, any command letter, and for `d`, an optional second index to diff against.
+pub type UserChoice = Option<(usize, Option<String>, Option<usize>)>;
@@ -1,24 +1,71 @@
 use crate::types::{Candidate, Candidates, UserChoice};
 use colored::Colorize;
+use common::timefmt;
 use regex::Regex;
-use std::io::{self, Write};
-use time::{format_description, OffsetDateTime, UtcOffset};
+use std::env;
+use std::ffi::OsStr;
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
 
-pub fn print_options(original_file: Option<Candidate>, candidates: &Candidates) {
+/// Turns colour off for the rest of the run, if it isn't wanted. See `use_colour()`.
+///
+pub fn set_colour(no_color_flag: bool) {
+    let wanted = use_colour(
+        no_color_flag,
+        env::var_os("NO_COLOR").as_deref(),
+        io::stdout().is_terminal(),
+    );
+
+    colored::control::set_override(wanted);
+}
+
+// Colour is only for people looking at a terminal, and only if they haven't asked for it to be
+// off. Following no-color.org, an empty NO_COLOR doesn't count.
+fn use_colour(no_color_flag: bool, no_color_env: Option<&OsStr>, is_tty: bool) -> bool {
+    let env_says_no = no_color_env.is_some_and(|value| !value.is_empty());
+    is_tty && !no_color_flag && !env_says_no
+}
+
+pub fn print_options(original_file: &Option<Candidate>, candidates: &Candidates) {
     let mut stdout = io::stdout();
     for (index, candidate) in candidates.iter().enumerate() {
         let basic_line = basic_line(index, candidate);
         writeln!(
             stdout,
             "{}",
-            decorated_line(&original_file, candidate, basic_line)
+            decorated_line(original_file, candidate, basic_line)
         )
         .unwrap();
     }
 }
 
 pub fn get_choice() -> io::Result<String> {
-    print!("choose file to promote [add 'd' for diff, 'k' to keep] > ");
+    prompt("choose file to promote [add 'd' for diff, 'dN' to diff with N, 'k' to keep] > ")
+}
+
+pub fn print_matches(matches: &[PathBuf]) {
+    for (index, path) in matches.iter().enumerate() {
+        println!("{:>2} {}", index, path.display());
+    }
+}
+
+pub fn get_match_choice(matches: &[PathBuf]) -> io::Result<Option<PathBuf>> {
+    let user_input = prompt("choose file to look for > ")?;
+
+    Ok(user_input
+        .parse::<usize>()
+        .ok()
+        .and_then(|index| matches.get(index))
+        .cloned())
+}
+
+pub fn confirm(message: &str) -> io::Result<bool> {
+    let answer = prompt(&format!("{} [y/N] > ", message))?;
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+fn prompt(message: &str) -> io::Result<String> {
+    print!("{}", message);
     io::stdout().flush().unwrap();
     let mut buffer = String::new();
     let stdin = io::stdin();
@@ -26,27 +73,38 @@ pub fn get_choice() -> io::Result<String> {
     Ok(buffer.to_owned().trim().to_string())
 }
 
+// Only the d command takes a second index.
 pub fn parse_choice(input: &str) -> UserChoice {
-    let pattern = Regex::new(r"^(\d+)([a-z]?)$").unwrap();
+    let pattern = Regex::new(r"^(\d+)(?:([a-z])(\d+)?)?$").unwrap();
     let captures = pattern.captures(input)?;
 
     let number = captures.get(1)?.as_str().parse::<usize>().ok()?;
-    let command = captures
-        .get(2)
-        .filter(|m| !m.as_str().is_empty())
-        .map(|m| m.as_str().to_string());
+    let command = captures.get(2).map(|m| m.as_str().to_string());
+    let other = match captures.get(3) {
+        Some(m) => Some(m.as_str().parse::<usize>().ok()?),
+        None => None,
+    };
+
+    if other.is_some() && command.as_deref() != Some("d") {
+        return None;
+    }
 
-    Some((number, command))
+    Some((number, command, other))
 }
 
 fn basic_line(index: usize, candidate: &Candidate) -> String {
-    format!(
+    let line = format!(
         "{:>2} {:<20} {:<35} {}",
         index,
-        candidate.snapname,
-        format_timestamp(candidate.mtime),
+        candidate.label(),
+        timefmt::format_local(candidate.mtime, timefmt::LONG_FORMAT),
         candidate.size
-    )
+    );
+
+    match &candidate.checksum {
+        Some(checksum) => format!("{:<70} {}", line, checksum),
+        None => line,
+    }
 }
 
 fn decorated_line(
@@ -66,25 +124,9 @@ fn decorated_line(
     }
 }
 
-fn format_timestamp(timestamp: i64) -> String {
-    let datetime =
-        OffsetDateTime::from_unix_timestamp(timestamp).unwrap_or(OffsetDateTime::UNIX_EPOCH);
-    let local_offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
-    let local_datetime = datetime.to_offset(local_offset);
-    let format = format_description::parse(
-        "[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]",
-    )
-    .unwrap();
-
-    local_datetime
-        .format(&format)
-        .unwrap_or_else(|_| String::from("Invalid date"))
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::path::PathBuf;
 
     #[test]
     fn test_basic_line() {
@@ -93,6 +135,8 @@ mod test {
             path: PathBuf::from("some/path"),
             mtime: 1730563919,
             size: 150679,
+            checksum: None,
+            root: None,
         };
 
         assert_eq!(
@@ -101,13 +145,57 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_basic_line_with_checksum() {
+        let candidate = Candidate {
+            snapname: "may".to_string(),
+            path: PathBuf::from("some/path"),
+            mtime: 1730563919,
+            size: 150679,
+            checksum: Some("8a3f09c2".to_string()),
+            root: None,
+        };
+
+        assert_eq!(
+            " 0 may                  2024-11-02 16:11:59 +0000           150679     8a3f09c2"
+                .to_string(),
+            basic_line(0, &candidate)
+        );
+    }
+
+    #[test]
+    fn test_use_colour() {
+        assert!(use_colour(false, None, true));
+        assert!(use_colour(false, Some(OsStr::new("")), true));
+        assert!(!use_colour(true, None, true));
+        assert!(!use_colour(false, Some(OsStr::new("1")), true));
+        assert!(!use_colour(false, None, false));
+        assert!(!use_colour(true, Some(OsStr::new("1")), false));
+    }
+
     #[test]
     fn test_parse_choice() {
         assert_eq!(None, parse_choice("x"));
         assert_eq!(
-            (47_usize, Some("k".to_string())),
+            (47_usize, Some("k".to_string()), None),
             parse_choice("47k").unwrap()
         );
-        assert_eq!((7_usize, None), parse_choice("7").unwrap());
+        assert_eq!((7_usize, None, None), parse_choice("7").unwrap());
+    }
+
+    #[test]
+    fn test_parse_choice_diff() {
+        assert_eq!(
+            (3_usize, Some("d".to_string()), Some(5_usize)),
+            parse_choice("3d5").unwrap()
+        );
+        assert_eq!(
+            (3_usize, Some("d".to_string()), None),
+            parse_choice("3d").unwrap()
+        );
+        assert_eq!((3_usize, None, None), parse_choice("3").unwrap());
+        assert_eq!(None, parse_choice("3k5"));
+        assert_eq!(None, parse_choice("d5"));
+        assert_eq!(None, parse_choice("3d5x"));
     }
 }